@@ -55,6 +55,7 @@ pub const ANTIDIAGS_TEMPLATE: [u64; 15] = [
 pub const BITS:              [u64; 64]       = get_bit_lut();
 pub const DIAGONALS:         [u64; 64]       = get_diagonals();
 pub const ANTIDIAGS:         [u64; 64]       = get_antidiags();
+#[allow(clippy::large_const_arrays)]
 pub const RAYS:              [[u64; 64]; 64] = get_rays_lut();
 pub const LUT_KNIGHT:        [u64; 64]       = get_lut_knight();
 pub const LUT_KING:          [u64; 64]       = get_lut_king();
@@ -306,7 +307,7 @@ const fn get_lut_rank_slide() -> [[u64; 256]; 8] {
             let msb1  = BITS[0] >> ((p - 1) & i | 1).leading_zeros();
             let slide   = ((i.wrapping_sub(p << 1) ^ i) | (p - msb1)) & 0xFF;
 
-            lut[r as usize][i as usize] = slide;
+            lut[r][i as usize] = slide;
 
             i += 1;
             if i == 256 {
@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::position::{Move, Position};
+
+pub const BOOK_ENTRY_SIZE: usize = 16;
+
+// Polyglot book entry, stored big-endian on disk
+#[derive(Default, Copy, Clone)]
+pub struct BookEntry {
+    pub key: u64,
+    pub poly_move: u16,    // Order: PROMOTION - FROM RANK - FROM FILE - TO RANK - TO FILE
+                           // Bits:      3          3           3          3         3
+    pub weight: u16,
+    pub learn: u32,
+}
+
+impl BookEntry {
+    pub fn from_bytes(bytes: &[u8]) -> BookEntry {
+        BookEntry {
+            key: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            poly_move: u16::from_be_bytes(bytes[8..10].try_into().unwrap()),
+            weight: u16::from_be_bytes(bytes[10..12].try_into().unwrap()),
+            learn: u32::from_be_bytes(bytes[12..16].try_into().unwrap()),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; BOOK_ENTRY_SIZE] {
+        let mut bytes = [0; BOOK_ENTRY_SIZE];
+        bytes[0..8].copy_from_slice(&self.key.to_be_bytes());
+        bytes[8..10].copy_from_slice(&self.poly_move.to_be_bytes());
+        bytes[10..12].copy_from_slice(&self.weight.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.learn.to_be_bytes());
+        bytes
+    }
+
+    pub fn get_origin(&self) -> usize { ((self.poly_move >> 6) & 0x3F) as usize }
+
+    pub fn get_target(&self) -> usize { (self.poly_move & 0x3F) as usize }
+
+    pub fn get_promotion(&self) -> u8 { ((self.poly_move >> 12) & 0x7) as u8 }
+}
+
+// Encodes a move the way Polyglot does, castling is written as the king capturing its rook
+pub fn encode_move(m: Move) -> u16 {
+    let origin = m.origin_index() as u16;
+    let mut target = m.target_index() as u16;
+
+    match m.code() {
+        6 => target += 1,
+        7 => target -= 2,
+        _ => {}
+    }
+
+    let promotion = if m.code() != 0 && m.code() <= 4 { m.code() as u16 } else { 0 };
+    target | (origin << 6) | (promotion << 12)
+}
+
+pub struct Book {
+    pub entries: Vec<BookEntry>,
+}
+
+impl Book {
+    pub fn load(path: &str) -> std::io::Result<Book> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        let entries = bytes.chunks_exact(BOOK_ENTRY_SIZE).map(BookEntry::from_bytes).collect();
+        Ok(Book { entries })
+    }
+
+    // Entries are sorted by key, so all entries for a position are adjacent
+    pub fn probe(&self, key: u64) -> &[BookEntry] {
+        let start = self.entries.partition_point(|e| e.key < key);
+        let end = start + self.entries[start..].partition_point(|e| e.key == key);
+        &self.entries[start..end]
+    }
+
+    // Returns all book moves for the position that are legal, paired with their weights
+    pub fn moves(&self, pos: &Position) -> Vec<(Move, u16)> {
+        let legal = pos.legal_moves();
+
+        let mut moves = Vec::new();
+        for entry in self.probe(pos.state.key) {
+            if let Some(&m) = legal.iter().find(|m| encode_move(**m) == entry.poly_move) {
+                moves.push((m, entry.weight));
+            }
+        }
+        moves
+    }
+
+    // Picks a move at random weighted by book weight, or the highest weighted one if best_only is set
+    pub fn pick(&self, pos: &Position, best_only: bool) -> Option<Move> {
+        let moves = self.moves(pos);
+        if moves.is_empty() {
+            return None;
+        }
+
+        if best_only {
+            return moves.iter().max_by_key(|(_, w)| *w).map(|(m, _)| *m);
+        }
+
+        let total: u64 = moves.iter().map(|(_, w)| *w as u64).sum();
+        if total == 0 {
+            return Some(moves[0].0);
+        }
+
+        let mut roll = random_u64() % total;
+        for (m, w) in moves.iter() {
+            if roll < *w as u64 {
+                return Some(*m);
+            }
+            roll -= *w as u64;
+        }
+
+        None
+    }
+}
+
+// Xorshift seeded from the system clock, only used to vary book moves between games
+fn random_u64() -> u64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+    let mut x = nanos | 1;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::move_to_string;
+    use crate::position::STARTPOS_FEN;
+    use crate::uci::parse_move;
+
+    fn play(moves: &[&str]) -> Position {
+        let mut pos = Position::build_from_fen(STARTPOS_FEN);
+        for m in moves {
+            pos = pos.make_move(parse_move(&pos, m).unwrap());
+        }
+        pos
+    }
+
+    // Keys from the Polyglot book format specification, the last one has an en-passant file
+    // that counts because a white pawn stands next to the double-pushed one
+    #[test]
+    fn keys_match_polyglot() {
+        assert_eq!(play(&[]).state.key, 0x463B96181691FC9C);
+        assert_eq!(play(&["e2e4"]).state.key, 0x823C9B50FD114196);
+        assert_eq!(play(&["e2e4", "d7d5"]).state.key, 0x0756B94461C50FB0);
+        assert_eq!(play(&["e2e4", "d7d5", "e4e5"]).state.key, 0x662FAFB965DB29D4);
+        assert_eq!(play(&["e2e4", "d7d5", "e4e5", "f7f5"]).state.key, 0x22A48B5A8E47FF78);
+    }
+
+    #[test]
+    fn probes_by_polyglot_key() {
+        let entry = |key, poly_move, weight| BookEntry { key, poly_move, weight, learn: 0 };
+        let e2e4 = encode_move(parse_move(&play(&[]), "e2e4").unwrap());
+        let d2d4 = encode_move(parse_move(&play(&[]), "d2d4").unwrap());
+        let book = Book { entries: vec![entry(0x463B96181691FC9C, e2e4, 3), entry(0x463B96181691FC9C, d2d4, 1), entry(0x823C9B50FD114196, 0, 1)] };
+
+        let moves: Vec<(String, u16)> = book.moves(&play(&[])).into_iter().map(|(m, w)| (move_to_string(m), w)).collect();
+        assert_eq!(moves, [("e2e4".to_string(), 3), ("d2d4".to_string(), 1)]);
+        assert_eq!(book.pick(&play(&[]), true).map(move_to_string).as_deref(), Some("e2e4"));
+    }
+
+    #[test]
+    fn encodes_castling_as_king_takes_rook() {
+        let pos = play(&["e2e4", "e7e5", "g1f3", "b8c6", "f1c4", "g8f6"]);
+        let castle = encode_move(parse_move(&pos, "e1g1").unwrap());
+        assert_eq!(castle, (4 << 6) | 7);
+    }
+
+    #[test]
+    fn entries_round_trip_through_bytes() {
+        let entry = BookEntry { key: 0x0123456789ABCDEF, poly_move: 0x0A1C, weight: 7, learn: 42 };
+        let read = BookEntry::from_bytes(&entry.to_bytes());
+        assert_eq!((read.key, read.poly_move, read.weight, read.learn), (entry.key, entry.poly_move, entry.weight, entry.learn));
+    }
+}
@@ -0,0 +1,409 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+use crate::eval::eval;
+use crate::makebook::{BookBuilder, BookSettings};
+use crate::output::move_to_string;
+use crate::perft::{divide, perft};
+use crate::position::{Move, Position, STARTPOS_FEN};
+use crate::search::{pvs, NoListener, SearchResult};
+use crate::state::{MAX_PLY, SearchLimits, SearchState};
+use crate::stress::stress;
+use crate::uci::parse_move;
+
+const USAGE: &str = "Usage: piston [command] [--json]
+Without a command the engine speaks UCI, or XBoard once the GUI sends xboard.
+  bench [depth]                                   search the bench positions, prints the node count
+  perft <depth> [fen] [moves]                     counts the leaf nodes, like divide when moves are given
+  divide <depth> [fen] [moves]                    leaf nodes below each move, perftree format
+  search [--fen fen] [--depth n] [--movetime ms] [--nodes n]
+                                                  searches one position, prints the result
+  eval [--fen fen]                                static evaluation, from the side to move
+  makebook [--out file] [--max-ply n] [--min-games n] [--min-rating n] <pgn files>
+                                                  builds a Polyglot book, see the README
+  stress [games] [seed]                           plays random games and validates every position";
+
+// Depth of bench when none is given, about a second in a release build
+const BENCH_DEPTH: u8 = 5;
+
+// Quiet positions from the opening to the endgame. Sharp tactical positions are left out, the
+// quiescence search has no SEE and takes too long on them.
+const BENCH_POSITIONS: [&str; 6] = [
+    STARTPOS_FEN,
+    "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R b KQkq - 3 3",
+    "rnbqkb1r/ppp1pppp/5n2/3p4/3P4/5N2/PPP1PPPP/RNBQKB1R w KQkq - 2 3",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "8/8/4k3/8/2p5/8/B2P2K1/8 w - - 0 1",
+    "6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1",
+];
+
+// Positional arguments, the values of the --name options and whether --json was given
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, String)>,
+    json: bool,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Option<Args> {
+        let mut parsed = Args { positional: Vec::new(), options: Vec::new(), json: false };
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            match arg.strip_prefix("--") {
+                Some("json") => parsed.json = true,
+                Some(name) => parsed.options.push((name.to_string(), iter.next()?.clone())),
+                None => parsed.positional.push(arg.clone()),
+            }
+        }
+        Some(parsed)
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    // Missing options are None, ones that do not parse are an error
+    fn number<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, ()> {
+        self.option(name).map(|v| v.parse().map_err(|_| ())).transpose()
+    }
+
+    fn only(&self, names: &[&str]) -> bool {
+        self.options.iter().all(|(n, _)| names.contains(&n.as_str()))
+    }
+}
+
+// Runs the command named by the first argument. Returns false when there is none, the caller
+// then starts the UCI loop.
+pub fn run(args: &[String]) -> bool {
+    let Some(command) = args.first() else { return false };
+
+    let ok = match command.as_str() {
+        "bench" => Args::parse(&args[1..]).and_then(|a| bench(&a)),
+        "perft" => Args::parse(&args[1..]).and_then(|a| run_perft(&a)),
+        "divide" => Args::parse(&args[1..]).and_then(|a| run_divide(&a)),
+        "search" => Args::parse(&args[1..]).and_then(|a| search(&a)),
+        "eval" => Args::parse(&args[1..]).and_then(|a| run_eval(&a)),
+        "makebook" => Args::parse(&args[1..]).and_then(|a| makebook(&a)),
+        "stress" => Args::parse(&args[1..]).and_then(|a| run_stress(&a)),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Some(())
+        }
+        _ => None,
+    };
+
+    if ok.is_none() {
+        println!("{}", USAGE);
+        std::process::exit(2);
+    }
+    true
+}
+
+fn bench(args: &Args) -> Option<()> {
+    if args.positional.len() > 1 || !args.only(&[]) {
+        return None;
+    }
+    let depth = match args.positional.first() {
+        Some(d) => d.parse::<u8>().ok()?.clamp(1, MAX_PLY as u8),
+        None => BENCH_DEPTH,
+    };
+
+    let mut searches = Vec::new();
+    for fen in BENCH_POSITIONS.iter() {
+        let mut limits = SearchLimits::new();
+        limits.depth = depth;
+        let result = run_search(Position::build_from_fen(fen), limits);
+
+        if !args.json {
+            println!("{:<70} {:>6} {:>12}", fen.trim(), result.best_move.map_or("0000".to_string(), move_to_string), result.nodes);
+        }
+        searches.push((fen.trim(), result));
+    }
+
+    let nodes: u64 = searches.iter().map(|(_, r)| r.nodes).sum();
+    let time: Duration = searches.iter().map(|(_, r)| r.time).sum();
+    let nps = (nodes as u128 * 1000 / time.as_millis().max(1)) as u64;
+
+    if args.json {
+        let positions: Vec<String> = searches.iter().map(|(fen, r)| {
+            format!("{{\"fen\":{},\"bestmove\":{},\"nodes\":{},\"time_ms\":{}}}", json_string(fen), json_move(r.best_move), r.nodes, r.time.as_millis())
+        }).collect();
+        println!("{{\"depth\":{},\"positions\":[{}],\"nodes\":{},\"time_ms\":{},\"nps\":{}}}", depth, positions.join(","), nodes, time.as_millis(), nps);
+    } else {
+        println!();
+        println!("Nodes searched: {}", nodes);
+        println!("Time (ms): {}", time.as_millis());
+        println!("Nodes/second: {}", nps);
+    }
+    Some(())
+}
+
+// With a moves argument, even an empty one, perft prints a divide for perftree scripts
+fn run_perft(args: &Args) -> Option<()> {
+    if args.positional.len() > 2 {
+        return run_divide(args);
+    }
+    if !args.only(&[]) {
+        return None;
+    }
+    let depth = args.positional.first()?.parse::<u8>().ok()?;
+    let fen = fen(args.positional.get(1).map(|f| f.as_str()));
+
+    let clock = Instant::now();
+    let nodes = perft(&position(args, fen), depth);
+    let time = clock.elapsed();
+    let nps = (nodes as u128 * 1000 / time.as_millis().max(1)) as u64;
+
+    if args.json {
+        println!("{{\"fen\":{},\"depth\":{},\"nodes\":{},\"time_ms\":{},\"nps\":{}}}", json_string(fen), depth, nodes, time.as_millis(), nps);
+    } else {
+        println!("Nodes: {}", nodes);
+        println!("Time (ms): {}", time.as_millis());
+        println!("Nodes/second: {}", nps);
+    }
+    Some(())
+}
+
+// The format expected by perftree: one "move count" line per root move, an empty line and the
+// total. Moves may be passed as one argument or several.
+fn run_divide(args: &Args) -> Option<()> {
+    if !args.only(&[]) {
+        return None;
+    }
+    let depth = args.positional.first()?.parse::<u8>().ok()?;
+    let fen = fen(args.positional.get(1).map(|f| f.as_str()));
+
+    let mut pos = position(args, fen);
+    for token in args.positional.iter().skip(2).flat_map(|a| a.split_whitespace()) {
+        // parse_move trusts the GUI, here the move has to be checked against the legal ones
+        match parse_move(&pos, token) {
+            Some(m) if pos.legal_moves().contains(&m) => {
+                pos = pos.make_move(m);
+                pos.state.move_ptr = 0;
+            }
+            _ => fail(args, &format!("Illegal move {}", token)),
+        }
+    }
+
+    let counts = divide(&pos, depth);
+    let total: u64 = counts.iter().map(|(_, c)| c).sum();
+
+    if args.json {
+        let moves: Vec<String> = counts.iter().map(|&(m, c)| format!("{}:{}", json_string(&move_to_string(m)), c)).collect();
+        println!("{{\"fen\":{},\"depth\":{},\"moves\":{{{}}},\"nodes\":{}}}", json_string(&pos.to_fen()), depth, moves.join(","), total);
+    } else {
+        for (m, count) in counts {
+            println!("{} {}", move_to_string(m), count);
+        }
+        println!();
+        println!("{}", total);
+    }
+    Some(())
+}
+
+fn search(args: &Args) -> Option<()> {
+    if !args.positional.is_empty() || !args.only(&["fen", "depth", "movetime", "nodes"]) {
+        return None;
+    }
+    let fen = fen(args.option("fen"));
+
+    let mut limits = SearchLimits::new();
+    if let Some(depth) = args.number::<u8>("depth").ok()? {
+        limits.depth = depth.clamp(1, MAX_PLY as u8);
+    }
+    limits.time = args.number("movetime").ok()?.map(Duration::from_millis);
+    limits.nodes = args.number("nodes").ok()?;
+
+    // Without any limit the search would never return
+    if args.option("depth").is_none() && limits.time.is_none() && limits.nodes.is_none() {
+        return None;
+    }
+
+    let result = run_search(position(args, fen), limits);
+    let pv: Vec<String> = result.pv.iter().map(|&m| move_to_string(m)).collect();
+
+    if args.json {
+        let pv: Vec<String> = pv.iter().map(|m| json_string(m)).collect();
+        println!("{{\"fen\":{},\"bestmove\":{},\"ponder\":{},\"score\":{},\"depth\":{},\"seldepth\":{},\"nodes\":{},\"nps\":{},\"tbhits\":{},\"hashfull\":{},\"time_ms\":{},\"pv\":[{}]}}",
+            json_string(fen), json_move(result.best_move), json_move(result.ponder_move), result.score, result.depth, result.seldepth,
+            result.nodes, result.nps(), result.tb_hits, result.hashfull, result.time.as_millis(), pv.join(","));
+    } else {
+        println!("bestmove {}", result.best_move.map_or("0000".to_string(), move_to_string));
+        println!("score cp {}", result.score);
+        println!("depth {} seldepth {}", result.depth, result.seldepth);
+        println!("nodes {} nps {} time {}", result.nodes, result.nps(), result.time.as_millis());
+        println!("pv {}", pv.join(" "));
+    }
+    Some(())
+}
+
+fn run_eval(args: &Args) -> Option<()> {
+    if !args.positional.is_empty() || !args.only(&["fen"]) {
+        return None;
+    }
+    let fen = fen(args.option("fen"));
+    let score = eval(&position(args, fen));
+
+    if args.json {
+        println!("{{\"fen\":{},\"eval\":{}}}", json_string(fen), score);
+    } else {
+        println!("{}", score);
+    }
+    Some(())
+}
+
+fn makebook(args: &Args) -> Option<()> {
+    if args.positional.is_empty() || !args.only(&["out", "max-ply", "min-games", "min-rating"]) {
+        return None;
+    }
+
+    let mut settings = BookSettings::new();
+    if let Some(out) = args.option("out") {
+        settings.out = out.to_string();
+    }
+    settings.max_ply = args.number("max-ply").ok()?.unwrap_or(settings.max_ply);
+    settings.min_games = args.number("min-games").ok()?.unwrap_or(settings.min_games);
+    settings.min_rating = args.number("min-rating").ok()?.unwrap_or(settings.min_rating);
+    settings.pgn_files = args.positional.clone();
+
+    let mut builder = BookBuilder::new(settings);
+    for path in args.positional.iter() {
+        if let Err(e) = builder.read_pgn(path) {
+            fail(args, &format!("Could not read {}: {}", path, e));
+        }
+    }
+
+    let out = &builder.settings.out;
+    let entries = builder.write().unwrap_or_else(|e| fail(args, &format!("Could not write {}: {}", out, e)));
+
+    if args.json {
+        println!("{{\"out\":{},\"games_read\":{},\"games_used\":{},\"entries\":{}}}", json_string(out), builder.games_read, builder.games_used, entries);
+    } else {
+        println!("Games read: {}", builder.games_read);
+        println!("Games used: {}", builder.games_used);
+        println!("Book entries written: {} ({})", entries, out);
+    }
+    Some(())
+}
+
+// A failed validation exits with 1 after printing the game that led to it
+fn run_stress(args: &Args) -> Option<()> {
+    if args.positional.len() > 2 || !args.only(&[]) {
+        return None;
+    }
+    let games = args.positional.first().map_or(Some(100_000), |g| g.parse::<u64>().ok())?;
+    let seed = args.positional.get(1).map_or(Some(1), |s| s.parse::<u64>().ok())?;
+
+    match stress(games, seed) {
+        Ok(positions) if args.json => println!("{{\"games\":{},\"seed\":{},\"positions\":{}}}", games, seed, positions),
+        Ok(positions) => println!("Played {} games, {} positions validated", games, positions),
+        Err(failure) => {
+            if args.json {
+                let moves: Vec<String> = failure.moves.iter().map(|&m| json_string(&move_to_string(m))).collect();
+                let errors: Vec<String> = failure.errors.iter().map(|e| json_string(e)).collect();
+                println!("{{\"error\":\"Validation failed\",\"start\":{},\"moves\":[{}],\"fen\":{},\"errors\":[{}]}}",
+                    json_string(&failure.start), moves.join(","), json_string(&failure.fen), errors.join(","));
+            } else {
+                failure.print();
+            }
+            std::process::exit(1);
+        }
+    }
+    Some(())
+}
+
+// A search on a fresh table, nothing can stop it but the limits
+fn run_search(root: Position, limits: SearchLimits) -> SearchResult {
+    let mutex = Mutex::new(SearchState::new(Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false))));
+    let mut state = mutex.lock().unwrap();
+    state.root = root;
+    state.limits = limits;
+    state.clock = Instant::now();
+    pvs(&mut state, &mut NoListener)
+}
+
+// The start position when no FEN or startpos is given
+fn fen(given: Option<&str>) -> &str {
+    match given {
+        None | Some("startpos") => STARTPOS_FEN.trim(),
+        Some(fen) => fen.trim(),
+    }
+}
+
+// The position of a FEN given on the command line, exits when it is malformed or illegal
+fn position(args: &Args, fen: &str) -> Position {
+    Position::parse_fen(fen).unwrap_or_else(|e| fail(args, &format!("Invalid FEN: {}", e)))
+}
+
+// Errors other than usage errors exit with 1, printed as {"error": ...} under --json
+fn fail(args: &Args, message: &str) -> ! {
+    if args.json {
+        println!("{{\"error\":{}}}", json_string(message));
+    } else {
+        println!("{}", message);
+    }
+    std::process::exit(1);
+}
+
+fn json_move(m: Option<Move>) -> String {
+    m.map_or("null".to_string(), |m| json_string(&move_to_string(m)))
+}
+
+// Error messages can quote user input, so control characters are escaped as well
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn options_and_positionals() {
+        let parsed = Args::parse(&args("5 --json --fen x --depth 3")).unwrap();
+        assert_eq!(parsed.positional, ["5"]);
+        assert!(parsed.json);
+        assert_eq!((parsed.option("fen"), parsed.option("movetime")), (Some("x"), None));
+        assert_eq!(parsed.number::<u8>("depth"), Ok(Some(3)));
+        assert_eq!(parsed.number::<u8>("fen"), Err(()));
+        assert!(parsed.only(&["fen", "depth"]) && !parsed.only(&["fen"]));
+
+        // An option without a value
+        assert!(Args::parse(&args("--depth")).is_none());
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("a\tb\u{1}"), "\"a\\u0009b\\u0001\"");
+        assert_eq!(json_move(None), "null");
+    }
+
+    #[test]
+    fn searches_are_repeatable() {
+        let search = || {
+            let mut limits = SearchLimits::new();
+            limits.depth = 4;
+            run_search(Position::build_from_fen(BENCH_POSITIONS[5]), limits)
+        };
+        let (first, second) = (search(), search());
+        assert_eq!(first.best_move.map(move_to_string).as_deref(), Some("d1d8"));
+        assert_eq!((first.nodes, first.pv.len()), (second.nodes, second.pv.len()));
+        assert!(first.best_move == second.best_move);
+    }
+}
@@ -3,9 +3,9 @@ use piston::index;
 use crate::movegen::{pseudo_bishop};
 use crate::position::Position;
 
-pub const TERMINATE: i16 = 10001;
 pub const LOSS: i16 = -10000;
 pub const DRAW: i16 = 0;
+pub const TB_WIN: i16 = 9000;
 pub const PIECE_VALUES: [i16; 6] = [100, 300, 300, 500, 1000, 0];
 
 pub fn eval(pos: &Position) -> i16 {
@@ -50,11 +50,10 @@ pub fn eval(pos: &Position) -> i16 {
     let player_obstruction = (player_attacks & pos.player).count_ones();
     let enemy_obstruction = (pos.state.attack_mask & pos.player).count_ones();
 
-    return
-        pos.metrics.material_balance +
+    pos.metrics.material_balance +
         (pos.metrics.advancement >> 1) +
         player_mobility as i16 -
         player_obstruction as i16 +
         enemy_obstruction as i16 -
-        enemy_mobility as i16;
+        enemy_mobility as i16
 }
\ No newline at end of file
@@ -0,0 +1,266 @@
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use crate::bitboard::{BITS, FILES, LUT_BISHOP, RANKS};
+use crate::movegen::{hyperbola_bishop, hyperbola_rook};
+
+// Slider attack backends, chosen with the cargo features. Hyperbola Quintessence needs no
+// tables and is the default, see the README for the comparison.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Backend {
+    Hyperbola,
+    Magic,
+    Pext,
+}
+
+static BMI2: AtomicBool = AtomicBool::new(false);
+
+// Constant unless the pext feature has to check for BMI2 at runtime, it falls back to magics
+#[inline(always)]
+pub fn backend() -> Backend {
+    if cfg!(feature = "pext") {
+        if cfg!(target_feature = "bmi2") || BMI2.load(Relaxed) { Backend::Pext } else { Backend::Magic }
+    } else if cfg!(feature = "magic") {
+        Backend::Magic
+    } else {
+        Backend::Hyperbola
+    }
+}
+
+// Detects BMI2 and builds the tables of the compiled backend, so the first search does not
+// pay for them
+pub fn init() {
+    BMI2.store(pext_available(), Relaxed);
+    match backend() {
+        Backend::Hyperbola => {}
+        Backend::Magic => { LazyLock::force(&MAGIC); }
+        Backend::Pext => { LazyLock::force(&PEXT); }
+    }
+}
+
+pub fn pext_available() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    { std::arch::is_x86_feature_detected!("bmi2") }
+    #[cfg(not(target_arch = "x86_64"))]
+    { false }
+}
+
+// Per square: the relevant occupancy mask, the magic factor and shift, and where the square's
+// attacks start in the shared table
+#[derive(Copy, Clone, Default)]
+struct Entry {
+    mask: u64,
+    magic: u64,
+    shift: u32,
+    offset: usize,
+}
+
+struct Tables {
+    bishops: [Entry; 64],
+    rooks: [Entry; 64],
+    attacks: Vec<u64>,
+}
+
+static MAGIC: LazyLock<Tables> = LazyLock::new(|| Tables::new(true));
+static PEXT: LazyLock<Tables> = LazyLock::new(|| Tables::new(false));
+
+// Edges only matter when they are not on the line the slider moves along
+fn bishop_mask(i: usize) -> u64 {
+    let edges = RANKS[0] | RANKS[7] | FILES[0] | FILES[7];
+    LUT_BISHOP[i] & !edges & !BITS[i]
+}
+
+fn rook_mask(i: usize) -> u64 {
+    let file = FILES[i & 7] & !RANKS[0] & !RANKS[7];
+    let rank = RANKS[i >> 3] & !FILES[0] & !FILES[7];
+    (file | rank) & !BITS[i]
+}
+
+// Subsets of the mask in the order of their PEXT index, carry-rippler
+fn subsets(mask: u64) -> Vec<u64> {
+    let mut subsets = Vec::with_capacity(1 << mask.count_ones());
+    let mut subset: u64 = 0;
+    loop {
+        subsets.push(subset);
+        subset = subset.wrapping_sub(mask) & mask;
+        if subset == 0 { break }
+    }
+    subsets
+}
+
+// xorshift64*, ANDing three numbers gives the sparse candidates magics are found among
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    fn sparse(&mut self) -> u64 {
+        self.next() & self.next() & self.next()
+    }
+}
+
+// Tries random candidates until one maps every occupancy to a slot that is free or already
+// holds the same attacks. The epoch array saves clearing the slots between attempts.
+fn find_magic(mask: u64, occupancies: &[u64], attacks: &[u64], rng: &mut Rng) -> (u64, Vec<u64>) {
+    let bits = mask.count_ones();
+    let mut table = vec![0; 1 << bits];
+    let mut epoch = vec![0u32; 1 << bits];
+    let mut attempt = 0;
+
+    loop {
+        let magic = rng.sparse();
+        if (mask.wrapping_mul(magic) >> 56).count_ones() < 6 {
+            continue;
+        }
+
+        attempt += 1;
+        let mut found = true;
+        for (&occupancy, &attack) in occupancies.iter().zip(attacks.iter()) {
+            let index = (occupancy.wrapping_mul(magic) >> (64 - bits)) as usize;
+            if epoch[index] < attempt {
+                epoch[index] = attempt;
+                table[index] = attack;
+            } else if table[index] != attack {
+                found = false;
+                break;
+            }
+        }
+
+        if found {
+            return (magic, table);
+        }
+    }
+}
+
+impl Tables {
+    #[allow(clippy::needless_range_loop)]
+    fn new(magic: bool) -> Tables {
+        let mut tables = Tables {
+            bishops: [Entry::default(); 64],
+            rooks: [Entry::default(); 64],
+            attacks: Vec::new(),
+        };
+        let mut rng = Rng(0x6D61676963);
+
+        for i in 0..64 {
+            tables.bishops[i] = tables.add(bishop_mask(i), |occupancy| hyperbola_bishop(BITS[i], occupancy, i), magic, &mut rng);
+            tables.rooks[i] = tables.add(rook_mask(i), |occupancy| hyperbola_rook(BITS[i], occupancy, i), magic, &mut rng);
+        }
+        tables
+    }
+
+    fn add(&mut self, mask: u64, reference: impl Fn(u64) -> u64, magic: bool, rng: &mut Rng) -> Entry {
+        let occupancies = subsets(mask);
+        let attacks: Vec<u64> = occupancies.iter().map(|&occupancy| reference(occupancy)).collect();
+        let offset = self.attacks.len();
+        let shift = 64 - mask.count_ones();
+
+        if magic {
+            let (magic, table) = find_magic(mask, &occupancies, &attacks, rng);
+            self.attacks.extend(table);
+            Entry { mask, magic, shift, offset }
+        } else {
+            self.attacks.extend(attacks);
+            Entry { mask, magic: 0, shift, offset }
+        }
+    }
+
+    fn magic_index(entry: &Entry, all: u64) -> usize {
+        entry.offset + ((all & entry.mask).wrapping_mul(entry.magic) >> entry.shift) as usize
+    }
+}
+
+pub fn magic_bishop(all: u64, i: usize) -> u64 {
+    let entry = &MAGIC.bishops[i];
+    MAGIC.attacks[Tables::magic_index(entry, all)]
+}
+
+pub fn magic_rook(all: u64, i: usize) -> u64 {
+    let entry = &MAGIC.rooks[i];
+    MAGIC.attacks[Tables::magic_index(entry, all)]
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "bmi2")]
+fn pext(all: u64, mask: u64) -> u64 {
+    std::arch::x86_64::_pext_u64(all, mask)
+}
+
+// Safety: the CPU must support BMI2, either checked with pext_available or enabled at
+// compile time. backend() only selects PEXT under these conditions.
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn pext_bishop(all: u64, i: usize) -> u64 {
+    let entry = &PEXT.bishops[i];
+    PEXT.attacks[entry.offset + unsafe { pext(all, entry.mask) } as usize]
+}
+
+// Safety: as for pext_bishop
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn pext_rook(all: u64, i: usize) -> u64 {
+    let entry = &PEXT.rooks[i];
+    PEXT.attacks[entry.offset + unsafe { pext(all, entry.mask) } as usize]
+}
+
+// Unsafe only to match the x86_64 signatures, backend() never selects PEXT elsewhere
+#[cfg(not(target_arch = "x86_64"))]
+pub unsafe fn pext_bishop(all: u64, i: usize) -> u64 {
+    magic_bishop(all, i)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub unsafe fn pext_rook(all: u64, i: usize) -> u64 {
+    magic_rook(all, i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Lookup = fn(u64, usize) -> u64;
+    type Reference = fn(u64, u64, usize) -> u64;
+
+    // Every relevant occupancy of every square, once as is and once with random noise on the
+    // squares outside the mask, which must not change the attacks
+    #[allow(clippy::needless_range_loop)]
+    fn check(bishop: Lookup, rook: Lookup) {
+        let mut rng = Rng(0x7E57);
+        for i in 0..64 {
+            let pieces: [(u64, Lookup, Reference); 2] = [
+                (bishop_mask(i), bishop, hyperbola_bishop),
+                (rook_mask(i), rook, hyperbola_rook),
+            ];
+            for (mask, lookup, reference) in pieces {
+                for occupancy in subsets(mask) {
+                    let noisy = occupancy | (rng.next() & !mask & !BITS[i]);
+                    for all in [occupancy, noisy] {
+                        assert_eq!(lookup(all, i), reference(BITS[i], all, i), "square {} occupancy {:016x}", i, all);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn table_sizes() {
+        let bishops: usize = (0..64).map(|i| 1 << bishop_mask(i).count_ones()).sum();
+        let rooks: usize = (0..64).map(|i| 1 << rook_mask(i).count_ones()).sum();
+        assert_eq!((bishops, rooks), (5248, 102400));
+        assert_eq!(MAGIC.attacks.len(), bishops + rooks);
+    }
+
+    #[test]
+    fn magic_matches_hyperbola() {
+        check(magic_bishop, magic_rook);
+    }
+
+    #[test]
+    fn pext_matches_hyperbola() {
+        if pext_available() {
+            check(|all, i| unsafe { pext_bishop(all, i) }, |all, i| unsafe { pext_rook(all, i) });
+        }
+    }
+}
@@ -11,7 +11,22 @@ mod tt;
 mod hash;
 mod eval;
 mod ordering;
+mod book;
+mod makebook;
+mod syzygy;
+mod san;
+mod pgn;
+mod perft;
+mod stress;
+mod magic;
+mod xboard;
+mod cli;
 
 fn main() {
-    uci::uci_loop();
+    let args: Vec<String> = std::env::args().collect();
+    magic::init();
+
+    if !cli::run(&args[1..]) {
+        uci::uci_loop();
+    }
 }
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use crate::book::{BookEntry, encode_move};
+use crate::pgn::{Game, PgnError, parse_games};
+
+pub struct BookSettings {
+    pub out: String,
+    pub max_ply: usize,
+    pub min_games: u32,
+    pub min_rating: u32,
+    pub pgn_files: Vec<String>,
+}
+
+impl BookSettings {
+    pub fn new() -> BookSettings {
+        BookSettings {
+            out: "book.bin".to_string(),
+            max_ply: 20,
+            min_games: 3,
+            min_rating: 0,
+            pgn_files: Vec::new(),
+        }
+    }
+}
+
+// Statistics for one move in one position, score counts 2 per win and 1 per draw
+#[derive(Default, Copy, Clone)]
+pub struct MoveStats {
+    pub games: u32,
+    pub score: u32,
+}
+
+pub struct BookBuilder {
+    pub settings: BookSettings,
+    pub stats: HashMap<(u64, u16), MoveStats>,
+    pub games_read: u64,
+    pub games_used: u64,
+}
+
+impl BookBuilder {
+    pub fn new(settings: BookSettings) -> BookBuilder {
+        BookBuilder {
+            settings,
+            stats: HashMap::new(),
+            games_read: 0,
+            games_used: 0,
+        }
+    }
+
+    // Counts every main line move up to the maximum ply
+    pub fn add_game(&mut self, game: &Game) {
+        self.games_read += 1;
+
+        let white_score = match game.result.as_str() {
+            "1-0" => 2,
+            "0-1" => 0,
+            "1/2-1/2" => 1,
+            _ => return,
+        };
+
+        if self.settings.min_rating > 0 {
+            let rating = |tag: &str| game.tag(tag).and_then(|r| r.parse::<u32>().ok()).unwrap_or(0);
+            if rating("WhiteElo") < self.settings.min_rating || rating("BlackElo") < self.settings.min_rating {
+                return;
+            }
+        }
+
+        self.games_used += 1;
+        for &node in game.mainline().iter().take(self.settings.max_ply) {
+            let (Some(m), Some(parent)) = (game.nodes[node].m, game.nodes[node].parent) else { break };
+            let pos = &game.nodes[parent].position;
+
+            let score = if pos.state.turn { white_score } else { 2 - white_score };
+            let entry = self.stats.entry((pos.state.key, encode_move(m))).or_default();
+            entry.games += 1;
+            entry.score += score;
+        }
+    }
+
+    // Games with errors are counted as read but not used, they do not stop the others
+    pub fn add_pgn(&mut self, text: &str) -> Result<(), PgnError> {
+        for game in parse_games(text)? {
+            match game {
+                Ok(game) => self.add_game(&game),
+                Err(_) => self.games_read += 1,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_pgn(&mut self, path: &str) -> std::io::Result<()> {
+        let text = std::fs::read_to_string(path)?;
+        self.add_pgn(&text).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    // Entries are written sorted by key and then by descending weight
+    pub fn write(&self) -> std::io::Result<usize> {
+        let mut entries: Vec<BookEntry> = Vec::new();
+        let max_score = self.stats.values().map(|s| s.score).max().unwrap_or(0);
+        let scale = if max_score > u16::MAX as u32 { max_score / u16::MAX as u32 + 1 } else { 1 };
+
+        for (&(key, poly_move), stats) in self.stats.iter() {
+            if stats.games < self.settings.min_games || stats.score / scale == 0 {
+                continue;
+            }
+            let weight = (stats.score / scale) as u16;
+            entries.push(BookEntry { key, poly_move, weight, learn: 0 });
+        }
+
+        entries.sort_by(|l, r| l.key.cmp(&r.key).then(r.weight.cmp(&l.weight)));
+
+        let mut writer = BufWriter::new(File::create(&self.settings.out)?);
+        for entry in entries.iter() {
+            writer.write_all(&entry.to_bytes())?;
+        }
+        writer.flush()?;
+
+        Ok(entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgn::parse_pgn;
+
+    #[test]
+    fn counts_main_line_moves() {
+        let mut builder = BookBuilder::new(BookSettings::new());
+        let games = "1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Ba4 Nf6 5. 0-0 Be7 1-0\n\
+            1. e4 e5 2. Nf3 (2. f4) Nc6 3. Bb5 a6 4. Ba4 Nf6 5. O-O b5 1/2-1/2\n\
+            1. e4 e5 2. Ke3 1-0\n\
+            [FEN \"8/8/8/8/8/8/8/8 w - - 0 1\"]\n1. Ke2 *\n\
+            1. d4 *\n\
+            1. e4 c5 0-1\n";
+        builder.add_pgn(games).unwrap();
+        assert_eq!((builder.games_read, builder.games_used), (6, 3));
+
+        // Castling is counted from both games, written as 0-0 and as O-O
+        let first = &parse_pgn("1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Ba4 Nf6 5. O-O *").unwrap()[0];
+        let castle = *first.mainline().last().unwrap();
+        let before = &first.nodes[first.nodes[castle].parent.unwrap()].position;
+        let stats = builder.stats[&(before.state.key, encode_move(first.nodes[castle].m.unwrap()))];
+        assert_eq!((stats.games, stats.score), (2, 3));
+
+        // e4 was played by white in all three games, scoring a win, a draw and a loss
+        let e4 = builder.stats[&(first.nodes[0].position.state.key, encode_move(first.nodes[1].m.unwrap()))];
+        assert_eq!((e4.games, e4.score), (3, 3));
+
+        // The first game's ten moves, b5 and c5, nothing from the variation or the illegal games
+        assert_eq!(builder.stats.len(), 12);
+    }
+}
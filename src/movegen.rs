@@ -1,4 +1,5 @@
 use crate::bitboard::{ANTIDIAGS, DIAGONALS, FILES, LUT_KING, LUT_KNIGHT, LUT_RANK_SLIDE};
+use crate::magic::{backend, magic_bishop, magic_rook, pext_bishop, pext_rook, Backend};
 
 pub fn pseudo_push(pawns: u64, all: u64, shift_offset: u8) -> u64 {
     ((pawns << 8) >> shift_offset) & !all
@@ -16,8 +17,28 @@ pub fn pseudo_knight(i: usize) -> u64 {
     LUT_KNIGHT[i]
 }
 
-// Pseudo-legal bishop moves, uses hyperbola quintessence
+// Pseudo-legal bishop moves, from the selected slider backend
 pub fn pseudo_bishop(p: u64, all: u64, i: usize) -> u64 {
+    match backend() {
+        Backend::Hyperbola => hyperbola_bishop(p, all, i),
+        Backend::Magic => magic_bishop(all, i),
+        // Safety: PEXT is only selected when BMI2 is available
+        Backend::Pext => unsafe { pext_bishop(all, i) },
+    }
+}
+
+// Pseudo-legal rook moves, from the selected slider backend
+pub fn pseudo_rook(p: u64, all: u64, i: usize) -> u64 {
+    match backend() {
+        Backend::Hyperbola => hyperbola_rook(p, all, i),
+        Backend::Magic => magic_rook(all, i),
+        // Safety: PEXT is only selected when BMI2 is available
+        Backend::Pext => unsafe { pext_rook(all, i) },
+    }
+}
+
+// Bishop moves using hyperbola quintessence
+pub fn hyperbola_bishop(p: u64, all: u64, i: usize) -> u64 {
     let mask_d:  u64   = DIAGONALS[i];
     let mask_a:  u64   = ANTIDIAGS[i];
     let d:       u64   = all & mask_d;
@@ -29,8 +50,8 @@ pub fn pseudo_bishop(p: u64, all: u64, i: usize) -> u64 {
     (moves_d & mask_d) | (moves_a & mask_a)
 }
 
-// Pseudo-legal rook moves, uses hyperbola quintessence + rank lookup
-pub fn pseudo_rook(p: u64, all: u64, i: usize) -> u64 {
+// Rook moves using hyperbola quintessence + rank lookup
+pub fn hyperbola_rook(p: u64, all: u64, i: usize) -> u64 {
     let file:       usize = i & 7;
     let mask_l:     u64   = FILES[file];
     let rank_shift: usize = 56 ^ i & 56;
@@ -4,10 +4,9 @@ use crate::eval::PIECE_VALUES;
 use crate::ordering::PickerStage::*;
 use crate::state::{MAX_MOVE_COUNT, SearchState};
 
-pub fn add_killer(ply: u8, state: &mut MutexGuard<SearchState>, m: Move) {
-    let table = &mut state.killer_table;
-    let index = ply as usize % table.len();
-    let ply_arr = &mut table[index];
+// Killers are kept per search ply, the search never goes beyond MAX_PLY
+pub fn add_killer(ply: usize, state: &mut MutexGuard<SearchState>, m: Move) {
+    let ply_arr = &mut state.killer_table[ply];
     ply_arr[1] = ply_arr[0];
     ply_arr[0] = m;
 }
@@ -33,10 +32,14 @@ pub struct PVSPicker<'a> {
     pub quiet_count: usize,
     pub scores: [i16; 256],
     pub ply: usize,
+    pub excluded: Option<Move>,
 }
 
 impl PVSPicker<'_> {
-    pub fn new(pos: &mut Position, ttmove: Option<Move>, ply: usize) -> PVSPicker {
+    pub fn new(pos: &mut Position, ttmove: Option<Move>, ply: usize, excluded: Option<Move>) -> PVSPicker<'_> {
+        // The position may have been searched before, a re-search or a singular search,
+        // and generating appends after the moves left from that search
+        pos.state.move_cnt = 0;
         PVSPicker {
             pos,
             ttmove,
@@ -44,32 +47,43 @@ impl PVSPicker<'_> {
             tactical_count: 0,
             quiet_count: 0,
             scores: [0; 256],
-            ply: ply % 64,
+            ply,
+            excluded,
+        }
+    }
+
+    // Skips the excluded move of a singular extension search
+    pub fn next(&mut self, state: &mut MutexGuard<SearchState>) -> (Option<Move>, Option<Position>) {
+        loop {
+            let (m, node) = self.pick(state);
+            if m.is_none() || m != self.excluded {
+                return (m, node);
+            }
         }
     }
 
     // use buffer and counters for each stage to generalize
     // use a score array to pick from, end when counter is 0
-    pub fn next(&mut self, state: &mut MutexGuard<SearchState>) -> (Option<Move>, Option<Position>) {
+    #[allow(clippy::needless_range_loop)]
+    fn pick(&mut self, state: &mut MutexGuard<SearchState>) -> (Option<Move>, Option<Position>) {
         let list = &mut state.move_table[self.pos.state.move_ptr..self.pos.state.move_ptr+MAX_MOVE_COUNT];
         match self.stage {
             TTMove => {
 
                 self.stage = Killer1;
-                if self.ttmove.is_some() {
-                    let m = self.ttmove.unwrap();
-                    return (Some(m), Some(self.pos.make_move(m)));
+                match self.ttmove {
+                    Some(m) if self.pos.is_legal(m) => (Some(m), Some(self.pos.make_move(m))),
+                    _ => self.pick(state),
                 }
-                return self.next(state);
             }
 
             Killer1 => {
                 let m = state.killer_table[self.ply][0];
                 self.stage = Killer2;
-                return if self.pos.killer_is_legal(m) {
+                if is_quiet(self.pos, m) && self.pos.is_legal(m) {
                     (Some(m), Some(self.pos.make_move(m)))
                 } else {
-                    self.next(state)
+                    self.pick(state)
                 }
 
             }
@@ -78,10 +92,10 @@ impl PVSPicker<'_> {
 
                 let m = state.killer_table[self.ply][1];
                 self.stage = GenTactical;
-                return if self.pos.killer_is_legal(m) {
+                if is_quiet(self.pos, m) && self.pos.is_legal(m) {
                     (Some(m), Some(self.pos.make_move(m)))
                 } else {
-                    self.next(state)
+                    self.pick(state)
                 }
             }
 
@@ -92,11 +106,11 @@ impl PVSPicker<'_> {
 
                 for i in 0..self.tactical_count {
                     let m = list[i];
-                    self.scores[i] = tactical_score(&self.pos, m);
+                    self.scores[i] = tactical_score(self.pos, m);
                 }
 
                 self.stage = HighPrio;
-                self.next(state)
+                self.pick(state)
             }
 
             HighPrio => {
@@ -111,11 +125,11 @@ impl PVSPicker<'_> {
 
                 if max < 0 {
                     self.stage = GenQuiet;
-                    return self.next(state);
+                    self.pick(state)
                 } else {
                     self.scores[i] = i16::MIN;
                     let m = list[i];
-                    return (Some(m), Some(self.pos.make_move(m)));
+                    (Some(m), Some(self.pos.make_move(m)))
                 }
             }
 
@@ -126,11 +140,11 @@ impl PVSPicker<'_> {
 
                 for i in self.tactical_count..self.pos.state.move_cnt {
                     let m = list[i];
-                    self.scores[i] = quiet_score(&self.pos, m);
+                    self.scores[i] = quiet_score(self.pos, m);
                 }
 
                 self.stage = Quiet;
-                self.next(state)
+                self.pick(state)
             }
 
             Quiet => {
@@ -146,11 +160,11 @@ impl PVSPicker<'_> {
 
                 if max == i16::MIN {
                     self.stage = LowPrio;
-                    return self.next(state);
+                    self.pick(state)
                 } else {
                     self.scores[i] = i16::MIN;
                     let m = list[i];
-                    return (Some(m), Some(self.pos.make_move(m)));
+                    (Some(m), Some(self.pos.make_move(m)))
                 }
             }
 
@@ -166,11 +180,11 @@ impl PVSPicker<'_> {
 
                 if max == i16::MIN {
                     self.stage = End;
-                    return self.next(state);
+                    self.pick(state)
                 } else {
                     self.scores[i] = i16::MIN;
                     let m = list[i];
-                    return (Some(m), Some(self.pos.make_move(m)));
+                    (Some(m), Some(self.pos.make_move(m)))
                 }
             }
 
@@ -181,43 +195,31 @@ impl PVSPicker<'_> {
     }
 }
 
-pub fn target_value(pos: &Position, target: u64) -> i16 {
-    if pos.pawns & target != 0 {
-        return PIECE_VALUES[0];
-    }
-
-    if pos.knights & target != 0 {
-        return PIECE_VALUES[1];
-    }
-
-    if pos.bishops & target != 0 {
-        return PIECE_VALUES[2];
-    }
-
-    if pos.rooks & target != 0 {
-        return PIECE_VALUES[3];
-    }
+// Killers are quiet moves, captures are left to the tactical stages
+fn is_quiet(pos: &Position, m: Move) -> bool {
+    m.code() == 0 && m.target() & pos.all == 0
+}
 
-    if pos.queens & target != 0 {
-        return PIECE_VALUES[4];
+pub fn target_value(pos: &Position, target_index: usize) -> i16 {
+    match pos.piece_on(target_index) {
+        tier @ 0..=4 => PIECE_VALUES[tier],
+        _ => 0,
     }
-
-    0
 }
 
 pub fn tactical_score(pos: &Position, m: Move) -> i16 {
-    if m.code == 8 {
+    if m.code() == 8 {
         return PIECE_VALUES[0];
     }
 
-    let target_value = target_value(pos, m.target);
-    let self_value = PIECE_VALUES[m.tier as usize];
+    let target_value = target_value(pos, m.target_index());
+    let self_value = PIECE_VALUES[pos.piece_on(m.origin_index())];
 
-    if m.code != 0 && m.code <= 4 {
-        return PIECE_VALUES[m.code as usize] + target_value;
+    if m.code() != 0 && m.code() <= 4 {
+        return PIECE_VALUES[m.code() as usize] + target_value;
     }
 
-    if m.target & pos.state.attack_mask == 0 {
+    if m.target() & pos.state.attack_mask == 0 {
         return target_value;
     }
 
@@ -229,20 +231,21 @@ pub fn tactical_score(pos: &Position, m: Move) -> i16 {
 }
 
 pub fn quiet_score(pos: &Position, m: Move) -> i16 {
-    if m.code == 5 {
+    if m.code() == 5 {
         return 160;
     }
 
-    if m.code == 6 || m.code == 7 {
+    if m.code() == 6 || m.code() == 7 {
         return 400;
     }
 
-    if m.origin & pos.state.attack_mask != 0 {
-        return PIECE_VALUES[m.tier as usize];
+    let tier = pos.piece_on(m.origin_index());
+    if m.origin() & pos.state.attack_mask != 0 {
+        return PIECE_VALUES[tier];
     }
 
-    if m.target & pos.state.attack_mask == 0 && m.tier != 5 {
-        return PIECE_VALUES[m.tier as usize] >> 1;
+    if m.target() & pos.state.attack_mask == 0 && tier != 5 {
+        return PIECE_VALUES[tier] >> 1;
     }
 
     0
@@ -1,11 +1,11 @@
 extern crate colored;
 use colored::Colorize;
 use std::ops::Add;
-use std::sync::MutexGuard;
 use piston::index;
 use crate::bitboard::BITS;
 use crate::position::{BLACK_LONG_CASTLE, BLACK_SHORT_CASTLE, FILE_CHARS, Move, Position, PositionState, WHITE_LONG_CASTLE, WHITE_SHORT_CASTLE};
-use crate::state::{SearchState, SearchStats};
+use crate::state::SearchStats;
+use crate::tt::{AGE_CLASSES, TTEntry, TTStats};
 
 pub fn string_to_index(str: &str) -> usize {
     if str == "-" {
@@ -23,7 +23,7 @@ pub fn index_to_string(index: usize) -> String {
         return "-".to_string()
     }
 
-    FILE_CHARS[index & 7].to_string().add((index >> 3).to_string().as_str())
+    FILE_CHARS[index & 7].to_string().add(((index >> 3) + 1).to_string().as_str())
 }
 
 pub trait Display {
@@ -31,6 +31,7 @@ pub trait Display {
 }
 
 impl Display for u64 {
+    #[allow(clippy::needless_range_loop)]
     fn print(&self) {
         for i in 0 .. 64 {
             let bit: u64 = BITS[i].swap_bytes();
@@ -42,22 +43,24 @@ impl Display for u64 {
     }
 }
 
+// Coordinate notation as used by UCI, e.g. e2e4 or a7a8q
+pub fn move_to_string(m: Move) -> String {
+    let mut string = index_to_string(m.origin_index()) + &index_to_string(m.target_index());
+    if m.code() != 0 && m.code() < 5 {
+        let promos = " nbrq";
+        string.push_str(&promos[m.code() as usize..m.code() as usize + 1]);
+    }
+    string
+}
+
 impl Display for Move {
     fn print(&self) {
-        let origin_file = (self.origin.leading_zeros() & 7) as usize;
-        let origin_rank = (self.origin.leading_zeros() >> 3) + 1;
-        let target_file = (self.target.leading_zeros() & 7) as usize;
-        let target_rank = (self.target.leading_zeros() >> 3) + 1;
-        print!("{}{}{}{}", FILE_CHARS[origin_file], origin_rank, FILE_CHARS[target_file], target_rank);
-        if self.code != 0 && self.code < 5 {
-            let promos = " nbrq";
-            print!("{}", &promos[self.code as usize..self.code as usize + 1]);
-        }
-        print!(" ");
+        print!("{} ", move_to_string(*self));
     }
 }
 
 impl Display for Position {
+    #[allow(clippy::needless_range_loop)]
     fn print(&self) {
         let pieces:   &str = "pnbrqk PNBRQK ";
         let row:      &str = "+───+───+───+───+───+───+───+───+";
@@ -69,7 +72,7 @@ impl Display for Position {
         println!("{}", row);
         for i in 0..64 {
             let bit:    u64   = BITS[i].swap_bytes();
-            let index:  usize = self.square_tier(bit) + (7 * ((bit & white != 0) as usize));
+            let index:  usize = self.piece_on(i ^ 56) + (7 * ((bit & white != 0) as usize));
             let symbol: &str  = &pieces[index..index+1];
             if self.state.turn != (bit & self.player != 0) { print!("│ {} ", symbol.truecolor(148, 95,  235)) }
             else                                            { print!("| {} ", symbol.truecolor(255, 204, 153)) }
@@ -102,26 +105,29 @@ impl Display for PositionState {
 
 impl Display for SearchStats {
     fn print(&self) {
-        println!("Perft-search nodes: {}", self.perft_nodes);
         println!("PV-search nodes: {}", self.pvs_nodes);
         println!("Q-search nodes: {}", self.qs_nodes);
         println!("Beta cutoffs: {}", self.beta_cutoffs);
         println!("TT hitrate: {}", self.table_hits as f64 / self.table_probes as f64);
+        println!("Tablebase hits: {}", self.tb_hits);
+        println!("Selective depth: {}", self.seldepth);
     }
 }
 
-pub fn print_pv(root: &Position, state: &mut MutexGuard<SearchState>) {
-    print!("pv ");
-    let mut curr = root.clone();
-
-    while let Some(entry) = state.hash_table.probe(curr.state.key) {
-        let m = entry.get_refutation();
-
-        if m.origin == m.target {
-            break
+impl Display for TTStats {
+    fn print(&self) {
+        let percent = |n: u64, total: u64| 100.0 * n as f64 / total.max(1) as f64;
+        let megabytes = (self.entries * size_of::<TTEntry>() as u64) >> 20;
+        println!("Entries: {} ({} MB)", self.entries, megabytes);
+        println!("Used: {} ({:.1}%)", self.used, percent(self.used, self.entries));
+
+        let buckets: u64 = self.buckets.iter().sum();
+        for (fill, &n) in self.buckets.iter().enumerate() {
+            println!("Buckets with {} entries: {} ({:.1}%)", fill, n, percent(n, buckets));
+        }
+        for (age, &n) in self.ages.iter().enumerate() {
+            let plus = if age == AGE_CLASSES - 1 { "+" } else { "" };
+            println!("Written {}{} searches ago: {} ({:.1}%)", age, plus, n, percent(n, self.used));
         }
-
-        m.print();
-        curr = curr.make_move(m);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,105 @@
+use crate::position::{Move, Position};
+
+// Counts the leaf nodes at the given depth, the last ply is counted without making the moves
+pub fn perft(pos: &Position, depth: u8) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+
+    let moves = pos.legal_moves();
+    if depth == 1 {
+        return moves.len() as u64;
+    }
+
+    moves.iter().map(|&m| perft(&pos.make_move(m), depth - 1)).sum()
+}
+
+// Leaf node counts below each legal move
+pub fn divide(pos: &Position, depth: u8) -> Vec<(Move, u64)> {
+    pos.legal_moves().into_iter().map(|m| (m, perft(&pos.make_move(m), depth.saturating_sub(1)))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::position::STARTPOS_FEN;
+
+    // Node limit for the regular test run, deeper counts run with --ignored
+    const QUICK_NODES: u64 = 1_000_000;
+
+    const SUITE: &str = include_str!("../tests/perft.epd");
+
+    // Standard positions from the chessprogramming wiki
+    const STANDARD: [(&str, &[u64]); 7] = [
+        (STARTPOS_FEN, &[20, 400, 8902, 197281, 4865609, 119060324]),
+        ("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", &[48, 2039, 97862, 4085603, 193690690]),
+        ("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", &[14, 191, 2812, 43238, 674624, 11030083]),
+        ("r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1", &[6, 264, 9467, 422333, 15833292]),
+        ("r2q1rk1/pP1p2pp/Q4n2/bbp1p3/Np6/1B3NBn/pPPP1PPP/R3K2R b KQ - 0 1", &[6, 264, 9467, 422333, 15833292]),
+        ("rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8", &[44, 1486, 62379, 2103487, 89941194]),
+        ("r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10", &[46, 2079, 89890, 3894594, 164075551]),
+    ];
+
+    fn parse_suite() -> Vec<(String, Vec<(u8, u64)>)> {
+        SUITE.lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let mut fields = line.split(';');
+                let fen = fields.next().unwrap().trim().to_string();
+                let counts = fields.map(|field| {
+                    let (depth, count) = field.trim().split_once(' ').unwrap();
+                    (depth.trim_start_matches('D').parse().unwrap(), count.trim().parse().unwrap())
+                }).collect();
+                (fen, counts)
+            })
+            .collect()
+    }
+
+    fn check(fen: &str, counts: &[(u8, u64)], max_nodes: u64) {
+        let pos = Position::build_from_fen(fen);
+        for &(depth, expected) in counts.iter().filter(|(_, count)| *count <= max_nodes) {
+            assert_eq!(perft(&pos, depth), expected, "perft {} of {}", depth, fen);
+        }
+    }
+
+    fn standard_counts(counts: &[u64]) -> Vec<(u8, u64)> {
+        counts.iter().enumerate().map(|(i, &c)| (i as u8 + 1, c)).collect()
+    }
+
+    #[test]
+    fn standard_positions() {
+        for (fen, counts) in STANDARD.iter() {
+            check(fen, &standard_counts(counts), QUICK_NODES);
+        }
+    }
+
+    #[test]
+    fn suite() {
+        for (fen, counts) in parse_suite() {
+            check(&fen, &counts, QUICK_NODES);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn standard_positions_deep() {
+        for (fen, counts) in STANDARD.iter() {
+            check(fen, &standard_counts(counts), u64::MAX);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn suite_deep() {
+        for (fen, counts) in parse_suite() {
+            check(&fen, &counts, u64::MAX);
+        }
+    }
+
+    #[test]
+    fn divide_sums_to_perft() {
+        let pos = Position::build_from_fen(STANDARD[1].0);
+        let total: u64 = divide(&pos, 3).iter().map(|(_, c)| c).sum();
+        assert_eq!(total, perft(&pos, 3));
+    }
+}
@@ -0,0 +1,568 @@
+use std::fmt;
+use crate::position::{Move, Position, STARTPOS_FEN};
+
+pub const SEVEN_TAG_ROSTER: [&str; 7] = ["Event", "Site", "Date", "Round", "White", "Black", "Result"];
+
+const LINE_LENGTH: usize = 80;
+
+#[derive(Debug)]
+pub struct PgnError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PgnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// One node of the game tree, holding the position reached by its move
+#[derive(Clone)]
+pub struct GameNode {
+    pub position: Position,
+    pub m: Option<Move>,        // None for the root
+    pub san: String,
+    pub nags: Vec<u8>,
+    pub comments: Vec<String>,  // comments following the move
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,   // the first child continues the line, the others are variations
+}
+
+pub struct Game {
+    pub tags: Vec<(String, String)>,
+    pub nodes: Vec<GameNode>,
+    pub result: String,
+}
+
+impl Game {
+    pub fn new(start: Position) -> Game {
+        let root = GameNode {
+            position: start,
+            m: None,
+            san: String::new(),
+            nags: Vec::new(),
+            comments: Vec::new(),
+            parent: None,
+            children: Vec::new(),
+        };
+        Game { tags: Vec::new(), nodes: vec![root], result: "*".to_string() }
+    }
+
+    // Starts a game from the FEN tag if there is one, otherwise from the standard position.
+    // The FEN comes from the file, so it is checked before the game is built on it.
+    pub fn from_tags(tags: Vec<(String, String)>) -> Result<Game, String> {
+        let fen = tags.iter().find(|(name, _)| name == "FEN").map(|(_, value)| value.as_str());
+        let start = Position::parse_fen(fen.unwrap_or(STARTPOS_FEN)).map_err(|e| format!("invalid FEN: {}", e))?;
+        let mut game = Game::new(start);
+        if let Some(result) = tags.iter().find(|(name, _)| name == "Result") {
+            game.result = result.1.clone();
+        }
+        game.tags = tags;
+        Ok(game)
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn set_tag(&mut self, name: &str, value: &str) {
+        match self.tags.iter_mut().find(|(n, _)| n == name) {
+            Some(tag) => tag.1 = value.to_string(),
+            None => self.tags.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    // Appends a legal move below the given node and returns the new node
+    pub fn add_move(&mut self, parent: usize, m: Move) -> usize {
+        let pos = &self.nodes[parent].position;
+        let san = pos.move_to_san(m);
+        let mut position = pos.make_move(m);
+        position.state.move_ptr = 0;
+
+        let index = self.nodes.len();
+        self.nodes.push(GameNode {
+            position,
+            m: Some(m),
+            san,
+            nags: Vec::new(),
+            comments: Vec::new(),
+            parent: Some(parent),
+            children: Vec::new(),
+        });
+        self.nodes[parent].children.push(index);
+        index
+    }
+
+    // Nodes of the main line, excluding the root
+    pub fn mainline(&self) -> Vec<usize> {
+        let mut line = Vec::new();
+        let mut node = 0;
+        while let Some(&next) = self.nodes[node].children.first() {
+            line.push(next);
+            node = next;
+        }
+        line
+    }
+
+    pub fn to_pgn(&self) -> String {
+        let mut out = String::new();
+
+        for name in SEVEN_TAG_ROSTER {
+            let value = match name {
+                "Result" => self.result.as_str(),
+                "Date" => self.tag(name).unwrap_or("????.??.??"),
+                _ => self.tag(name).unwrap_or("?"),
+            };
+            out.push_str(&format_tag(name, value));
+        }
+
+        let start_fen = self.nodes[0].position.to_fen();
+        let standard_start = Position::build_from_fen(STARTPOS_FEN).to_fen() == start_fen;
+        for (name, value) in self.tags.iter() {
+            if SEVEN_TAG_ROSTER.contains(&name.as_str()) || name == "SetUp" || name == "FEN" {
+                continue;
+            }
+            out.push_str(&format_tag(name, value));
+        }
+        if !standard_start {
+            out.push_str(&format_tag("SetUp", "1"));
+            out.push_str(&format_tag("FEN", self.tag("FEN").unwrap_or(&start_fen)));
+        }
+        out.push('\n');
+
+        let mut tokens = Vec::new();
+        for comment in self.nodes[0].comments.iter() {
+            tokens.push(format!("{{{}}}", comment));
+        }
+        self.write_line(0, &mut tokens, true);
+        tokens.push(self.result.clone());
+
+        let mut line = String::new();
+        for token in tokens {
+            if !line.is_empty() && line.len() + token.len() + 1 > LINE_LENGTH {
+                out.push_str(&line);
+                out.push('\n');
+                line.clear();
+            }
+            // Parentheses are written without spaces on their inner side
+            if !line.is_empty() && !line.ends_with('(') && token != ")" {
+                line.push(' ');
+            }
+            line.push_str(&token);
+        }
+        out.push_str(&line);
+        out.push_str("\n\n");
+        out
+    }
+
+    // Writes the line continuing from node, with variations in parentheses after each move
+    fn write_line(&self, mut node: usize, tokens: &mut Vec<String>, mut force_number: bool) {
+        while let Some(&main) = self.nodes[node].children.first() {
+            force_number = self.write_move(main, tokens, force_number);
+
+            for &variation in self.nodes[node].children[1..].iter() {
+                tokens.push("(".to_string());
+                let after_comment = self.write_move(variation, tokens, true);
+                self.write_line(variation, tokens, after_comment);
+                tokens.push(")".to_string());
+                force_number = true;
+            }
+
+            node = main;
+        }
+    }
+
+    // Returns whether the next move needs its number repeated
+    fn write_move(&self, node: usize, tokens: &mut Vec<String>, force_number: bool) -> bool {
+        let parent = &self.nodes[self.nodes[node].parent.unwrap()].position;
+        let number = parent.state.half_move / 2 + 1;
+
+        if parent.state.turn {
+            tokens.push(format!("{}.", number));
+        } else if force_number {
+            tokens.push(format!("{}...", number));
+        }
+
+        tokens.push(self.nodes[node].san.clone());
+        for nag in self.nodes[node].nags.iter() {
+            tokens.push(format!("${}", nag));
+        }
+        for comment in self.nodes[node].comments.iter() {
+            tokens.push(format!("{{{}}}", comment));
+        }
+
+        !self.nodes[node].comments.is_empty()
+    }
+}
+
+fn format_tag(name: &str, value: &str) -> String {
+    format!("[{} \"{}\"]\n", name, value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Tag(String, String),
+    Comment(String),
+    Open,
+    Close,
+    Nag(u8),
+    Result(String),
+    San(String),
+}
+
+// Splits PGN text into tokens paired with their line numbers
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, PgnError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+
+    let error = |line: usize, message: &str| PgnError { line, message: message.to_string() };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let line_start = i == 0 || chars[i - 1] == '\n';
+
+        match c {
+            '\n' => {
+                line += 1;
+                i += 1;
+            }
+            _ if c.is_whitespace() => i += 1,
+
+            // Escaped lines are ignored
+            '%' if line_start => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+
+            '[' => {
+                let start_line = line;
+                let mut name = String::new();
+                let mut value = String::new();
+                i += 1;
+                while i < chars.len() && !chars[i].is_whitespace() && chars[i] != ']' {
+                    name.push(chars[i]);
+                    i += 1;
+                }
+                while i < chars.len() && chars[i] != '"' && chars[i] != ']' {
+                    i += 1;
+                }
+                if i < chars.len() && chars[i] == '"' {
+                    i += 1;
+                    while i < chars.len() && chars[i] != '"' {
+                        if chars[i] == '\\' && i + 1 < chars.len() {
+                            i += 1;
+                        }
+                        if chars[i] == '\n' {
+                            return Err(error(start_line, "unterminated tag value"));
+                        }
+                        value.push(chars[i]);
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                while i < chars.len() && chars[i] != ']' {
+                    i += 1;
+                }
+                if i >= chars.len() || name.is_empty() {
+                    return Err(error(start_line, "malformed tag"));
+                }
+                i += 1;
+                tokens.push((start_line, Token::Tag(name, value)));
+            }
+
+            '{' => {
+                let start_line = line;
+                let mut comment = String::new();
+                i += 1;
+                while i < chars.len() && chars[i] != '}' {
+                    if chars[i] == '\n' {
+                        line += 1;
+                    }
+                    comment.push(chars[i]);
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(error(start_line, "unterminated comment"));
+                }
+                i += 1;
+                tokens.push((start_line, Token::Comment(comment.split_whitespace().collect::<Vec<_>>().join(" "))));
+            }
+
+            ';' => {
+                let mut comment = String::new();
+                i += 1;
+                while i < chars.len() && chars[i] != '\n' {
+                    comment.push(chars[i]);
+                    i += 1;
+                }
+                tokens.push((line, Token::Comment(comment.trim().to_string())));
+            }
+
+            '(' => {
+                tokens.push((line, Token::Open));
+                i += 1;
+            }
+
+            ')' => {
+                tokens.push((line, Token::Close));
+                i += 1;
+            }
+
+            '$' => {
+                let mut number = String::new();
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    number.push(chars[i]);
+                    i += 1;
+                }
+                let nag = number.parse().map_err(|_| error(line, "malformed NAG"))?;
+                tokens.push((line, Token::Nag(nag)));
+            }
+
+            _ => {
+                let mut symbol = String::new();
+                while i < chars.len() && !chars[i].is_whitespace() && !"[]{}();$".contains(chars[i]) {
+                    symbol.push(chars[i]);
+                    i += 1;
+                }
+                push_symbol(&mut tokens, line, &symbol);
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Classifies a symbol as a result, a move number or a move with optional suffix annotations
+fn push_symbol(tokens: &mut Vec<(usize, Token)>, line: usize, symbol: &str) {
+    if matches!(symbol, "1-0" | "0-1" | "1/2-1/2" | "*") {
+        tokens.push((line, Token::Result(symbol.to_string())));
+        return;
+    }
+
+    // Move numbers are digits followed by periods, 0-0 starts with a digit as well
+    let number = symbol.trim_start_matches(|c: char| c.is_ascii_digit());
+    let symbol = if number.is_empty() || number.starts_with('.') { number.trim_start_matches('.') } else { symbol };
+    let san = symbol.trim_end_matches(['!', '?']);
+    if !san.is_empty() {
+        tokens.push((line, Token::San(san.to_string())));
+    }
+
+    let nag = match &symbol[san.len()..] {
+        "!" => 1,
+        "?" => 2,
+        "!!" => 3,
+        "??" => 4,
+        "!?" => 5,
+        "?!" => 6,
+        _ => return,
+    };
+    tokens.push((line, Token::Nag(nag)));
+}
+
+// Parses every game in a PGN text. Games end at their result, or where the tags of the next game begin.
+pub fn parse_pgn(text: &str) -> Result<Vec<Game>, PgnError> {
+    parse_games(text)?.into_iter().collect()
+}
+
+// Like parse_pgn, but a game with an illegal move or a broken variation does not stop the
+// games after it. Only errors of the tokenizer fail the whole text.
+pub fn parse_games(text: &str) -> Result<Vec<Result<Game, PgnError>>, PgnError> {
+    let tokens = tokenize(text)?;
+    let mut games = Vec::new();
+    let mut start = 0;
+
+    while start < tokens.len() {
+        let mut end = start;
+        let mut in_moves = false;
+        while end < tokens.len() {
+            match &tokens[end].1 {
+                Token::Tag(..) if in_moves => break,
+                Token::Tag(..) => {}
+                Token::Result(_) => {
+                    end += 1;
+                    break;
+                }
+                _ => in_moves = true,
+            }
+            end += 1;
+        }
+
+        games.push(build_game(&tokens[start..end]));
+        start = end;
+    }
+
+    Ok(games)
+}
+
+fn build_game(tokens: &[(usize, Token)]) -> Result<Game, PgnError> {
+    let tags = tokens.iter()
+        .filter_map(|(_, t)| if let Token::Tag(name, value) = t { Some((name.clone(), value.clone())) } else { None })
+        .collect();
+    let fen_line = tokens.iter().find(|(_, t)| matches!(t, Token::Tag(name, _) if name == "FEN")).map(|(l, _)| *l);
+    let mut game = Game::from_tags(tags).map_err(|message| PgnError { line: fen_line.unwrap_or(0), message })?;
+
+    let mut node = 0;
+    let mut stack = Vec::new();
+
+    for (line, token) in tokens.iter() {
+        let error = |message: String| PgnError { line: *line, message };
+
+        match token {
+            Token::Tag(..) => {}
+
+            Token::San(san) => {
+                let m = game.nodes[node].position.parse_san(san)
+                    .map_err(|e| error(format!("{:?} move {}", e, san)))?;
+                node = game.add_move(node, m);
+            }
+
+            Token::Nag(nag) => game.nodes[node].nags.push(*nag),
+
+            Token::Comment(comment) => game.nodes[node].comments.push(comment.clone()),
+
+            // A variation replaces the last move, so it starts from that move's parent
+            Token::Open => {
+                let parent = game.nodes[node].parent.ok_or_else(|| error("variation before any move".to_string()))?;
+                stack.push(node);
+                node = parent;
+            }
+
+            Token::Close => node = stack.pop().ok_or_else(|| error("unmatched ')'".to_string()))?,
+
+            Token::Result(result) => game.result = result.clone(),
+        }
+    }
+
+    if !stack.is_empty() {
+        let line = tokens.last().map(|(l, _)| *l).unwrap_or(0);
+        return Err(PgnError { line, message: "unterminated variation".to_string() });
+    }
+
+    Ok(game)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::move_to_string;
+
+    const GAME: &str = r#"[Event "Test"]
+[Site "?"]
+[Date "2024.01.01"]
+[Round "1"]
+[White "A"]
+[Black "B"]
+[Result "1-0"]
+
+{Opening} 1. e4 e5 2. Nf3 $1 (2. f4?! exf4 3. Nf3 {King's gambit} (3. Bc4) 3... g5) 2... Nc6
+3. Bb5 a6 ; the Morphy defence
+4. Ba4 Nf6 5. 0-0 Be7!? 6. Re1 b5 7. Bb3 d6 8. c3 O-O 1-0
+"#;
+
+    fn sans(game: &Game, line: &[usize]) -> Vec<String> {
+        line.iter().map(|&n| game.nodes[n].san.clone()).collect()
+    }
+
+    fn symbols(text: &str) -> Vec<Token> {
+        tokenize(text).unwrap().into_iter().map(|(_, t)| t).collect()
+    }
+
+    #[test]
+    fn tokens() {
+        let san = |s: &str| Token::San(s.to_string());
+        assert_eq!(symbols("1. e4 1...e5 2.0-0 0-0-0 12 exd8=Q+!? $14 *"),
+            [san("e4"), san("e5"), san("0-0"), san("0-0-0"), san("exd8=Q+"), Token::Nag(5), Token::Nag(14), Token::Result("*".to_string())]);
+        assert_eq!(symbols("{a\n  b} ; rest of line\n( ) 1/2-1/2"),
+            [Token::Comment("a b".to_string()), Token::Comment("rest of line".to_string()), Token::Open, Token::Close, Token::Result("1/2-1/2".to_string())]);
+        assert_eq!(symbols("[White \"A \\\"B\\\"\"]\n%escaped\n0-1"),
+            [Token::Tag("White".to_string(), "A \"B\"".to_string()), Token::Result("0-1".to_string())]);
+
+        assert_eq!(tokenize("\n{open").unwrap_err().line, 2);
+        assert!(tokenize("[White \"A").is_err());
+    }
+
+    #[test]
+    fn parses_moves_variations_and_annotations() {
+        let games = parse_pgn(GAME).unwrap();
+        assert_eq!(games.len(), 1);
+        let game = &games[0];
+
+        assert_eq!(game.result, "1-0");
+        assert_eq!(game.tag("White"), Some("A"));
+        assert_eq!(sans(game, &game.mainline()),
+            ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6", "Ba4", "Nf6", "O-O", "Be7", "Re1", "b5", "Bb3", "d6", "c3", "O-O"]);
+        assert_eq!(game.nodes[0].comments, ["Opening"]);
+
+        // 2. Nf3 carries a NAG and the King's gambit as a variation
+        let e5 = game.mainline()[1];
+        let (nf3, f4) = (game.nodes[e5].children[0], game.nodes[e5].children[1]);
+        assert_eq!(game.nodes[nf3].nags, [1]);
+        assert_eq!(game.nodes[f4].nags, [6]);
+
+        let exf4 = game.nodes[f4].children[0];
+        let gambit = game.nodes[exf4].children.clone();
+        assert_eq!(sans(game, &gambit), ["Nf3", "Bc4"]);
+        assert_eq!(game.nodes[gambit[0]].comments, ["King's gambit"]);
+        assert_eq!(sans(game, &game.nodes[gambit[0]].children), ["g5"]);
+
+        let a6 = game.mainline()[5];
+        assert_eq!(game.nodes[a6].comments, ["the Morphy defence"]);
+        assert_eq!(game.nodes[game.mainline()[9]].nags, [5]);
+    }
+
+    #[test]
+    fn games_end_at_results_and_tags() {
+        let games = parse_pgn("1. e4 *\n1. d4 d5 0-1\n[Event \"x\"]\n1. c4").unwrap();
+        let results: Vec<&str> = games.iter().map(|g| g.result.as_str()).collect();
+        assert_eq!(results, ["*", "0-1", "*"]);
+        assert_eq!(sans(&games[2], &games[2].mainline()), ["c4"]);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| parse_pgn(text).err().map(|e| e.to_string());
+        assert_eq!(error("1. e4 e5\n2. Ke3 *"), Some("line 2: Illegal move Ke3".to_string()));
+        assert_eq!(error("(1. e4) *"), Some("line 1: variation before any move".to_string()));
+        assert_eq!(error("1. e4 ) *"), Some("line 1: unmatched ')'".to_string()));
+        assert_eq!(error("1. e4 (1. d4 *"), Some("line 1: unterminated variation".to_string()));
+        assert_eq!(error("[Event \"x\"]\n[FEN \"garbage\"]\n1. e4 *"), Some("line 2: invalid FEN: expected 4 to 6 fields".to_string()));
+        assert_eq!(error("[FEN \"8/8/8/8/8/8/8/8 w - - 0 1\"]\n*"), Some("line 1: invalid FEN: each side needs exactly one king".to_string()));
+    }
+
+    #[test]
+    fn export_round_trips() {
+        let game = &parse_pgn(GAME).unwrap()[0];
+        let pgn = game.to_pgn();
+        assert!(pgn.starts_with("[Event \"Test\"]\n[Site \"?\"]\n[Date \"2024.01.01\"]\n"));
+        assert!(pgn.lines().all(|line| line.len() <= LINE_LENGTH));
+
+        // Lines are wrapped, the movetext is compared with single spaces
+        let movetext = pgn.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(movetext.contains("2. Nf3 $1 (2. f4 $6 exf4 3. Nf3 {King's gambit} (3. Bc4) 3... g5) 2... Nc6"));
+        assert!(movetext.ends_with("5. O-O Be7 $5 6. Re1 b5 7. Bb3 d6 8. c3 O-O 1-0"));
+
+        let again = &parse_pgn(&pgn).unwrap()[0];
+        assert_eq!(again.to_pgn(), pgn);
+        assert_eq!(again.nodes.len(), game.nodes.len());
+    }
+
+    #[test]
+    fn keeps_the_fullmove_number() {
+        let fen = "8/8/4k3/8/8/4K3/4P3/8 b - - 0 30";
+        let game = &parse_pgn(&format!("[FEN \"{}\"]\n30... Kd6 31. Kd4 *", fen)).unwrap()[0];
+        assert_eq!(game.nodes[0].position.to_fen(), fen);
+
+        let pgn = game.to_pgn();
+        assert!(pgn.contains(&format!("[FEN \"{}\"]", fen)));
+        assert!(pgn.contains("30... Kd6 31. Kd4 *"));
+
+        let last = *game.mainline().last().unwrap();
+        assert_eq!(game.nodes[last].m.map(move_to_string).as_deref(), Some("e3d4"));
+        assert_eq!(game.nodes[last].position.to_fen(), "8/8/3k4/8/3K4/8/4P3/8 b - - 0 31");
+    }
+}
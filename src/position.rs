@@ -3,9 +3,9 @@ use piston::{index};
 use crate::state::{MAX_MOVE_COUNT, MOVE_TABLE_SIZE};
 use crate::bitboard::{ANTIDIAGS, BITS, DIAGONALS, FILES, LUT_BISHOP, LUT_KING, LUT_KNIGHT, LUT_PAWN_CAPTURES, LUT_ROOK, RANKS, RAYS};
 use crate::eval::PIECE_VALUES;
-use crate::hash::{HASH_BLACK_LONG_CASTLE, HASH_BLACK_SHORT_CASTLE, HASH_ENPASSANT, HASH_PIECES, HASH_TURN, HASH_WHITE_LONG_CASTLE, HASH_WHITE_SHORT_CASTLE, zobrist_key};
+use crate::hash::{HASH_BLACK_LONG_CASTLE, HASH_BLACK_SHORT_CASTLE, HASH_ENPASSANT, HASH_PIECES, HASH_TURN, HASH_WHITE_LONG_CASTLE, HASH_WHITE_SHORT_CASTLE, hashes_en_passant, zobrist_key};
 use crate::movegen::*;
-use crate::output::{string_to_index, Display};
+use crate::output::{index_to_string, string_to_index, Display};
 
 pub const STARTPOS_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 ";
 
@@ -24,35 +24,34 @@ pub const BLACK_LONG_CASTLE_BITS: u64 = 0x88;
 
 pub const FILE_CHARS: [char; 8] = ['a', 'b', 'c', 'd', 'e', 'f', 'g', 'h'];
 
-#[derive(Default, Copy, Clone, PartialEq)]
-pub struct Move {
-    pub origin: u64,
-    pub target: u64,
-    pub tier: u8,
-    pub code: u8,
+// Mailbox entry of an empty square, occupied squares hold the piece tier
+pub const EMPTY: u8 = 6;
+
+// The rank, file or diagonal through the index that contains the square, empty if none does
+pub fn line_through(index: usize, square: u64) -> u64 {
+    let lines = [FILES[index & 7], RANKS[index >> 3], DIAGONALS[index], ANTIDIAGS[index]];
+    lines.into_iter().find(|&line| line & square != 0).unwrap_or(0)
 }
 
+// Origin index, target index and move code packed as 6 + 6 + 4 bits. The moving piece is
+// not stored, it is looked up on the board with piece_on.
+#[derive(Default, Copy, Clone, PartialEq, Eq)]
+pub struct Move(pub u16);
+
 impl Move {
-    pub fn to_u32(&self) -> u32 {
-        let mut int = 0;
-        int |= index!(self.origin) as u32;
-        int |= (index!(self.target) as u32) << 6;
-        int |= (self.tier as u32) << 12;
-        int |= (self.code as u32) << 16;
-        int
+    pub fn new(origin: u64, target: u64, code: u8) -> Move {
+        Move(index!(origin) as u16 | (index!(target) as u16) << 6 | (code as u16) << 12)
     }
 
-    pub fn from_u32(m: u32) -> Move {
-        let origin = BITS[(m & 0x3F) as usize];
-        let target = BITS[((m >> 6) & 0x3F) as usize];
-        let tier = ((m >> 12) & 0x7) as u8;
-        let code = ((m >> 16) & 0xF) as u8;
-        Move { origin, target, tier, code }
-    }
+    pub fn origin_index(&self) -> usize { (self.0 & 0x3F) as usize }
 
-    pub fn tier(&self) -> usize {
-        self.tier as usize
-    }
+    pub fn target_index(&self) -> usize { ((self.0 >> 6) & 0x3F) as usize }
+
+    pub fn origin(&self) -> u64 { BITS[self.origin_index()] }
+
+    pub fn target(&self) -> u64 { BITS[self.target_index()] }
+
+    pub fn code(&self) -> u8 { (self.0 >> 12) as u8 }
 }
 
 #[derive(Default, Copy, Clone)]
@@ -62,7 +61,7 @@ pub struct PositionState {
     pub castle_flags: u8,
     pub move_ptr: usize,
     pub move_cnt: usize,
-    pub half_move: u8,
+    pub half_move: u16,
     pub turn: bool,
     pub evasion_mask: u64,
     pub pinned_mask: u64,
@@ -108,7 +107,7 @@ impl EvaluationMetrics {
     }
 }
 
-#[derive(Default, Copy, Clone)]
+#[derive(Copy, Clone)]
 pub struct Position {
     pub pawns: u64,
     pub knights: u64,
@@ -119,10 +118,30 @@ pub struct Position {
     pub all: u64,
     pub player: u64,
     pub enemy: u64,
+    pub board: [u8; 64],
     pub state: PositionState,
     pub metrics: EvaluationMetrics,
 }
 
+impl Default for Position {
+    fn default() -> Position {
+        Position {
+            pawns: 0,
+            knights: 0,
+            bishops: 0,
+            rooks: 0,
+            queens: 0,
+            kings: 0,
+            all: 0,
+            player: 0,
+            enemy: 0,
+            board: [EMPTY; 64],
+            state: PositionState::default(),
+            metrics: EvaluationMetrics::default(),
+        }
+    }
+}
+
 impl Position {
     pub fn next(&self, cleared_bits: u64, m: Move) -> Position {
         Position {
@@ -135,6 +154,7 @@ impl Position {
             all: self.all,
             player: self.player,
             enemy: self.enemy,
+            board: self.board,
             state: self.state.next(m),
             metrics: self.metrics.next(),
         }
@@ -168,13 +188,14 @@ impl Position {
                 }
 
                 pos.all |= bit;
+                pos.board[index!(bit)] = tier as u8;
 
                 if c.is_uppercase() {
                     pos.metrics.advancement += (index!(bit) >> 3) as i16;
                     pos.metrics.material_balance += PIECE_VALUES[tier];
                     pos.player |= bit;
                 } else {
-                    pos.metrics.advancement += ((63-index!(bit)) >> 3) as i16;
+                    pos.metrics.advancement -= ((63-index!(bit)) >> 3) as i16;
                     pos.metrics.material_balance -= PIECE_VALUES[tier];
                 }
 
@@ -208,40 +229,464 @@ impl Position {
         }
 
         if fen_split[3] != "-" {
-            let offset = if pos.state.turn { -8 } else { 8 };
+            let offset = if pos.state.turn { 8 } else { -8 };
             pos.state.en_passant = BITS[(string_to_index(fen_split[3]) as i8 - offset) as usize];
         }
 
+        // Plies are counted from the start of the game, so the fullmove number survives to_fen
+        let fullmove = fen_split.get(5).and_then(|n| n.parse::<u16>().ok()).unwrap_or(1).clamp(1, 10000);
+        pos.state.half_move = 2 * (fullmove - 1) + !pos.state.turn as u16;
+
         pos.state.key = zobrist_key(&pos);
         pos.set_masks();
         pos
     }
 
-    pub fn push_move_with_code(&mut self, move_slice: &mut [Move], origin: u64, target: u64, tier: u8, code: u8) {
-        move_slice[self.state.move_cnt] = Move { origin, target, tier, code };
+    // Checks a FEN from outside the engine before building it, build_from_fen expects a well
+    // formed FEN of a legal position and may panic on anything else
+    pub fn parse_fen(fen: &str) -> Result<Position, String> {
+        let fields: Vec<&str> = fen.split_whitespace().collect();
+        if fields.len() < 4 || fields.len() > 6 {
+            return Err("expected 4 to 6 fields".to_string());
+        }
+
+        let ranks: Vec<&str> = fields[0].split('/').collect();
+        if ranks.len() != 8 {
+            return Err("the board needs 8 ranks".to_string());
+        }
+
+        let mut kings = Vec::new();
+        for (i, rank) in ranks.iter().enumerate() {
+            let mut file = 0;
+            for c in rank.chars() {
+                match c {
+                    '1'..='8' => file += c.to_digit(10).unwrap() as usize,
+                    'P' | 'N' | 'B' | 'R' | 'Q' | 'K' | 'p' | 'n' | 'b' | 'r' | 'q' | 'k' => {
+                        if c.eq_ignore_ascii_case(&'k') {
+                            kings.push((c, 8 * (7 - i) + file));
+                        }
+                        file += 1;
+                    }
+                    _ => return Err(format!("unexpected '{}' on the board", c)),
+                }
+            }
+            if file != 8 {
+                return Err(format!("rank {} does not have 8 squares", 8 - i));
+            }
+        }
+
+        let white_kings = kings.iter().filter(|(c, _)| *c == 'K').count();
+        if white_kings != 1 || kings.len() != 2 {
+            return Err("each side needs exactly one king".to_string());
+        }
+        if LUT_KING[kings[0].1] & BITS[kings[1].1] != 0 {
+            return Err("the kings are next to each other".to_string());
+        }
+
+        if fields[1] != "w" && fields[1] != "b" {
+            return Err(format!("side to move is {}, expected w or b", fields[1]));
+        }
+        if fields[2] != "-" && !fields[2].chars().all(|c| "KQkq".contains(c)) {
+            return Err(format!("unexpected castling rights {}", fields[2]));
+        }
+
+        let en_passant_rank = if fields[1] == "w" { '6' } else { '3' };
+        let mut en_passant = fields[3].chars();
+        if fields[3] != "-" && !(fields[3].len() == 2 && en_passant.next().is_some_and(|f| ('a'..='h').contains(&f)) && en_passant.next() == Some(en_passant_rank)) {
+            return Err(format!("unexpected en-passant square {}", fields[3]));
+        }
+
+        if let Some(n) = fields[4..].iter().find(|n| n.parse::<u16>().is_err()) {
+            return Err(format!("move counter {} is not a number", n));
+        }
+
+        let pos = Position::build_from_fen(&fields.join(" "));
+        pos.validate().map_err(|errors| errors.join(", "))?;
+        Ok(pos)
+    }
+
+    // The halfmove clock is not tracked, so it is always written as 0
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_fen(&self) -> String {
+        let white = if self.state.turn { self.player } else { self.enemy };
+        let pieces = ['P', 'N', 'B', 'R', 'Q', 'K', 'p', 'n', 'b', 'r', 'q', 'k'];
+        let mut fen = String::new();
+
+        for rank in (0..8).rev() {
+            let mut empty = 0;
+            for file in 0..8 {
+                let bit = BITS[file + 8 * rank];
+                if bit & self.all == 0 {
+                    empty += 1;
+                    continue;
+                }
+                if empty > 0 {
+                    fen.push_str(&empty.to_string());
+                    empty = 0;
+                }
+                let color_offset = if bit & white != 0 { 0 } else { 6 };
+                fen.push(pieces[self.square_tier(bit) + color_offset]);
+            }
+            if empty > 0 {
+                fen.push_str(&empty.to_string());
+            }
+            if rank > 0 {
+                fen.push('/');
+            }
+        }
+
+        fen.push_str(if self.state.turn { " w " } else { " b " });
+
+        let castles = [(WHITE_SHORT_CASTLE, 'K'), (WHITE_LONG_CASTLE, 'Q'), (BLACK_SHORT_CASTLE, 'k'), (BLACK_LONG_CASTLE, 'q')];
+        for (flag, c) in castles {
+            if self.state.castle_flags & flag != 0 {
+                fen.push(c);
+            }
+        }
+        if self.state.castle_flags == 0 {
+            fen.push('-');
+        }
+
+        // en_passant holds the pawn that double pushed, FEN wants the square behind it
+        if self.state.en_passant != 0 {
+            let index = index!(self.state.en_passant) as i8 + if self.state.turn { 8 } else { -8 };
+            fen.push(' ');
+            fen.push_str(&index_to_string(index as usize));
+        } else {
+            fen.push_str(" -");
+        }
+
+        fen.push_str(&format!(" 0 {}", self.state.half_move / 2 + 1));
+        fen
+    }
+
+    // Recomputes everything make_move updates incrementally and lists every mismatch
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        let pieces = [self.pawns, self.knights, self.bishops, self.rooks, self.queens, self.kings];
+        let names = ["pawns", "knights", "bishops", "rooks", "queens", "kings"];
+
+        for i in 0..6 {
+            for j in i + 1..6 {
+                if pieces[i] & pieces[j] != 0 {
+                    errors.push(format!("{} and {} overlap: {:016x}", names[i], names[j], pieces[i] & pieces[j]));
+                }
+            }
+        }
+
+        let union = pieces.iter().fold(0, |acc, p| acc | p);
+        if union != self.all {
+            errors.push(format!("all is {:016x}, the pieces make {:016x}", self.all, union));
+        }
+        if self.player & self.enemy != 0 {
+            errors.push(format!("player and enemy overlap: {:016x}", self.player & self.enemy));
+        }
+        if self.player | self.enemy != self.all {
+            errors.push(format!("player | enemy is {:016x}, all is {:016x}", self.player | self.enemy, self.all));
+        }
+        if (self.kings & self.player).count_ones() != 1 || (self.kings & self.enemy).count_ones() != 1 {
+            errors.push("each side needs exactly one king".to_string());
+            return Err(errors);
+        }
+        if self.pawns & (RANKS[0] | RANKS[7]) != 0 {
+            errors.push("pawn on the first or last rank".to_string());
+        }
+
+        let enemy_king = self.kings & self.enemy;
+        if self.get_attack_bitboard(self.player, self.player_shift_offset()) & enemy_king != 0 {
+            errors.push("side not to move is in check".to_string());
+        }
+
+        let white = if self.state.turn { self.player } else { self.enemy };
+        let black = self.all ^ white;
+        let castles = [
+            (WHITE_SHORT_CASTLE, WHITE_SHORT_CASTLE_BITS, white, "K"),
+            (WHITE_LONG_CASTLE, WHITE_LONG_CASTLE_BITS, white, "Q"),
+            (BLACK_SHORT_CASTLE, BLACK_SHORT_CASTLE_BITS, black, "k"),
+            (BLACK_LONG_CASTLE, BLACK_LONG_CASTLE_BITS, black, "q"),
+        ];
+        for (flag, bits, side, name) in castles {
+            if self.state.castle_flags & flag != 0 && bits & side & (self.kings | self.rooks) != bits {
+                errors.push(format!("castling right {} without king and rook in place", name));
+            }
+        }
+
+        let en_passant = self.state.en_passant;
+        if en_passant != 0 {
+            let rank = if self.state.turn { RANKS[4] } else { RANKS[3] };
+            if en_passant.count_ones() != 1 || en_passant & self.pawns & self.enemy & rank == 0 {
+                errors.push(format!("en-passant pawn {:016x} is not a double pushed enemy pawn", en_passant));
+            }
+        }
+
+        for (index, &bit) in BITS.iter().enumerate() {
+            if self.board[index] as usize != self.square_tier(bit) {
+                errors.push(format!("mailbox has {} on {}, bitboards have {}", self.board[index], index_to_string(index), self.square_tier(bit)));
+            }
+        }
+
+        let key = zobrist_key(self);
+        if key != self.state.key {
+            errors.push(format!("key is {:016x}, expected {:016x}", self.state.key, key));
+        }
+
+        // Metrics are relative to the side to move, like in build_from_fen
+        let mut material_balance = 0;
+        let mut advancement = 0;
+        let mut occupied = self.all;
+        while occupied != 0 {
+            let bit = occupied & (!occupied + 1);
+            let index = index!(NonZeroU64::new(bit).unwrap()) as i16;
+            let tier = self.square_tier(bit);
+            if bit & white != 0 {
+                material_balance += PIECE_VALUES[tier];
+                advancement += index >> 3;
+            } else {
+                material_balance -= PIECE_VALUES[tier];
+                advancement -= (63 - index) >> 3;
+            }
+            occupied &= occupied - 1;
+        }
+        if !self.state.turn {
+            material_balance = -material_balance;
+            advancement = -advancement;
+        }
+        if material_balance != self.metrics.material_balance {
+            errors.push(format!("material balance is {}, expected {}", self.metrics.material_balance, material_balance));
+        }
+        if advancement != self.metrics.advancement {
+            errors.push(format!("advancement is {}, expected {}", self.metrics.advancement, advancement));
+        }
+
+        let mut fresh = *self;
+        fresh.set_masks();
+        let masks = [
+            ("attack mask", self.state.attack_mask, fresh.state.attack_mask),
+            ("pinned mask", self.state.pinned_mask, fresh.state.pinned_mask),
+            ("evasion mask", self.state.evasion_mask, fresh.state.evasion_mask),
+        ];
+        for (name, actual, expected) in masks {
+            if actual != expected {
+                errors.push(format!("{} is {:016x}, expected {:016x}", name, actual, expected));
+            }
+        }
+        if self.state.check != fresh.state.check || self.state.double_check != fresh.state.double_check {
+            errors.push("check flags do not match the masks".to_string());
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    pub fn push_move_with_code(&mut self, move_slice: &mut [Move], origin: u64, target: u64, code: u8) {
+        move_slice[self.state.move_cnt] = Move::new(origin, target, code);
         self.state.move_cnt += 1;
     }
 
-    pub fn push_move(&mut self, move_slice: &mut [Move], origin: u64, target: u64, tier: u8) {
-        move_slice[self.state.move_cnt] = Move { origin, target, tier, code: 0};
+    pub fn push_move(&mut self, move_slice: &mut [Move], origin: u64, target: u64) {
+        move_slice[self.state.move_cnt] = Move::new(origin, target, 0);
         self.state.move_cnt += 1;
     }
 
-    // assumes the move is quiet
-    pub fn killer_is_legal(&self, m: Move) -> bool {
-        let piece_bool = self.player & m.origin != 0;
-        let space_bool = self.all & m.target == 0;
-        let pinned_bool = self.state.pinned_mask & m.origin == 0;
-        let check_bool = !self.state.check || m.target & self.state.evasion_mask != 0;
-        let dcheck_bool = !self.state.double_check || m.tier == 5;
+    // Checks that a move from outside the generator, like a TT move or a killer, could be
+    // generated in this position when king safety is ignored
+    pub fn is_pseudo_legal(&self, m: Move) -> bool {
+        if m.code() > 8 || m.origin() & self.player == 0 || m.target() & self.player != 0 {
+            return false;
+        }
+
+        let origin_index = m.origin_index();
+        let target_index = m.target_index();
+        let capture = m.target() & self.enemy != 0;
+
+        match self.piece_on(origin_index) {
+            0 => {
+                let forward: i32 = if self.state.turn { 8 } else { -8 };
+                let step = target_index as i32 - origin_index as i32;
+                let last_rank = if self.state.turn { RANKS[7] } else { RANKS[0] };
+                let promotion = m.target() & last_rank != 0;
+                let diagonal = (step == forward - 1 || step == forward + 1) && (target_index & 7).abs_diff(origin_index & 7) == 1;
+
+                match m.code() {
+                    0..=4 if promotion != (m.code() == 0) => {
+                        (step == forward && !capture) || (diagonal && capture)
+                    }
+                    5 => {
+                        let start_rank = if self.state.turn { RANKS[1] } else { RANKS[6] };
+                        let between = if self.state.turn { m.origin() >> 8 } else { m.origin() << 8 };
+                        step == 2 * forward && m.origin() & start_rank != 0 && (between | m.target()) & self.all == 0
+                    }
+                    8 => {
+                        let captured = if self.state.turn { m.target() << 8 } else { m.target() >> 8 };
+                        diagonal && !capture && self.state.en_passant == captured && captured & self.pawns & self.enemy != 0
+                    }
+                    _ => false,
+                }
+            }
+            1 => m.code() == 0 && pseudo_knight(origin_index) & m.target() != 0,
+            2 => m.code() == 0 && pseudo_bishop(m.origin(), self.all, origin_index) & m.target() != 0,
+            3 => m.code() == 0 && pseudo_rook(m.origin(), self.all, origin_index) & m.target() != 0,
+            4 => m.code() == 0 && pseudo_queen(m.origin(), self.all, origin_index) & m.target() != 0,
+            5 => {
+                let (short, long, home) = if self.state.turn {
+                    (WHITE_SHORT_CASTLE, WHITE_LONG_CASTLE, BITS[4])
+                } else {
+                    (BLACK_SHORT_CASTLE, BLACK_LONG_CASTLE, BITS[60])
+                };
+                let rooks = self.rooks & self.player;
+
+                match m.code() {
+                    0 => pseudo_king(origin_index) & m.target() != 0,
+                    6 => self.state.castle_flags & short != 0 && m.origin() == home && m.target() == home >> 2
+                        && rooks & (home >> 3) != 0 && ((home >> 1) | (home >> 2)) & self.all == 0,
+                    7 => self.state.castle_flags & long != 0 && m.origin() == home && m.target() == home << 2
+                        && rooks & (home << 4) != 0 && ((home << 1) | (home << 2) | (home << 3)) & self.all == 0,
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
 
-        if piece_bool && space_bool && pinned_bool && check_bool && dcheck_bool && m.code == 0 {
-            let sq_tier = self.square_tier(m.origin);
-            let pseudo_mask = self.origin_pseudo(m);
-            return m.tier() == sq_tier && m.target & pseudo_mask != 0;
+    // Full legality check for moves that do not come from the generator
+    pub fn is_legal(&self, m: Move) -> bool {
+        if !self.is_pseudo_legal(m) {
+            return false;
         }
 
-        return false;
+        if m.origin() & self.kings != 0 {
+            return match m.code() {
+                6 => !self.state.check && ((m.origin() >> 1) | m.target()) & self.state.attack_mask == 0,
+                7 => !self.state.check && ((m.origin() << 1) | m.target()) & self.state.attack_mask == 0,
+                _ => m.target() & self.state.attack_mask == 0,
+            };
+        }
+
+        if self.state.double_check {
+            return false;
+        }
+
+        // En-passant removes two pieces from the rank of the king, so it is simply tried
+        if m.code() == 8 {
+            let node = self.make_move(m);
+            return node.get_attack_bitboard(node.player, node.player_shift_offset()) & node.kings & node.enemy == 0;
+        }
+
+        if m.target() & self.state.evasion_mask == 0 {
+            return false;
+        }
+
+        if m.origin() & self.state.pinned_mask != 0 {
+            let king_index = index!(NonZeroU64::new(self.kings & self.player).unwrap());
+            return line_through(king_index, m.origin()) & m.target() != 0;
+        }
+
+        true
+    }
+
+    // Whether a legal move checks the enemy king, directly or by discovery
+    pub fn gives_check(&self, m: Move) -> bool {
+        // Promotions, castling and en-passant are rare enough to simply be made
+        if m.code() != 0 && m.code() != 5 {
+            return self.make_move(m).state.check;
+        }
+
+        let king = self.kings & self.enemy;
+        let king_index = index!(NonZeroU64::new(king).unwrap());
+        let discoverers = self.get_blockers(king, king_index, self.player, self.player);
+        if m.origin() & discoverers != 0 && line_through(king_index, m.origin()) & m.target() == 0 {
+            return true;
+        }
+
+        let all = (self.all ^ m.origin()) | m.target();
+        match self.piece_on(m.origin_index()) {
+            0 => LUT_PAWN_CAPTURES[self.state.turn as usize][king_index] & m.target() != 0,
+            1 => pseudo_knight(king_index) & m.target() != 0,
+            2 => pseudo_bishop(king, all, king_index) & m.target() != 0,
+            3 => pseudo_rook(king, all, king_index) & m.target() != 0,
+            4 => pseudo_queen(king, all, king_index) & m.target() != 0,
+            _ => false,
+        }
+    }
+
+    // Quiet moves giving direct or discovered check, for quiescence. Captures and promotions
+    // come from generate_tactical. Assumes the side to move is not in check.
+    pub fn generate_checks(&mut self, move_slice: &mut [Move]) {
+        let king_index = index!(NonZeroU64::new(self.kings & self.player).unwrap());
+        let enemy_king = self.kings & self.enemy;
+        let enemy_king_index = index!(NonZeroU64::new(enemy_king).unwrap());
+        let discoverers = self.get_blockers(enemy_king, enemy_king_index, self.player, self.player);
+        let pinned = self.state.pinned_mask;
+        let empty = !self.all;
+
+        // Squares from which each piece type checks the enemy king
+        let bishop_checks = pseudo_bishop(enemy_king, self.all, enemy_king_index);
+        let rook_checks = pseudo_rook(enemy_king, self.all, enemy_king_index);
+        let checks = [
+            LUT_PAWN_CAPTURES[self.state.turn as usize][enemy_king_index],
+            pseudo_knight(enemy_king_index),
+            bishop_checks,
+            rook_checks,
+            bishop_checks | rook_checks,
+        ];
+
+        let last_rank = if self.state.turn { RANKS[7] } else { RANKS[0] };
+        let double_rank = if self.state.turn { RANKS[3] } else { RANKS[4] };
+        let mut pieces = self.player & !self.kings;
+        while pieces != 0 {
+            let origin = pieces & (!pieces + 1);
+            let origin_index = index!(NonZeroU64::new(origin).unwrap());
+            let tier = self.piece_on(origin_index);
+
+            let mut moves = match tier {
+                0 => {
+                    let single = pseudo_push(origin, self.all, self.player_shift_offset()) & !last_rank;
+                    single | (pseudo_push(single, self.all, self.player_shift_offset()) & double_rank)
+                }
+                1 => pseudo_knight(origin_index) & empty,
+                2 => pseudo_bishop(origin, self.all, origin_index) & empty,
+                3 => pseudo_rook(origin, self.all, origin_index) & empty,
+                _ => pseudo_queen(origin, self.all, origin_index) & empty,
+            };
+
+            if origin & pinned != 0 {
+                moves &= line_through(king_index, origin);
+            }
+            if origin & discoverers != 0 {
+                moves &= checks[tier] | !line_through(enemy_king_index, origin);
+            } else {
+                moves &= checks[tier];
+            }
+
+            while moves != 0 {
+                let target = moves & (!moves + 1);
+                let code = if tier == 0 && (target << 16 == origin || target >> 16 == origin) { 5 } else { 0 };
+                self.push_move_with_code(move_slice, origin, target, code);
+                moves &= moves - 1;
+            }
+            pieces &= pieces - 1;
+        }
+
+        let king = self.kings & self.player;
+        if king & discoverers != 0 {
+            let mut moves = pseudo_king(king_index) & empty & !self.state.attack_mask & !line_through(enemy_king_index, king);
+            while moves != 0 {
+                self.push_move(move_slice, king, moves & (!moves + 1));
+                moves &= moves - 1;
+            }
+        }
+
+        let home = if self.state.turn { BITS[4] } else { BITS[60] };
+        for (target, code) in [(home >> 2, 6), (home << 2, 7)] {
+            let m = Move::new(king, target, code);
+            if self.is_legal(m) && self.gives_check(m) {
+                self.push_move_with_code(move_slice, king, target, code);
+            }
+        }
+    }
+
+    // Tier of the piece on the square index, EMPTY if there is none
+    pub fn piece_on(&self, index: usize) -> usize {
+        self.board[index] as usize
     }
 
     pub fn square_tier(&self, square: u64) -> usize {
@@ -254,25 +699,12 @@ impl Position {
         6
     }
 
-    // used for killer moves, not completely correct
-    pub fn origin_pseudo(&self, m: Move) -> u64 {
-        match m.tier {
-            0 => pseudo_push(m.origin, self.all, self.player_shift_offset()),
-            1 => pseudo_knight(index!(NonZeroU64::new(m.origin).unwrap())),
-            2 => pseudo_bishop(m.origin, self.all, index!(NonZeroU64::new(m.origin).unwrap())),
-            3 => pseudo_rook(m.origin, self.all, index!(NonZeroU64::new(m.origin).unwrap())),
-            4 => pseudo_queen(m.origin, self.all, index!(NonZeroU64::new(m.origin).unwrap())),
-            5 => pseudo_king(index!(NonZeroU64::new(m.origin).unwrap())) & !self.state.attack_mask,
-            _ => 0,
-        }
-    }
-
     pub fn player_shift_offset(&self) -> u8 {
-        return (self.state.turn as u8) << 4;
+        (self.state.turn as u8) << 4
     }
 
     pub fn enemy_shift_offset(&self) -> u8 {
-        return (!self.state.turn as u8) << 4;
+        (!self.state.turn as u8) << 4
     }
 
     pub fn is_attacked(&self, square: u64) -> bool {
@@ -339,20 +771,27 @@ impl Position {
     }
 
     pub fn get_pinned_bitboard(&self, king: u64, king_index: usize) -> u64 {
+        self.get_blockers(king, king_index, self.enemy, self.player)
+    }
+
+    // Pieces of the blocking side that are the only piece between the king and a slider of the
+    // sniping side. With the enemy sniping these are pins, with the player sniping at the enemy
+    // king they are the pieces that can give discovered check.
+    pub fn get_blockers(&self, king: u64, king_index: usize, sniping: u64, blocking: u64) -> u64 {
         let mut pinned = 0;
 
-        let diag_snipers = self.enemy & (self.bishops | self.queens);
-        let line_snipers = self.enemy & (self.rooks | self.queens);
+        let diag_snipers = sniping & (self.bishops | self.queens);
+        let line_snipers = sniping & (self.rooks | self.queens);
 
         let blockers = pseudo_queen(king, self.all, king_index) & self.all;
-        let cleared = self.all ^ (blockers & self.player);
+        let cleared = self.all ^ (blockers & blocking);
 
         let mut snipers = ((pseudo_rook(king, cleared, king_index) & line_snipers)
             | (pseudo_bishop(king, cleared, king_index) & diag_snipers)) & !blockers;
 
         while snipers != 0 {
             let piece = NonZeroU64::new(snipers & (!snipers + 1)).unwrap();
-            pinned |= blockers & RAYS[king_index][index!(piece)];
+            pinned |= blockers & RAYS[king_index][index!(piece)] & blocking;
             snipers &= snipers - 1;
         }
 
@@ -376,7 +815,7 @@ impl Position {
 
         let attackers_mask = attacking_pawns | attacking_knights | attacking_bishops | attacking_rooks;
 
-        return if attackers_mask & (attackers_mask - 1) == 0 {
+        if attackers_mask & (attackers_mask - 1) == 0 {
             if attacking_bishops != 0 {
                 let attacker_index = index!(NonZeroU64::new(attacking_bishops).unwrap());
                 pseudo_bishop(king, self.all, king_index)
@@ -415,7 +854,7 @@ impl Position {
 
         let mut king_moves = pseudo_king(king_index) & space & !self.state.attack_mask & self.enemy;
         while king_moves != 0 {
-            self.push_move(move_slice, king, king_moves & (!king_moves + 1), 5);
+            self.push_move(move_slice, king, king_moves & (!king_moves + 1));
             king_moves &= king_moves - 1;
         }
 
@@ -437,7 +876,7 @@ impl Position {
                 let mut right_captures = ((pawns | (self.pawns & pinned & DIAGONALS[king_index])) >> 9) & !FILES[0] & evasion_mask & self.enemy;
                 single_push &= evasion_mask;
 
-                if pawns & RANKS[6] != 0 {
+                if self.pawns & self.player & RANKS[6] != 0 {
                     let rank8 = RANKS[7];
                     let mut single_promo = single_push & rank8;
                     let mut left_capture_promo = left_captures & rank8;
@@ -449,30 +888,30 @@ impl Position {
                     while single_promo != 0 {
                         let target = single_promo & (!single_promo + 1);
                         let origin = target << 8;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         single_promo &= single_promo - 1;
                     }
 
                     while left_capture_promo != 0 {
                         let target = left_capture_promo & (!left_capture_promo + 1);
                         let origin = target << 7;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         left_capture_promo &= left_capture_promo - 1;
                     }
 
                     while right_capture_promo != 0 {
                         let target = right_capture_promo & (!right_capture_promo + 1);
                         let origin = target << 9;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         right_capture_promo &= right_capture_promo - 1;
                     }
                 }
@@ -487,7 +926,7 @@ impl Position {
                         let en_passant_mask = left_capturer | ps | target;
                         let line_sliders = self.enemy & (self.rooks | self.queens);
                         if pseudo_rook(king, self.all ^ en_passant_mask, king_index) & line_sliders == 0 {
-                            self.push_move_with_code(move_slice, left_capturer, target, 0, 8);
+                            self.push_move_with_code(move_slice, left_capturer, target, 8);
                         }
                     }
 
@@ -497,7 +936,7 @@ impl Position {
 
                         let line_sliders = self.enemy & (self.rooks | self.queens);
                         if pseudo_rook(king, self.all ^ en_passant_mask, king_index) & line_sliders == 0 {
-                            self.push_move_with_code(move_slice, right_capturer, target, 0, 8);
+                            self.push_move_with_code(move_slice, right_capturer, target, 8);
                         }
                     }
                 }
@@ -505,14 +944,14 @@ impl Position {
                 while left_captures != 0 {
                     let target = left_captures & (!left_captures + 1);
                     let origin = target << 7;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     left_captures &= left_captures - 1;
                 }
 
                 while right_captures != 0 {
                     let target = right_captures & (!right_captures + 1);
                     let origin = target << 9;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     right_captures &= right_captures - 1;
                 }
             }
@@ -523,7 +962,7 @@ impl Position {
                 let mut right_captures = ((pawns | (self.pawns & pinned & ANTIDIAGS[king_index])) << 7) & !FILES[0] & evasion_mask & self.enemy;
                 single_push &= evasion_mask;
 
-                if pawns & RANKS[1] != 0 {
+                if self.pawns & self.player & RANKS[1] != 0 {
                     let rank8 = RANKS[0];
                     let mut single_promo = single_push & rank8;
                     let mut left_capture_promo = left_captures & rank8;
@@ -535,30 +974,30 @@ impl Position {
                     while single_promo != 0 {
                         let target = single_promo & (!single_promo + 1);
                         let origin = target >> 8;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         single_promo &= single_promo - 1;
                     }
 
                     while left_capture_promo != 0 {
                         let target = left_capture_promo & (!left_capture_promo + 1);
                         let origin = target >> 9;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         left_capture_promo &= left_capture_promo - 1;
                     }
 
                     while right_capture_promo != 0 {
                         let target = right_capture_promo & (!right_capture_promo + 1);
                         let origin = target >> 7;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         right_capture_promo &= right_capture_promo - 1;
                     }
                 }
@@ -573,7 +1012,7 @@ impl Position {
                         let en_passant_mask = left_capturer | ps | target;
                         let line_sliders = self.enemy & (self.rooks | self.queens);
                         if pseudo_rook(king, self.all ^ en_passant_mask, king_index) & line_sliders == 0 {
-                            self.push_move_with_code(move_slice, left_capturer, target, 0, 8);
+                            self.push_move_with_code(move_slice, left_capturer, target, 8);
                         }
                     }
 
@@ -583,7 +1022,7 @@ impl Position {
 
                         let line_sliders = self.enemy & (self.rooks | self.queens);
                         if pseudo_rook(king, self.all ^ en_passant_mask, king_index) & line_sliders == 0 {
-                            self.push_move_with_code(move_slice, right_capturer, target, 0, 8);
+                            self.push_move_with_code(move_slice, right_capturer, target, 8);
                         }
                     }
                 }
@@ -591,14 +1030,14 @@ impl Position {
                 while left_captures != 0 {
                     let target = left_captures & (!left_captures + 1);
                     let origin = target >> 9;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     left_captures &= left_captures - 1;
                 }
 
                 while right_captures != 0 {
                     let target = right_captures & (!right_captures + 1);
                     let origin = target >> 7;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     right_captures &= right_captures - 1;
                 }
             }
@@ -608,7 +1047,7 @@ impl Position {
                 let index = NonZeroU64::new(origin).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_knight(index) & move_mask & self.enemy;
                 while moves != 0 {
-                    self.push_move(move_slice, origin, moves & (!moves + 1), 1);
+                    self.push_move(move_slice, origin, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 knights &= knights - 1;
@@ -619,7 +1058,7 @@ impl Position {
                 let origin_index = NonZeroU64::new(piece).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_bishop(piece, self.all, origin_index) & move_mask & self.enemy;
                 while moves != 0 {
-                    self.push_move(move_slice, piece, moves & (!moves + 1), 2);
+                    self.push_move(move_slice, piece, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 bishops &= bishops - 1;
@@ -630,7 +1069,7 @@ impl Position {
                 let origin_index = NonZeroU64::new(piece).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_rook(piece, self.all, origin_index) & move_mask & self.enemy;
                 while moves != 0 {
-                    self.push_move(move_slice, piece, moves & (!moves + 1), 3);
+                    self.push_move(move_slice, piece, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 rooks &= rooks - 1;
@@ -642,7 +1081,7 @@ impl Position {
                 let origin_index = NonZeroU64::new(piece).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_queen(piece, self.all, origin_index) & move_mask & self.enemy;
                 while moves != 0 {
-                    self.push_move(move_slice, piece, moves & (!moves + 1), 4);
+                    self.push_move(move_slice, piece, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 queens &= queens - 1;
@@ -657,7 +1096,7 @@ impl Position {
                     let mut moves = pseudo_rook(piece, self.all, origin_index) & space & LUT_ROOK[king_index] & self.enemy;
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 3);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     line_sliders &= line_sliders - 1;
@@ -670,7 +1109,7 @@ impl Position {
                     let mut moves = pseudo_rook(piece, self.all, origin_index) & space & LUT_ROOK[king_index] & self.enemy;
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 4);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     line_sliders &= line_sliders - 1;
@@ -685,7 +1124,7 @@ impl Position {
                     let mut moves = pseudo_bishop(piece, self.all, origin_index) & space & LUT_BISHOP[king_index] & self.enemy;
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 2);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     diag_sliders &= diag_sliders - 1;
@@ -698,7 +1137,7 @@ impl Position {
                     let mut moves = pseudo_bishop(piece, self.all, origin_index) & space & LUT_BISHOP[king_index] & self.enemy;
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 4);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     diag_sliders &= diag_sliders - 1;
@@ -718,14 +1157,14 @@ impl Position {
             if self.state.castle_flags & castle_bit != 0 {
                 let king_slide = (king >> 1) | (king >> 2);
                 if king_slide & (self.state.attack_mask | self.all) == 0 {
-                    self.push_move_with_code(move_slice, king, king >> 2, 5, 6);
+                    self.push_move_with_code(move_slice, king, king >> 2, 6);
                 }
             }
             if self.state.castle_flags & (castle_bit << 1) != 0 {
                 let king_slide = (king << 1) | (king << 2);
                 let rook_slide = king_slide | (king << 3);
                 if king_slide & self.state.attack_mask == 0 && rook_slide & self.all == 0 {
-                    self.push_move_with_code(move_slice, king, king << 2, 5, 7);
+                    self.push_move_with_code(move_slice, king, king << 2, 7);
                 }
             }
         }
@@ -746,19 +1185,19 @@ impl Position {
             if self.state.turn {
                 let mut single_push = ((pawns | (self.pawns & pinned & FILES[king_index & 7])) >> 8) & !self.all;
                 let mut double_push = ((single_push) >> 8) & RANKS[3] & evasion_mask & !self.all;
-                single_push &= evasion_mask;
+                single_push &= evasion_mask & !RANKS[7];
 
                 while single_push != 0 {
                     let target = single_push & (!single_push + 1);
                     let origin = target << 8;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     single_push &= single_push - 1;
                 }
 
                 while double_push != 0 {
                     let target = double_push & (!double_push + 1);
                     let origin = target << 16;
-                    self.push_move_with_code(move_slice, origin, target, 0, 5);
+                    self.push_move_with_code(move_slice, origin, target, 5);
                     double_push &= double_push - 1;
                 }
             }
@@ -766,19 +1205,19 @@ impl Position {
             else {
                 let mut single_push = ((pawns | (self.pawns & pinned & FILES[king_index & 7])) << 8) & !self.all;
                 let mut double_push = ((single_push) << 8) & RANKS[4] & evasion_mask & !self.all;
-                single_push &= evasion_mask;
+                single_push &= evasion_mask & !RANKS[0];
 
                 while single_push != 0 {
                     let target = single_push & (!single_push + 1);
                     let origin = target >> 8;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     single_push &= single_push - 1;
                 }
 
                 while double_push != 0 {
                     let target = double_push & (!double_push + 1);
                     let origin = target >> 16;
-                    self.push_move_with_code(move_slice, origin, target, 0, 5);
+                    self.push_move_with_code(move_slice, origin, target, 5);
                     double_push &= double_push - 1;
                 }
             }
@@ -788,7 +1227,7 @@ impl Position {
                 let index = NonZeroU64::new(origin).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_knight(index) & move_mask & !self.enemy;
                 while moves != 0 {
-                    self.push_move(move_slice, origin, moves & (!moves + 1), 1);
+                    self.push_move(move_slice, origin, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 knights &= knights - 1;
@@ -799,7 +1238,7 @@ impl Position {
                 let origin_index = NonZeroU64::new(piece).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_bishop(piece, self.all, origin_index) & move_mask & !self.enemy;
                 while moves != 0 {
-                    self.push_move(move_slice, piece, moves & (!moves + 1), 2);
+                    self.push_move(move_slice, piece, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 bishops &= bishops - 1;
@@ -810,7 +1249,7 @@ impl Position {
                 let origin_index = NonZeroU64::new(piece).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_rook(piece, self.all, origin_index) & move_mask & !self.enemy;
                 while moves != 0 {
-                    self.push_move(move_slice, piece, moves & (!moves + 1), 3);
+                    self.push_move(move_slice, piece, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 rooks &= rooks - 1;
@@ -822,7 +1261,7 @@ impl Position {
                 let origin_index = NonZeroU64::new(piece).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_queen(piece, self.all, origin_index) & move_mask & !self.enemy;
                 while moves != 0 {
-                    self.push_move(move_slice, piece, moves & (!moves + 1), 4);
+                    self.push_move(move_slice, piece, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 queens &= queens - 1;
@@ -837,7 +1276,7 @@ impl Position {
                     let mut moves = pseudo_rook(piece, self.all, origin_index) & space & LUT_ROOK[king_index] & !self.enemy;
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 3);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     line_sliders &= line_sliders - 1;
@@ -850,7 +1289,7 @@ impl Position {
                     let mut moves = pseudo_rook(piece, self.all, origin_index) & space & LUT_ROOK[king_index] & !self.enemy;
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 4);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     line_sliders &= line_sliders - 1;
@@ -865,7 +1304,7 @@ impl Position {
                     let mut moves = pseudo_bishop(piece, self.all, origin_index) & space & LUT_BISHOP[king_index] & !self.enemy;
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 2);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     diag_sliders &= diag_sliders - 1;
@@ -878,7 +1317,7 @@ impl Position {
                     let mut moves = pseudo_bishop(piece, self.all, origin_index) & space & LUT_BISHOP[king_index] & !self.enemy;
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 4);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     diag_sliders &= diag_sliders - 1;
@@ -888,7 +1327,7 @@ impl Position {
 
         let mut king_moves = pseudo_king(king_index) & space & !self.state.attack_mask & !self.enemy;
         while king_moves != 0 {
-            self.push_move(move_slice, king, king_moves & (!king_moves + 1), 5);
+            self.push_move(move_slice, king, king_moves & (!king_moves + 1));
             king_moves &= king_moves - 1;
         }
     }
@@ -904,14 +1343,14 @@ impl Position {
             if self.state.castle_flags & castle_bit != 0 {
                 let king_slide = (king >> 1) | (king >> 2);
                 if king_slide & (self.state.attack_mask | self.all) == 0 {
-                    self.push_move_with_code(move_slice, king, king >> 2, 5, 6);
+                    self.push_move_with_code(move_slice, king, king >> 2, 6);
                 }
             }
             if self.state.castle_flags & (castle_bit << 1) != 0 {
                 let king_slide = (king << 1) | (king << 2);
                 let rook_slide = king_slide | (king << 3);
                 if king_slide & self.state.attack_mask == 0 && rook_slide & self.all == 0 {
-                    self.push_move_with_code(move_slice, king, king << 2, 5, 7);
+                    self.push_move_with_code(move_slice, king, king << 2, 7);
                 }
             }
         }
@@ -936,7 +1375,7 @@ impl Position {
                 single_push &= evasion_mask;
 
 
-                if pawns & RANKS[6] != 0 {
+                if self.pawns & self.player & RANKS[6] != 0 {
                     let rank8 = RANKS[7];
                     let mut single_promo = single_push & rank8;
                     let mut left_capture_promo = left_captures & rank8;
@@ -949,30 +1388,30 @@ impl Position {
                     while single_promo != 0 {
                         let target = single_promo & (!single_promo + 1);
                         let origin = target << 8;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         single_promo &= single_promo - 1;
                     }
 
                     while left_capture_promo != 0 {
                         let target = left_capture_promo & (!left_capture_promo + 1);
                         let origin = target << 7;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         left_capture_promo &= left_capture_promo - 1;
                     }
 
                     while right_capture_promo != 0 {
                         let target = right_capture_promo & (!right_capture_promo + 1);
                         let origin = target << 9;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         right_capture_promo &= right_capture_promo - 1;
                     }
                 }
@@ -988,7 +1427,7 @@ impl Position {
                         let en_passant_mask = left_capturer | ps | target;
                         let line_sliders = self.enemy & (self.rooks | self.queens);
                         if pseudo_rook(king, self.all ^ en_passant_mask, king_index) & line_sliders == 0 {
-                            self.push_move_with_code(move_slice, left_capturer, target, 0, 8);
+                            self.push_move_with_code(move_slice, left_capturer, target, 8);
                         }
                     }
 
@@ -998,7 +1437,7 @@ impl Position {
 
                         let line_sliders = self.enemy & (self.rooks | self.queens);
                         if pseudo_rook(king, self.all ^ en_passant_mask, king_index) & line_sliders == 0 {
-                            self.push_move_with_code(move_slice, right_capturer, target, 0, 8);
+                            self.push_move_with_code(move_slice, right_capturer, target, 8);
                         }
                     }
                 }
@@ -1006,28 +1445,28 @@ impl Position {
                 while single_push != 0 {
                     let target = single_push & (!single_push + 1);
                     let origin = target << 8;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     single_push &= single_push - 1;
                 }
 
                 while double_push != 0 {
                     let target = double_push & (!double_push + 1);
                     let origin = target << 16;
-                    self.push_move_with_code(move_slice, origin, target, 0, 5);
+                    self.push_move_with_code(move_slice, origin, target, 5);
                     double_push &= double_push - 1;
                 }
 
                 while left_captures != 0 {
                     let target = left_captures & (!left_captures + 1);
                     let origin = target << 7;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     left_captures &= left_captures - 1;
                 }
 
                 while right_captures != 0 {
                     let target = right_captures & (!right_captures + 1);
                     let origin = target << 9;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     right_captures &= right_captures - 1;
                 }
             }
@@ -1040,7 +1479,7 @@ impl Position {
                 single_push &= evasion_mask;
 
 
-                if pawns & RANKS[1] != 0 {
+                if self.pawns & self.player & RANKS[1] != 0 {
                     let rank8 = RANKS[0];
                     let mut single_promo = single_push & rank8;
                     let mut left_capture_promo = left_captures & rank8;
@@ -1053,30 +1492,30 @@ impl Position {
                     while single_promo != 0 {
                         let target = single_promo & (!single_promo + 1);
                         let origin = target >> 8;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         single_promo &= single_promo - 1;
                     }
 
                     while left_capture_promo != 0 {
                         let target = left_capture_promo & (!left_capture_promo + 1);
                         let origin = target >> 9;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         left_capture_promo &= left_capture_promo - 1;
                     }
 
                     while right_capture_promo != 0 {
                         let target = right_capture_promo & (!right_capture_promo + 1);
                         let origin = target >> 7;
-                        self.push_move_with_code(move_slice, origin, target, 0, 1);
-                        self.push_move_with_code(move_slice, origin, target, 0, 2);
-                        self.push_move_with_code(move_slice, origin, target, 0, 3);
-                        self.push_move_with_code(move_slice, origin, target, 0, 4);
+                        self.push_move_with_code(move_slice, origin, target, 1);
+                        self.push_move_with_code(move_slice, origin, target, 2);
+                        self.push_move_with_code(move_slice, origin, target, 3);
+                        self.push_move_with_code(move_slice, origin, target, 4);
                         right_capture_promo &= right_capture_promo - 1;
                     }
                 }
@@ -1092,7 +1531,7 @@ impl Position {
                         let en_passant_mask = left_capturer | ps | target;
                         let line_sliders = self.enemy & (self.rooks | self.queens);
                         if pseudo_rook(king, self.all ^ en_passant_mask, king_index) & line_sliders == 0 {
-                            self.push_move_with_code(move_slice, left_capturer, target, 0, 8);
+                            self.push_move_with_code(move_slice, left_capturer, target, 8);
                         }
                     }
 
@@ -1102,7 +1541,7 @@ impl Position {
 
                         let line_sliders = self.enemy & (self.rooks | self.queens);
                         if pseudo_rook(king, self.all ^ en_passant_mask, king_index) & line_sliders == 0 {
-                            self.push_move_with_code(move_slice, right_capturer, target, 0, 8);
+                            self.push_move_with_code(move_slice, right_capturer, target, 8);
                         }
                     }
                 }
@@ -1110,28 +1549,28 @@ impl Position {
                 while single_push != 0 {
                     let target = single_push & (!single_push + 1);
                     let origin = target >> 8;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     single_push &= single_push - 1;
                 }
 
                 while double_push != 0 {
                     let target = double_push & (!double_push + 1);
                     let origin = target >> 16;
-                    self.push_move_with_code(move_slice, origin, target, 0, 5);
+                    self.push_move_with_code(move_slice, origin, target, 5);
                     double_push &= double_push - 1;
                 }
 
                 while left_captures != 0 {
                     let target = left_captures & (!left_captures + 1);
                     let origin = target >> 9;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     left_captures &= left_captures - 1;
                 }
 
                 while right_captures != 0 {
                     let target = right_captures & (!right_captures + 1);
                     let origin = target >> 7;
-                    self.push_move(move_slice, origin, target, 0);
+                    self.push_move(move_slice, origin, target);
                     right_captures &= right_captures - 1;
                 }
             }
@@ -1141,7 +1580,7 @@ impl Position {
                 let index = NonZeroU64::new(origin).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_knight(index) & move_mask;
                 while moves != 0 {
-                    self.push_move(move_slice, origin, moves & (!moves + 1), 1);
+                    self.push_move(move_slice, origin, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 knights &= knights - 1;
@@ -1152,7 +1591,7 @@ impl Position {
                 let origin_index = NonZeroU64::new(piece).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_bishop(piece, self.all, origin_index) & move_mask;
                 while moves != 0 {
-                    self.push_move(move_slice, piece, moves & (!moves + 1), 2);
+                    self.push_move(move_slice, piece, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 bishops &= bishops - 1;
@@ -1163,7 +1602,7 @@ impl Position {
                 let origin_index = NonZeroU64::new(piece).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_rook(piece, self.all, origin_index) & move_mask;
                 while moves != 0 {
-                    self.push_move(move_slice, piece, moves & (!moves + 1), 3);
+                    self.push_move(move_slice, piece, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 rooks &= rooks - 1;
@@ -1175,7 +1614,7 @@ impl Position {
                 let origin_index = NonZeroU64::new(piece).unwrap().leading_zeros() as usize;
                 let mut moves = pseudo_queen(piece, self.all, origin_index) & move_mask;
                 while moves != 0 {
-                    self.push_move(move_slice, piece, moves & (!moves + 1), 4);
+                    self.push_move(move_slice, piece, moves & (!moves + 1));
                     moves &= moves - 1;
                 }
                 queens &= queens - 1;
@@ -1190,7 +1629,7 @@ impl Position {
                     let mut moves = pseudo_rook(piece, self.all, origin_index) & space & LUT_ROOK[king_index];
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 3);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     line_sliders &= line_sliders - 1;
@@ -1203,7 +1642,7 @@ impl Position {
                     let mut moves = pseudo_rook(piece, self.all, origin_index) & space & LUT_ROOK[king_index];
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 4);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     line_sliders &= line_sliders - 1;
@@ -1218,7 +1657,7 @@ impl Position {
                     let mut moves = pseudo_bishop(piece, self.all, origin_index) & space & LUT_BISHOP[king_index];
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 2);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     diag_sliders &= diag_sliders - 1;
@@ -1231,7 +1670,7 @@ impl Position {
                     let mut moves = pseudo_bishop(piece, self.all, origin_index) & space & LUT_BISHOP[king_index];
                     while moves != 0 {
                         let target = moves & (!moves + 1);
-                        self.push_move(move_slice, piece, target, 4);
+                        self.push_move(move_slice, piece, target);
                         moves &= moves - 1;
                     }
                     diag_sliders &= diag_sliders - 1;
@@ -1241,21 +1680,31 @@ impl Position {
 
         let mut king_moves = pseudo_king(king_index) & space & !self.state.attack_mask;
         while king_moves != 0 {
-            self.push_move(move_slice, king, king_moves & (!king_moves + 1), 5);
+            self.push_move(move_slice, king, king_moves & (!king_moves + 1));
             king_moves &= king_moves - 1;
         }
     }
 
+    // The legal moves in generation order, without touching the move count of self
+    pub fn legal_moves(&self) -> Vec<Move> {
+        let mut list = [Move::default(); MAX_MOVE_COUNT];
+        let mut root = *self;
+        root.state.move_cnt = 0;
+        root.generate(&mut list[0..MAX_MOVE_COUNT]);
+        list[0..root.state.move_cnt].to_vec()
+    }
+
+    #[allow(clippy::identity_op)]
     pub fn make_move(&self, m: Move) -> Position {
-        let origin = m.origin;
-        let target = m.target;
-        let origin_index = index!(NonZeroU64::new(origin).unwrap());
-        let target_index = index!(NonZeroU64::new(target).unwrap());
+        let origin = m.origin();
+        let target = m.target();
+        let origin_index = m.origin_index();
+        let target_index = m.target_index();
         let oi_i16 = origin_index as i16;
         let ti_i16 = target_index as i16;
 
         let player_tier = self.state.turn as usize * 6;
-        let tier = m.tier();
+        let tier = self.piece_on(origin_index);
         let move_mask = origin | target;
 
         let mut pos = self.next(!move_mask, m);
@@ -1298,16 +1747,18 @@ impl Position {
         pos.all |= target;
         pos.player ^= move_mask;
         pos.enemy &= !target;
+        pos.board[origin_index] = EMPTY;
+        pos.board[target_index] = tier as u8;
 
-        match m.code {
+        match m.code() {
             // Normal moves
             0 => {
                 if pos.state.turn {
-                    pos.metrics.advancement += oi_i16 >> 3;
-                    pos.metrics.advancement -= ti_i16 >> 3;
-                } else {
                     pos.metrics.advancement += (63 - oi_i16) >> 3;
                     pos.metrics.advancement -= (63 - ti_i16) >> 3;
+                } else {
+                    pos.metrics.advancement += oi_i16 >> 3;
+                    pos.metrics.advancement -= ti_i16 >> 3;
                 }
             }
 
@@ -1315,6 +1766,7 @@ impl Position {
             1 => {
                 pos.pawns &= !target;
                 pos.knights |= target;
+                pos.board[target_index] = 1;
                 pos.state.key ^= HASH_PIECES[0 + player_tier][target_index];
                 pos.state.key ^= HASH_PIECES[1 + player_tier][target_index];
                 pos.metrics.material_balance += PIECE_VALUES[0];
@@ -1325,6 +1777,7 @@ impl Position {
             2 => {
                 pos.pawns &= !target;
                 pos.bishops |= target;
+                pos.board[target_index] = 2;
                 pos.state.key ^= HASH_PIECES[0 + player_tier][target_index];
                 pos.state.key ^= HASH_PIECES[2 + player_tier][target_index];
                 pos.metrics.material_balance += PIECE_VALUES[0];
@@ -1335,6 +1788,7 @@ impl Position {
             3 => {
                 pos.pawns &= !target;
                 pos.rooks |= target;
+                pos.board[target_index] = 3;
                 pos.state.key ^= HASH_PIECES[0 + player_tier][target_index];
                 pos.state.key ^= HASH_PIECES[3 + player_tier][target_index];
                 pos.metrics.material_balance += PIECE_VALUES[0];
@@ -1345,6 +1799,7 @@ impl Position {
             4 => {
                 pos.pawns &= !target;
                 pos.queens |= target;
+                pos.board[target_index] = 4;
                 pos.state.key ^= HASH_PIECES[0 + player_tier][target_index];
                 pos.state.key ^= HASH_PIECES[4 + player_tier][target_index];
                 pos.metrics.material_balance += PIECE_VALUES[0];
@@ -1355,7 +1810,9 @@ impl Position {
             // Double pushes
             5 => {
                 pos.state.en_passant = target;
-                pos.state.key ^= HASH_ENPASSANT[target_index & 7];
+                if hashes_en_passant(target, self.pawns & self.enemy) {
+                    pos.state.key ^= HASH_ENPASSANT[target_index & 7];
+                }
                 pos.metrics.advancement -= 2;
             }
 
@@ -1367,6 +1824,8 @@ impl Position {
                 pos.rooks ^= rook_mask;
                 pos.state.key ^= HASH_PIECES[3 + player_tier][target_index - 1];
                 pos.state.key ^= HASH_PIECES[3 + player_tier][target_index + 1];
+                pos.board[target_index - 1] = 3;
+                pos.board[target_index + 1] = EMPTY;
             }
 
             // Long castle
@@ -1377,14 +1836,18 @@ impl Position {
                 pos.rooks ^= rook_mask;
                 pos.state.key ^= HASH_PIECES[3 + player_tier][target_index - 2];
                 pos.state.key ^= HASH_PIECES[3 + player_tier][target_index + 1];
+                pos.board[target_index - 2] = EMPTY;
+                pos.board[target_index + 1] = 3;
             }
 
             // En-passant
             8 => {
                 pos.all ^= self.state.en_passant;
                 pos.enemy ^= self.state.en_passant;
-                pos.rooks ^= self.state.en_passant;
-                pos.state.key ^= HASH_PIECES[0 + (player_tier ^ 6)][index!(NonZeroU64::new(self.state.en_passant).unwrap())];
+                pos.pawns ^= self.state.en_passant;
+                let en_passant_index = index!(NonZeroU64::new(self.state.en_passant).unwrap());
+                pos.board[en_passant_index] = EMPTY;
+                pos.state.key ^= HASH_PIECES[0 + (player_tier ^ 6)][en_passant_index];
                 pos.metrics.material_balance -= PIECE_VALUES[0];
                 pos.metrics.advancement -= 4;
             }
@@ -1393,13 +1856,13 @@ impl Position {
         }
 
         if self.all & target != 0 {
-            let captured_tier = self.square_tier(target);
+            let captured_tier = self.piece_on(target_index);
             pos.state.key ^= HASH_PIECES[captured_tier + (player_tier ^ 6)][target_index];
             pos.metrics.material_balance -= PIECE_VALUES[captured_tier];
             if pos.state.turn {
-                pos.metrics.advancement -= (63 - ti_i16) >> 3;
-            } else {
                 pos.metrics.advancement -= ti_i16 >> 3;
+            } else {
+                pos.metrics.advancement -= (63 - ti_i16) >> 3;
             }
         }
 
@@ -1423,8 +1886,8 @@ impl Position {
             pos.state.key ^= HASH_BLACK_LONG_CASTLE;
         }
 
-        if self.state.last_move.code == 5 {
-            pos.state.key ^= HASH_ENPASSANT[index!(self.state.last_move.target) & 7];
+        if self.state.en_passant != 0 && hashes_en_passant(self.state.en_passant, self.pawns & self.player) {
+            pos.state.key ^= HASH_ENPASSANT[index!(self.state.en_passant) & 7];
         }
 
         pos.enemy = pos.player;
@@ -1437,11 +1900,51 @@ impl Position {
         let mut i = 0;
         while i < self.state.move_cnt {
             let m = move_slice[i];
-            if m.origin != m.target {
+            if m.origin() != m.target() {
                 m.print();
-                println!();
+                println!("{}", self.move_to_san(m));
             }
             i += 1;
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fen_accepts_legal_positions() {
+        for fen in [
+            STARTPOS_FEN,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "rnbqkbnr/ppp1pppp/8/8/3pP3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 3",
+            "8/8/8/8/8/8/8/K1k5  w  -  -",
+        ] {
+            let pos = Position::parse_fen(fen).unwrap();
+            assert_eq!(pos.to_fen().split(' ').next(), fen.split(' ').next());
+        }
+    }
+
+    #[test]
+    fn parse_fen_rejects_malformed_and_illegal_positions() {
+        for fen in [
+            "",
+            "foo bar",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP w KQkq - 0 1",
+            "rnbqkbnr/pppppppp/9/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/ppppxppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR x KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQxq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq e9 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq e3 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - x 1",
+            "8/8/8/8/8/8/8/K7 w - - 0 1",
+            "8/8/8/8/8/8/8/Kk6 w - - 0 1",
+            "4k3/8/8/8/8/8/8/4QK2 w - - 0 1",
+            "4k3/8/8/8/8/8/8/R3K3 w K - 0 1",
+            "4k3/8/8/8/8/8/8/P3K3 w - - 0 1",
+        ] {
+            assert!(Position::parse_fen(fen).is_err(), "{}", fen);
+        }
+    }
+}
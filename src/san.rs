@@ -0,0 +1,239 @@
+use crate::output::index_to_string;
+use crate::position::{FILE_CHARS, Move, Position};
+
+const PIECE_CHARS: [char; 6] = ['P', 'N', 'B', 'R', 'Q', 'K'];
+
+#[derive(Debug, PartialEq)]
+pub enum SanError {
+    Syntax,
+    Illegal,
+    Ambiguous,
+}
+
+impl Position {
+    // Writes a legal move in Standard Algebraic Notation, including check and mate suffixes
+    pub fn move_to_san(&self, m: Move) -> String {
+        let legal = self.legal_moves();
+        let origin = m.origin_index();
+        let target = m.target_index();
+        let capture = m.target() & self.enemy != 0 || m.code() == 8;
+
+        let mut san = String::new();
+        match m.code() {
+            6 => san.push_str("O-O"),
+            7 => san.push_str("O-O-O"),
+            _ => {
+                let tier = self.piece_on(origin);
+                if tier == 0 {
+                    if capture {
+                        san.push(FILE_CHARS[origin & 7]);
+                    }
+                } else {
+                    san.push(PIECE_CHARS[tier]);
+
+                    // Disambiguate by file if possible, then by rank, then by both
+                    let others: Vec<usize> = legal.iter()
+                        .filter(|o| self.piece_on(o.origin_index()) == tier && o.target() == m.target() && o.origin() != m.origin())
+                        .map(|o| o.origin_index())
+                        .collect();
+
+                    if !others.is_empty() {
+                        if others.iter().all(|&o| o & 7 != origin & 7) {
+                            san.push(FILE_CHARS[origin & 7]);
+                        } else if others.iter().all(|&o| o >> 3 != origin >> 3) {
+                            san.push_str(&((origin >> 3) + 1).to_string());
+                        } else {
+                            san.push_str(&index_to_string(origin));
+                        }
+                    }
+                }
+
+                if capture {
+                    san.push('x');
+                }
+                san.push_str(&index_to_string(target));
+
+                if m.code() != 0 && m.code() <= 4 {
+                    san.push('=');
+                    san.push(PIECE_CHARS[m.code() as usize]);
+                }
+            }
+        }
+
+        let node = self.make_move(m);
+        if node.state.check {
+            san.push(if node.legal_moves().is_empty() { '#' } else { '+' });
+        }
+        san
+    }
+
+    // Finds the legal move written in SAN. Castling with zeros, missing or extra check
+    // markers, promotions without '=' and long algebraic forms like Ng1-f3 are accepted.
+    pub fn parse_san(&self, san: &str) -> Result<Move, SanError> {
+        let san = san.trim().trim_end_matches(['+', '#', '!', '?']);
+        let san = san.strip_suffix("e.p.").unwrap_or(san).trim_end();
+        let legal = self.legal_moves();
+
+        match san {
+            "O-O" | "0-0" => return legal.iter().find(|m| m.code() == 6).copied().ok_or(SanError::Illegal),
+            "O-O-O" | "0-0-0" => return legal.iter().find(|m| m.code() == 7).copied().ok_or(SanError::Illegal),
+            _ => {}
+        }
+
+        let mut chars: Vec<char> = san.chars().filter(|&c| c != 'x' && c != '-' && c != '=' && c != ':').collect();
+
+        let tier = match chars.first() {
+            Some(&c) if c.is_ascii_uppercase() => {
+                chars.remove(0);
+                PIECE_CHARS.iter().position(|&p| p == c).ok_or(SanError::Syntax)?
+            }
+            _ => 0,
+        };
+
+        let mut promotion = 0;
+        if let Some(&c) = chars.last() {
+            if c.is_alphabetic() {
+                promotion = "NBRQ".find(c.to_ascii_uppercase()).ok_or(SanError::Syntax)? as u8 + 1;
+                chars.pop();
+            }
+        }
+
+        if chars.len() < 2 {
+            return Err(SanError::Syntax);
+        }
+        let rank = chars[chars.len() - 1].to_digit(10).ok_or(SanError::Syntax)? as usize;
+        let file = (chars[chars.len() - 2] as u8).checked_sub(b'a').ok_or(SanError::Syntax)? as usize;
+        if !(1..=8).contains(&rank) || file > 7 {
+            return Err(SanError::Syntax);
+        }
+        let target = file + ((rank - 1) << 3);
+
+        let mut from_file = None;
+        let mut from_rank = None;
+        for &c in chars[0..chars.len() - 2].iter() {
+            if ('1'..='8').contains(&c) {
+                from_rank = c.to_digit(10).map(|r| r as usize - 1);
+            } else if ('a'..='h').contains(&c) {
+                from_file = Some(c as usize - 'a' as usize);
+            } else {
+                return Err(SanError::Syntax);
+            }
+        }
+
+        let mut found = None;
+        for &m in legal.iter() {
+            let origin = m.origin_index();
+            let promo = if m.code() != 0 && m.code() <= 4 { m.code() } else { 0 };
+            if self.piece_on(m.origin_index()) == tier && m.target_index() == target && promo == promotion
+                && from_file.is_none_or(|f| origin & 7 == f) && from_rank.is_none_or(|r| origin >> 3 == r) {
+                if found.is_some() {
+                    return Err(SanError::Ambiguous);
+                }
+                found = Some(m);
+            }
+        }
+        found.ok_or(SanError::Illegal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::move_to_string;
+    use crate::position::STARTPOS_FEN;
+
+    fn find(pos: &Position, uci: &str) -> Move {
+        pos.legal_moves().into_iter().find(|&m| move_to_string(m) == uci).unwrap()
+    }
+
+    fn san(fen: &str, uci: &str) -> String {
+        let pos = Position::build_from_fen(fen);
+        pos.move_to_san(find(&pos, uci))
+    }
+
+    fn parse(fen: &str, san: &str) -> Result<String, SanError> {
+        Position::build_from_fen(fen).parse_san(san).map(move_to_string)
+    }
+
+    const KNIGHTS: &str = "4k3/8/8/8/8/8/8/1N1K1N2 w - - 0 1";
+    const ROOKS: &str = "4k3/8/8/R7/8/8/8/R3K3 w - - 0 1";
+    const QUEENS: &str = "4k3/8/8/8/8/Q7/8/Q1Q1K3 w - - 0 1";
+    const PROMOTION: &str = "3r4/4P3/8/8/8/8/k7/4K3 w - - 0 1";
+    const CASTLING: &str = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+    const MATE: &str = "7k/8/6K1/8/8/8/Q7/8 w - - 0 1";
+
+    #[test]
+    fn disambiguation() {
+        assert_eq!(san(KNIGHTS, "b1d2"), "Nbd2");
+        assert_eq!(san(KNIGHTS, "f1d2"), "Nfd2");
+        assert_eq!(san(KNIGHTS, "b1c3"), "Nc3");
+        assert_eq!(san(ROOKS, "a1a3"), "R1a3");
+        assert_eq!(san(ROOKS, "a5a3"), "R5a3");
+        assert_eq!(san(QUEENS, "a1b2"), "Qa1b2");
+        assert_eq!(san(QUEENS, "c1b2"), "Qcb2");
+        assert_eq!(san(QUEENS, "a3b2"), "Q3b2");
+
+        assert_eq!(parse(KNIGHTS, "Nbd2").unwrap(), "b1d2");
+        assert_eq!(parse(ROOKS, "R5a3").unwrap(), "a5a3");
+        assert_eq!(parse(QUEENS, "Qa1b2").unwrap(), "a1b2");
+        assert_eq!(parse(QUEENS, "Qa1-b2").unwrap(), "a1b2");
+        assert_eq!(parse(QUEENS, "Q3b2").unwrap(), "a3b2");
+    }
+
+    #[test]
+    fn promotions() {
+        assert_eq!(san(PROMOTION, "e7e8q"), "e8=Q");
+        assert_eq!(san(PROMOTION, "e7d8n"), "exd8=N");
+
+        assert_eq!(parse(PROMOTION, "e8=Q").unwrap(), "e7e8q");
+        assert_eq!(parse(PROMOTION, "e8Q").unwrap(), "e7e8q");
+        assert_eq!(parse(PROMOTION, "e8=r").unwrap(), "e7e8r");
+        assert_eq!(parse(PROMOTION, "exd8N").unwrap(), "e7d8n");
+        assert_eq!(parse(PROMOTION, "e8"), Err(SanError::Illegal));
+    }
+
+    #[test]
+    fn castling() {
+        assert_eq!(san(CASTLING, "e1g1"), "O-O");
+        assert_eq!(san(CASTLING, "e1c1"), "O-O-O");
+
+        assert_eq!(parse(CASTLING, "O-O").unwrap(), "e1g1");
+        assert_eq!(parse(CASTLING, "0-0").unwrap(), "e1g1");
+        assert_eq!(parse(CASTLING, "O-O-O").unwrap(), "e1c1");
+        assert_eq!(parse(CASTLING, "0-0-0+").unwrap(), "e1c1");
+        assert_eq!(parse(STARTPOS_FEN, "O-O"), Err(SanError::Illegal));
+    }
+
+    #[test]
+    fn check_and_mate_suffixes() {
+        assert_eq!(san(MATE, "a2a8"), "Qa8#");
+        assert_eq!(san(MATE, "a2h2"), "Qh2+");
+        assert_eq!(san(MATE, "a2a3"), "Qa3");
+
+        assert_eq!(parse(MATE, "Qa8").unwrap(), "a2a8");
+        assert_eq!(parse(MATE, "Qa8#").unwrap(), "a2a8");
+        assert_eq!(parse(MATE, "Qh2+!?").unwrap(), "a2h2");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse(KNIGHTS, "Nd2"), Err(SanError::Ambiguous));
+        assert_eq!(parse(QUEENS, "Qab2"), Err(SanError::Ambiguous));
+        assert_eq!(parse(QUEENS, "Q1b2"), Err(SanError::Ambiguous));
+        assert_eq!(parse(STARTPOS_FEN, "e5"), Err(SanError::Illegal));
+        assert_eq!(parse(STARTPOS_FEN, "Ke2"), Err(SanError::Illegal));
+        assert_eq!(parse(STARTPOS_FEN, "Zf3"), Err(SanError::Syntax));
+        assert_eq!(parse(STARTPOS_FEN, "e9"), Err(SanError::Syntax));
+        assert_eq!(parse(STARTPOS_FEN, ""), Err(SanError::Syntax));
+    }
+
+    #[test]
+    fn every_move_round_trips() {
+        for fen in [STARTPOS_FEN, KNIGHTS, ROOKS, QUEENS, PROMOTION, CASTLING, MATE] {
+            let pos = Position::build_from_fen(fen);
+            for m in pos.legal_moves() {
+                assert!(pos.parse_san(&pos.move_to_san(m)) == Ok(m), "{}", move_to_string(m));
+            }
+        }
+    }
+}
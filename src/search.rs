@@ -1,63 +1,229 @@
-use std::sync::mpsc::Receiver;
 use std::sync::MutexGuard;
-use std::time::Instant;
-use crate::eval::{DRAW, LOSS, TERMINATE, eval};
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use crate::eval::{DRAW, LOSS, TB_WIN, eval};
 use crate::ordering::{add_killer, PVSPicker};
-use crate::output::{Display, print_pv};
 use crate::position::{Move, Position};
 use crate::ordering::PickerStage::*;
-use crate::state::{MAX_MOVE_COUNT, SearchState};
+use crate::state::{MAX_MOVE_COUNT, MAX_PLY, SearchState};
+use crate::syzygy::{WDL_BLESSED_LOSS, WDL_CURSED_WIN};
+use crate::tt::Bound::*;
 
-pub fn pvs(state: &mut MutexGuard<SearchState>, receiver: &Receiver<bool>) {
-    let mut pos = state.root.clone();
+// Returned through the whole search once the stop flag is set or a limit is reached
+pub struct Aborted;
 
-    state.root_key = pos.state.key;
-    state.root_age = pos.state.half_move;
-    state.max_depth = 64;
+// The clock and the node limit are checked once per this many nodes
+const POLL_INTERVAL: u64 = 2048;
 
-    let slice = &mut state.move_table[pos.state.move_ptr..MAX_MOVE_COUNT];
+// Time between heartbeats during an iteration
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Called by both searches before they count their node, so every total is polled once.
+// A pondering search has no limits until the ponderhit, but still sends its heartbeats.
+fn poll(state: &mut MutexGuard<SearchState>, listener: &mut dyn SearchListener) -> Result<(), Aborted> {
+    if state.stop.load(Relaxed) {
+        return Err(Aborted);
+    }
+
+    let nodes = state.stats.pvs_nodes + state.stats.qs_nodes;
+    if !nodes.is_multiple_of(POLL_INTERVAL) {
+        return Ok(());
+    }
+
+    if !state.ponder.load(Relaxed) {
+        let out_of_nodes = state.limits.nodes.is_some_and(|n| nodes >= n);
+        let out_of_time = state.limits.time.is_some_and(|t| state.clock.elapsed() >= t);
+        if out_of_nodes || out_of_time {
+            state.stop.store(true, Relaxed);
+            return Err(Aborted);
+        }
+    }
+
+    if state.clock.elapsed() >= state.next_heartbeat {
+        state.next_heartbeat = state.clock.elapsed() + HEARTBEAT_INTERVAL;
+        let depth = state.depth;
+        listener.heartbeat(&result(state, Vec::new(), 0, depth));
+    }
+    Ok(())
+}
+
+// Distance from the root, indexes the killers and bounds the move table
+fn search_ply(pos: &Position, state: &MutexGuard<SearchState>) -> usize {
+    (pos.state.half_move - state.root_ply) as usize
+}
+
+// What a search found in its last completed iteration. The best move is None only when the
+// root has no legal moves, the ponder move is the reply the PV expects.
+#[derive(Clone, Default)]
+pub struct SearchResult {
+    pub best_move: Option<Move>,
+    pub ponder_move: Option<Move>,
+    pub score: i16,
+    pub depth: u8,
+    pub seldepth: u8,
+    pub nodes: u64,
+    pub tb_hits: u64,
+    pub hashfull: u64,
+    pub pv: Vec<Move>,
+    pub time: Duration,
+}
+
+impl SearchResult {
+    pub fn nps(&self) -> u64 {
+        (self.nodes as u128 * 1000 / self.time.as_millis().max(1)) as u64
+    }
+}
+
+// Receives the progress of pvs, every event is ignored unless implemented
+pub trait SearchListener {
+    // An iteration was completed, result is what pvs would return if it stopped now
+    fn iteration(&mut self, _result: &SearchResult) {}
+
+    // A root move is about to be searched at the given depth, number counts from 1
+    fn current_move(&mut self, _m: Move, _number: usize, _depth: u8) {}
+
+    // A root move beat the best one so far, the score is a lower bound for the iteration
+    fn bound(&mut self, _result: &SearchResult) {}
+
+    // Sent about once per HEARTBEAT_INTERVAL while an iteration runs, without score and PV
+    fn heartbeat(&mut self, _result: &SearchResult) {}
+}
+
+// For searches nobody watches
+pub struct NoListener;
+
+impl SearchListener for NoListener {}
+
+// Follows the TT moves from the given position, the line ends where the TT has no legal move
+// or at the first repetition
+pub fn principal_variation(root: &Position, state: &MutexGuard<SearchState>) -> Vec<Move> {
+    let mut pv = Vec::new();
+    let mut curr = *root;
+    let mut keys = vec![curr.state.key];
+
+    while let Some(entry) = state.hash_table.probe(curr.state.key) {
+        let m = entry.get_refutation();
+
+        if !curr.is_legal(m) || keys.len() > MAX_PLY {
+            break
+        }
+
+        pv.push(m);
+        curr = curr.make_move(m);
+
+        if keys.contains(&curr.state.key) {
+            break
+        }
+        keys.push(curr.state.key);
+    }
+    pv
+}
+
+// The root move followed by the PV the TT holds for the position after it
+fn line(root: &Position, m: Move, state: &MutexGuard<SearchState>) -> Vec<Move> {
+    let mut pv = vec![m];
+    pv.extend(principal_variation(&root.make_move(m), state));
+    pv
+}
+
+fn result(state: &MutexGuard<SearchState>, pv: Vec<Move>, score: i16, depth: u8) -> SearchResult {
+    SearchResult {
+        best_move: pv.first().copied(),
+        ponder_move: pv.get(1).copied(),
+        score,
+        depth,
+        seldepth: state.stats.seldepth,
+        nodes: state.stats.pvs_nodes + state.stats.qs_nodes,
+        tb_hits: state.stats.tb_hits,
+        hashfull: state.hash_table.hashfull(),
+        pv,
+        time: state.clock.elapsed(),
+    }
+}
+
+pub fn pvs(state: &mut MutexGuard<SearchState>, listener: &mut dyn SearchListener) -> SearchResult {
+    let mut pos = state.root;
+    pos.state.move_ptr = 0;
+
+    state.hash_table.new_search();
+    state.root_ply = pos.state.half_move;
+    state.next_heartbeat = HEARTBEAT_INTERVAL;
+    state.max_depth = state.limits.depth.max(1);
+
+    let slice = &mut state.move_table[0..MAX_MOVE_COUNT];
     pos.generate(slice);
 
-    let clock = Instant::now();
     let mut depth = 1;
     let mut bestmove = Move::default();
     let mut besteval;
 
+    // With tablebases available only the root moves with the best DTZ rank are searched
+    let mut ranks = None;
+    if state.tablebases.can_probe(&pos) {
+        let moves = state.move_table[0..pos.state.move_cnt].to_vec();
+        ranks = state.tablebases.root_ranks(&pos, &moves);
+    }
+    let best_rank = ranks.as_ref().and_then(|r| r.iter().max().copied());
+    let tb_score = best_rank.map(tb_rank_score);
+
+    // Mates found by the search are kept, otherwise the tablebase result is reported
+    let reported = |eval: i16| match tb_score {
+        Some(s) if eval.abs() != -LOSS => s,
+        _ => eval,
+    };
+
     let mut ordered_moves = [(Move::default(), 0); MAX_MOVE_COUNT];
-    for i in 0..MAX_MOVE_COUNT {
-        ordered_moves[i] = (state.move_table[i], 0);
+    let mut root_cnt = 0;
+    for i in 0..pos.state.move_cnt {
+        if ranks.as_ref().is_none_or(|r| Some(r[i]) == best_rank) {
+            ordered_moves[root_cnt] = (state.move_table[i], 0);
+            root_cnt += 1;
+        }
+    }
+
+    if root_cnt == 0 {
+        return result(state, Vec::new(), 0, 0);
     }
 
+    // An aborted iteration is discarded, the move of the last completed one is played
+    let mut completed = result(state, vec![ordered_moves[0].0], 0, 0);
+
     'outer: loop {
+        state.depth = depth;
         besteval = LOSS;
         let mut ptr = 0;
-        while ptr < pos.state.move_cnt {
+        while ptr < root_cnt {
 
             let m = ordered_moves[ptr].0;
-            let node = &mut pos.make_move(m);
-            let eval = -pvs_internal(node, state, LOSS, -besteval, depth-1, receiver);
+            listener.current_move(m, ptr + 1, depth);
 
-            if eval == -TERMINATE {
-                break 'outer;
-            }
+            let node = &mut pos.make_move(m);
+            let eval = match pvs_internal(node, state, listener, LOSS, -besteval, depth-1, 0, None) {
+                Ok(eval) => -eval,
+                Err(Aborted) => break 'outer,
+            };
 
             ordered_moves[ptr].1 = eval;
 
             if eval > besteval {
                 bestmove = m;
                 besteval = eval;
+
+                if ptr > 0 {
+                    let pv = line(&pos, m, state);
+                    listener.bound(&result(state, pv, reported(eval), depth));
+                }
             }
 
             ptr += 1;
         }
 
-        state.hash_table.place(pos.state.key, pos.state.half_move, pos.state.key, besteval, 1, depth, bestmove.to_u32());
+        state.hash_table.place(pos.state.key, besteval, Exact, depth, bestmove);
 
-        print!("info score cp {} nodes {} time {} depth {} ", besteval, state.stats.pvs_nodes, clock.elapsed().as_millis(), depth);
-        print_pv(&pos, state);
-        println!();
+        completed = result(state, line(&pos, bestmove, state), reported(besteval), depth);
+        listener.iteration(&completed);
 
-        ordered_moves[0..pos.state.move_cnt].sort_by(|l, r | r.1.cmp(&l.1));
+        ordered_moves[0..root_cnt].sort_by_key(|entry| std::cmp::Reverse(entry.1));
 
         if depth == state.max_depth {
             break 'outer;
@@ -66,75 +232,141 @@ pub fn pvs(state: &mut MutexGuard<SearchState>, receiver: &Receiver<bool>) {
         depth += 1;
     }
 
-    print!("bestmove ");
-    state.hash_table.probe(state.root_key).unwrap().get_refutation().print();
-    println!();
+    completed
 }
 
-pub fn pvs_internal(pos: &mut Position, state: &mut MutexGuard<SearchState>,
-                       alpha: i16, beta: i16, depth_left: u8, receiver: &Receiver<bool>) -> i16 {
+// Extensions a single line may collect, so extended searches stay bounded
+const MAX_EXTENSIONS: u8 = 16;
 
-    if receiver.try_recv().is_ok() {
-        return TERMINATE;
-    }
+// Singular extensions are tried from this depth on, with a TT entry at most 3 plies shallower
+const SINGULAR_DEPTH: u8 = 6;
 
+// extensions counts the extensions already spent on the line leading here. The excluded move
+// is skipped, which is how singular extensions search the alternatives to the TT move.
+#[allow(clippy::too_many_arguments)]
+pub fn pvs_internal(pos: &mut Position, state: &mut MutexGuard<SearchState>, listener: &mut dyn SearchListener, mut alpha: i16, beta: i16,
+                       depth_left: u8, extensions: u8, excluded: Option<Move>) -> Result<i16, Aborted> {
+
+    // Quiescence polls and counts the node itself
     if depth_left == 0 {
-        return quiesce(pos, state, alpha, beta);
+        return quiesce(pos, state, listener, alpha, beta, 0);
     }
 
+    poll(state, listener)?;
     state.stats.pvs_nodes += 1;
 
+    // The line has used up the move table, nothing is generated beyond it
+    let ply = search_ply(pos, state);
+    state.stats.seldepth = state.stats.seldepth.max(ply as u8);
+    if ply >= MAX_PLY - 1 {
+        return Ok(eval(pos));
+    }
+
     let mut ttmove = None;
+    let mut singular = None;
 
     state.stats.table_probes += 1;
     if let Some(entry) = state.hash_table.probe(pos.state.key) {
         state.stats.table_hits += 1;
-        if beta == alpha + 1 && entry.get_depth() >= depth_left {
-            let entryeval = entry.get_eval();
-            let outcome = entry.get_outcome();
-            if outcome == 1 {
-                return entryeval;
+        let entryeval = score_from_tt(entry.get_eval(), ply);
+        if beta == alpha + 1 && entry.get_depth() >= depth_left && excluded.is_none() {
+            match entry.get_bound() {
+                Exact => return Ok(entryeval),
+                Upper if entryeval <= alpha => return Ok(entryeval),
+                Lower if entryeval >= beta => return Ok(entryeval),
+                _ => {}
             }
-            if outcome == 0 && entryeval <= alpha {
-                return alpha;
+        }
+        let refutation = entry.get_refutation();
+        if refutation.origin() != refutation.target() {
+            ttmove = Some(refutation);
+
+            if depth_left >= SINGULAR_DEPTH && excluded.is_none() && entry.get_bound() != Upper
+                && entry.get_depth() + 3 >= depth_left && entryeval.abs() < TB_WIN - 1000 {
+                singular = Some((refutation, entryeval));
             }
-            if outcome == 2 && entryeval >= beta {
-                return beta;
+        }
+    }
+
+    // Tablebase results are exact, so they are stored with extra depth
+    let tb = &state.tablebases;
+    if excluded.is_none() && tb.can_probe(pos) && (pos.all.count_ones() < tb.max_pieces as u32 || depth_left >= tb.probe_depth) {
+        if let Some(wdl) = state.tablebases.probe_wdl(pos) {
+            state.stats.tb_hits += 1;
+
+            let (value, bound) = if wdl > WDL_CURSED_WIN {
+                (TB_WIN - ply as i16, Lower)
+            } else if wdl < WDL_BLESSED_LOSS {
+                (-TB_WIN + ply as i16, Upper)
+            } else {
+                (DRAW + wdl as i16, Exact)
+            };
+
+            if bound == Exact || (bound == Lower && value >= beta) || (bound == Upper && value <= alpha) {
+                let key = pos.state.key;
+                state.hash_table.place(key, score_to_tt(value, ply), bound, depth_left.saturating_add(6), Move::default());
+                return Ok(value);
+            }
+        }
+    }
+
+    // The TT move is singular if every alternative fails low against a margin below its score
+    // at reduced depth. If even the alternatives beat beta, several moves refute the position
+    // and the node is cut without searching it fully (multi-cut).
+    let mut singular_move = None;
+    if let Some((m, entryeval)) = singular {
+        if pos.is_legal(m) {
+            let singular_beta = entryeval - 2 * depth_left as i16;
+            let eval = pvs_internal(pos, state, listener, singular_beta - 1, singular_beta, (depth_left - 1) / 2, extensions, Some(m))?;
+            if eval < singular_beta {
+                singular_move = Some(m);
+            } else if singular_beta >= beta {
+                return Ok(singular_beta);
             }
         }
-        ttmove = Some(entry.get_refutation());
     }
 
-    let mut picker = PVSPicker::new(pos, ttmove, pos.state.half_move as usize);
+    // Checking moves and singular TT moves are searched a ply deeper while the budget lasts
+    // and the line still fits into the move table
+    let can_extend = extensions < MAX_EXTENSIONS && ply + (depth_left as usize) < MAX_PLY;
+    let child_depth = |m: Option<Move>, node: &Position| -> (u8, u8) {
+        if can_extend && (node.state.check || m == singular_move) {
+            (depth_left, extensions + 1)
+        } else {
+            (depth_left - 1, extensions)
+        }
+    };
+
+    let mut picker = PVSPicker::new(pos, ttmove, ply, excluded);
 
     let (mut m, mut node) = picker.next(state);
     let mut counter = 0;
-    let mut besteval = alpha;
+    let mut besteval = LOSS;
     let mut bestmove = m;
+    let original_alpha = alpha;
 
     // search first move with full window
-    if m.is_some() {
+    if let Some(first) = m {
         counter += 1;
 
-        let eval = -pvs_internal(&mut node.unwrap(), state, -beta, -alpha, depth_left-1, receiver);
-
-        if eval == -TERMINATE {
-            return TERMINATE;
-        }
+        let mut child = node.unwrap();
+        let (depth, ext) = child_depth(m, &child);
+        let eval = -pvs_internal(&mut child, state, listener, -beta, -alpha, depth, ext, None)?;
 
         if eval >= beta {
-            let rk = state.root_key;
-            let ra = state.root_age;
-            let key = pos.state.key;
-            state.hash_table.place(rk, ra, key, beta, 2, depth_left, m.unwrap().to_u32());
+            if excluded.is_none() {
+                let key = pos.state.key;
+                state.hash_table.place(key, score_to_tt(eval, ply), Lower, depth_left, first);
+            }
             state.stats.beta_cutoffs += 1;
 
-            return beta;
+            return Ok(eval);
         }
 
         if eval > besteval {
             besteval = eval;
             bestmove = m;
+            alpha = alpha.max(eval);
         }
     }
 
@@ -144,81 +376,134 @@ pub fn pvs_internal(pos: &mut Position, state: &mut MutexGuard<SearchState>,
     while m.is_some() {
         counter += 1;
 
-        let mut eval = -pvs_internal(&mut node.unwrap(), state, -besteval-1, -besteval, depth_left-1, receiver);
-        if eval > besteval && eval != -TERMINATE && beta != alpha+1{
-            eval = -pvs_internal(&mut node.unwrap(), state, -beta, -besteval, depth_left-1, receiver);
-        }
-
-        if eval == -TERMINATE {
-            return TERMINATE;
+        let mut child = node.unwrap();
+        let (depth, ext) = child_depth(m, &child);
+        let mut eval = -pvs_internal(&mut child, state, listener, -alpha-1, -alpha, depth, ext, None)?;
+        if eval > alpha && eval < beta {
+            eval = -pvs_internal(&mut child, state, listener, -beta, -alpha, depth, ext, None)?;
         }
 
         if eval >= beta {
             let mu = m.unwrap();
             if picker.stage == Quiet {
-                add_killer(pos.state.half_move, state, mu);
+                add_killer(ply, state, mu);
             }
 
-            let rk = state.root_key;
-            let ra = state.root_age;
-            let key = pos.state.key;
-            state.hash_table.place(rk, ra, key, beta, 2, depth_left, mu.to_u32());
+            if excluded.is_none() {
+                let key = pos.state.key;
+                state.hash_table.place(key, score_to_tt(eval, ply), Lower, depth_left, mu);
+            }
             state.stats.beta_cutoffs += 1;
 
-            return beta;
+            return Ok(eval);
         }
 
         if eval > besteval {
             besteval = eval;
             bestmove = m;
+            alpha = alpha.max(eval);
         }
 
         (m, node) = picker.next(state);
     }
 
 
+    // With the only legal move excluded there is nothing to compare the TT move against
+    if counter == 0 && excluded.is_some() {
+        return Ok(alpha);
+    }
+
     if counter == 0 {
-        return if pos.is_attacked(pos.player & pos.kings) {
+        return Ok(if pos.is_attacked(pos.player & pos.kings) {
             LOSS
         } else {
             DRAW
-        }
+        });
+    }
+
+    if excluded.is_none() {
+        let key = pos.state.key;
+        let bound = if besteval > original_alpha { Exact } else { Upper };
+        state.hash_table.place(key, score_to_tt(besteval, ply), bound, depth_left, bestmove.unwrap());
     }
 
-    let rk = state.root_key;
-    let ra = state.root_age;
-    let key = pos.state.key;
-    let outcome = (besteval != alpha) as u8;
-    state.hash_table.place(rk, ra, key, beta, outcome, depth_left, bestmove.unwrap().to_u32());
+    Ok(besteval)
+}
 
-    besteval
+// Tablebase scores count plies from the root, the table keeps them relative to the position
+// so they stay right when it is reached at another ply
+fn score_to_tt(score: i16, ply: usize) -> i16 {
+    if score.abs() > TB_WIN - MAX_PLY as i16 && score.abs() <= TB_WIN {
+        score + score.signum() * ply as i16
+    } else {
+        score
+    }
+}
+
+fn score_from_tt(score: i16, ply: usize) -> i16 {
+    if score.abs() > TB_WIN - MAX_PLY as i16 && score.abs() <= TB_WIN {
+        score - score.signum() * ply as i16
+    } else {
+        score
+    }
+}
+
+// Converts a root DTZ rank into a displayed score, cursed wins and blessed losses stay close to a draw
+fn tb_rank_score(rank: i32) -> i16 {
+    if rank > 900 {
+        TB_WIN
+    } else if rank > 0 {
+        ((rank - 800).max(3) / 2) as i16
+    } else if rank == 0 {
+        DRAW
+    } else if rank > -900 {
+        ((rank + 800).min(-3) / 2) as i16
+    } else {
+        -TB_WIN
+    }
 }
 
-pub fn quiesce(pos: &mut Position, state: &mut MutexGuard<SearchState>, alpha: i16, beta: i16) -> i16 {
+// Quiet checks are searched at the first plies of quiescence, deeper only captures and evasions
+const QS_CHECK_PLIES: u8 = 1;
+
+// qs_ply counts the plies since quiescence started, search_ply those since the root
+pub fn quiesce(pos: &mut Position, state: &mut MutexGuard<SearchState>, listener: &mut dyn SearchListener, mut alpha: i16, beta: i16,
+               qs_ply: u8) -> Result<i16, Aborted> {
+    poll(state, listener)?;
+
     state.stats.qs_nodes += 1;
 
-    let standing = eval(pos);
-    if standing >= beta {
-        return beta;
+    let ply = search_ply(pos, state);
+    state.stats.seldepth = state.stats.seldepth.max(ply as u8);
+    if ply >= MAX_PLY - 1 {
+        return Ok(eval(pos));
     }
 
-    let mut besteval = alpha;
-    if standing > besteval {
+    // No standing pat in check, the evasions decide whether the checks led to mate
+    let mut besteval = LOSS;
+    if !pos.state.check {
+        let standing = eval(pos);
+        if standing >= beta {
+            return Ok(standing);
+        }
+
         besteval = standing;
+        alpha = alpha.max(standing);
     }
 
+    // A re-search reaches the same position again with the old moves still counted
+    pos.state.move_cnt = 0;
     let slice = &mut state.move_table[pos.state.move_ptr..pos.state.move_ptr + MAX_MOVE_COUNT];
-    if pos.is_attacked(pos.kings & pos.player) {
+    if pos.state.check {
         pos.generate(slice);
         if pos.state.move_cnt == 0 {
-            return  if pos.is_attacked(pos.player & pos.kings) {
-                LOSS
-            } else {
-                DRAW
-            }
+            return Ok(LOSS);
         }
     } else {
         pos.generate_tactical(slice);
+        if qs_ply < QS_CHECK_PLIES {
+            pos.generate_checks(slice);
+        }
     }
 
     let mut ptr = pos.state.move_ptr;
@@ -228,76 +513,136 @@ pub fn quiesce(pos: &mut Position, state: &mut MutexGuard<SearchState>, alpha: i
         let m = state.move_table[ptr];
 
         let node = &mut pos.make_move(m);
-        let eval = -quiesce(node, state, -beta, -besteval);
+        let eval = -quiesce(node, state, listener, -beta, -alpha, qs_ply.saturating_add(1))?;
 
         if eval >= beta {
-            return beta;
+            return Ok(eval);
         }
 
         if eval > besteval {
             besteval = eval;
+            alpha = alpha.max(eval);
         }
         ptr += 1;
     }
 
-    return besteval;
+    Ok(besteval)
 }
 
 
-pub fn perft(state: &mut MutexGuard<SearchState>) {
-
-    let clock = Instant::now();
-    let mut pos = state.root.clone();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::AtomicBool;
+    use std::time::Instant;
+    use crate::output::move_to_string;
+    use crate::position::STARTPOS_FEN;
+
+    #[derive(Default)]
+    struct Recorder {
+        iterations: Vec<SearchResult>,
+        current: Vec<(Move, usize, u8)>,
+        bounds: Vec<SearchResult>,
+        heartbeats: usize,
+    }
 
-    let slice = &mut state.move_table[0..MAX_MOVE_COUNT];
-    pos.generate(slice);
+    impl SearchListener for Recorder {
+        fn iteration(&mut self, result: &SearchResult) { self.iterations.push(result.clone()) }
+        fn current_move(&mut self, m: Move, number: usize, depth: u8) { self.current.push((m, number, depth)) }
+        fn bound(&mut self, result: &SearchResult) { self.bounds.push(result.clone()) }
+        fn heartbeat(&mut self, _result: &SearchResult) { self.heartbeats += 1 }
+    }
 
-    if state.max_depth > 1 {
-        let mut i = 0;
-        let last = pos.state.move_cnt;
+    fn search(fen: &str, depth: u8, listener: &mut dyn SearchListener) -> SearchResult {
+        let mutex = Mutex::new(SearchState::new(Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false))));
+        let mut state = mutex.lock().unwrap();
+        state.root = Position::build_from_fen(fen);
+        state.limits.depth = depth;
+        state.clock = Instant::now();
+        pvs(&mut state, listener)
+    }
 
-        while i < last {
-            let start = state.stats.perft_nodes;
+    #[test]
+    fn result_holds_the_last_iteration() {
+        let mut recorder = Recorder::default();
+        let result = search("6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1", 3, &mut recorder);
 
-            let m = state.move_table[i];
-            let node = &mut pos.make_move(m);
+        assert_eq!(result.best_move.map(move_to_string).as_deref(), Some("d1d8"));
+        assert!(result.pv[0] == result.best_move.unwrap());
+        assert_eq!((result.score, result.depth), (-LOSS, 3));
+        assert!(result.nodes > 0 && result.seldepth >= 2);
 
-            perft_internal(state, node, 1);
+        let depths: Vec<u8> = recorder.iterations.iter().map(|r| r.depth).collect();
+        assert_eq!(depths, [1, 2, 3]);
+        assert_eq!(recorder.iterations[2].pv.len(), result.pv.len());
+    }
 
+    #[test]
+    fn every_root_move_is_announced() {
+        let mut recorder = Recorder::default();
+        search(STARTPOS_FEN, 2, &mut recorder);
 
-            m.print();
-            println!(": {}", state.stats.perft_nodes - start);
-            i += 1;
+        for depth in 1..=2 {
+            let numbers: Vec<usize> = recorder.current.iter().filter(|c| c.2 == depth).map(|c| c.1).collect();
+            assert_eq!(numbers, (1..=20).collect::<Vec<_>>());
+        }
+        for bound in recorder.bounds.iter() {
+            assert!(bound.best_move.is_some() && bound.depth <= 2);
         }
-    } else {
-        state.stats.perft_nodes += state.root.state.move_cnt as u64;
     }
 
-    let stop_time = clock.elapsed().as_millis();
-    println!();
-    println!("Nodes searched: {}", state.stats.perft_nodes);
-    println!("Time elapsed: {} ms", stop_time);
-    println!("Nodes per second: {} million", state.stats.perft_nodes as u128 / (stop_time + 1) / 1000)
-}
-
-pub fn perft_internal(state: &mut MutexGuard<SearchState>, pos: &mut Position, depth: u8) {
-    let slice = &mut state.move_table[pos.state.move_ptr..pos.state.move_ptr + MAX_MOVE_COUNT];
-    pos.generate(slice);
-
-    if depth + 1 == state.max_depth {
-        state.stats.perft_nodes += pos.state.move_cnt as u64;
-    } else {
-        let mut i = pos.state.move_ptr;
-        let last = pos.state.move_ptr + pos.state.move_cnt;
+    #[test]
+    fn no_best_move_without_legal_moves() {
+        let mut recorder = Recorder::default();
+        let result = search("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1", 3, &mut recorder);
+        assert!(result.best_move.is_none() && result.pv.is_empty());
+        assert!(recorder.iterations.is_empty());
+    }
 
-        while i < last {
-            let m = state.move_table[i];
-            let node = &mut pos.make_move(m);
+    #[test]
+    fn searching_a_position_again_gives_the_same_tree() {
+        // A re-search or a singular search reaches a position whose moves were generated
+        // before, they must not be searched a second time
+        let mutex = Mutex::new(SearchState::new(Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false))));
+        let mut state = mutex.lock().unwrap();
+        let mut listener = Recorder::default();
+        let mut pos = Position::build_from_fen("r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4");
+
+        for depth in [0, 3] {
+            let mut nodes = Vec::new();
+            for _ in 0..2 {
+                state.new_game();
+                state.root_ply = pos.state.half_move;
+                assert!(pvs_internal(&mut pos, &mut state, &mut listener, LOSS, -LOSS, depth, 0, None).is_ok());
+                nodes.push(state.stats.pvs_nodes + state.stats.qs_nodes);
+            }
+            assert_eq!(nodes[0], nodes[1]);
+        }
+    }
 
-            perft_internal(state, node, depth + 1);
+    #[test]
+    fn quiescence_nodes_send_heartbeats() {
+        let mutex = Mutex::new(SearchState::new(Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false))));
+        let mut state = mutex.lock().unwrap();
+        let mut recorder = Recorder::default();
+        let mut pos = Position::build_from_fen(STARTPOS_FEN);
+
+        // The heartbeat is due and the next node completes a poll interval
+        state.clock = Instant::now();
+        state.stats.pvs_nodes = 1;
+        state.stats.qs_nodes = POLL_INTERVAL - 1;
+        assert!(quiesce(&mut pos, &mut state, &mut recorder, LOSS, -LOSS, 0).is_ok());
+        assert_eq!(recorder.heartbeats, 1);
+    }
 
-            i += 1;
-        }
+    #[test]
+    fn cursed_ranks_are_not_reported_as_wins() {
+        // A DTZ of 99 plies wins within the fifty-move rule, 100 and above do not
+        assert_eq!(tb_rank_score(1000), TB_WIN);
+        assert!(tb_rank_score(1000 - 100) < TB_WIN && tb_rank_score(1000 - 100) > DRAW);
+        assert!(tb_rank_score(1000 - 101) < tb_rank_score(1000 - 100));
+        assert_eq!(tb_rank_score(0), DRAW);
+        assert_eq!(tb_rank_score(-1000), -TB_WIN);
     }
 }
-
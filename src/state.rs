@@ -1,74 +1,136 @@
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+use crate::book::Book;
 use crate::position::{Move, Position, STARTPOS_FEN};
+use crate::syzygy::Tablebases;
 use crate::tt::{create_tt, TT, TT_DEFAULT_SIZE};
 
-pub const MAX_PLY: usize = 64;
+// Plies a search line may reach, counting extensions and quiescence. Each ply has its own
+// slice of the move table, which move_ptr steps through modulo the table size.
+pub const MAX_PLY: usize = 128;
 pub const MAX_MOVE_COUNT: usize = 256;
 pub const MOVE_TABLE_SIZE: usize = MAX_PLY * MAX_MOVE_COUNT;
+const _: () = assert!(MOVE_TABLE_SIZE.is_power_of_two());
 
 pub struct SearchStats {
-    pub perft_nodes: u64,
     pub pvs_nodes: u64,
     pub qs_nodes: u64,
     pub beta_cutoffs: u64,
     pub table_probes: u64,
     pub table_hits: u64,
+    pub tb_hits: u64,
+    pub seldepth: u8,
 }
 
 impl SearchStats {
     pub fn new() -> SearchStats {
         SearchStats {
-            perft_nodes: 0,
             pvs_nodes: 0,
             qs_nodes: 0,
             beta_cutoffs: 0,
             table_probes: 0,
             table_hits: 0,
+            tb_hits: 0,
+            seldepth: 0,
+        }
+    }
+}
+
+// Limits of a single search, time and nodes are polled every few thousand nodes
+pub struct SearchLimits {
+    pub depth: u8,
+    pub nodes: Option<u64>,
+    pub time: Option<Duration>,
+}
+
+impl SearchLimits {
+    pub fn new() -> SearchLimits {
+        SearchLimits {
+            depth: MAX_PLY as u8,
+            nodes: None,
+            time: None,
         }
     }
 }
 
 pub struct SearchState {
     pub root: Position,
-    pub root_key: u64,
-    pub root_age: u8,
+    pub root_ply: u16,
     pub hash_table: TT,
     pub move_table: [Move; MOVE_TABLE_SIZE],
-    pub killer_table: [[Move; 2]; 64], // needs to match max_depth
+    pub killer_table: [[Move; 2]; MAX_PLY],
     pub max_depth: u8,
+    pub depth: u8,
+    pub next_heartbeat: Duration,
     pub stats: SearchStats,
+    pub tablebases: Tablebases,
+    pub limits: SearchLimits,
+    pub clock: Instant,
+    pub stop: Arc<AtomicBool>,
+    pub ponder: Arc<AtomicBool>,
 }
 
 impl SearchState {
-    pub fn new() -> SearchState {
+    pub fn new(stop: Arc<AtomicBool>, ponder: Arc<AtomicBool>) -> SearchState {
         SearchState {
             root: Position::build_from_fen(STARTPOS_FEN),
-            root_key: 0,
-            root_age: 0,
+            root_ply: 0,
             hash_table: create_tt(TT_DEFAULT_SIZE),
             move_table: [Move::default(); MOVE_TABLE_SIZE],
-            killer_table: [[Move::default(); 2]; 64],
+            killer_table: [[Move::default(); 2]; MAX_PLY],
             max_depth: 0,
+            depth: 0,
+            next_heartbeat: Duration::ZERO,
             stats: SearchStats::new(),
+            tablebases: Tablebases::new(),
+            limits: SearchLimits::new(),
+            clock: Instant::now(),
+            stop,
+            ponder,
         }
     }
+
+    // Forgets what earlier games taught the search, so games of a match do not depend on
+    // the ones played before
+    pub fn new_game(&mut self) {
+        self.hash_table.reset();
+        self.killer_table = [[Move::default(); 2]; MAX_PLY];
+        self.stats = SearchStats::new();
+        self.root = Position::build_from_fen(STARTPOS_FEN);
+        self.root_ply = 0;
+    }
 }
 
 pub struct EngineState {
     pub root: Position,
     pub move_buffer: [Move; MAX_MOVE_COUNT],
-    pub terminate: Arc<AtomicBool>,
+    pub stop: Arc<AtomicBool>,
+    pub ponder: Arc<AtomicBool>,
     pub search_state: Arc<Mutex<SearchState>>,
+    pub book: Option<Book>,
+    pub own_book: bool,
+    pub book_best_only: bool,
+    pub hash_save_depth: u8,
+    pub search: Option<JoinHandle<()>>,
 }
 
 impl EngineState {
     pub fn new() -> EngineState {
+        let stop = Arc::new(AtomicBool::new(false));
+        let ponder = Arc::new(AtomicBool::new(false));
         EngineState {
             root: Position::build_from_fen(STARTPOS_FEN),
             move_buffer: [Move::default(); MAX_MOVE_COUNT],
-            terminate: Arc::new(AtomicBool::new(false)),
-            search_state: Arc::new(Mutex::new(SearchState::new())),
+            stop: stop.clone(),
+            ponder: ponder.clone(),
+            search_state: Arc::new(Mutex::new(SearchState::new(stop, ponder))),
+            book: None,
+            own_book: false,
+            book_best_only: false,
+            hash_save_depth: 0,
+            search: None,
         }
     }
 }
\ No newline at end of file
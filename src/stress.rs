@@ -0,0 +1,220 @@
+use std::panic;
+use crate::output::move_to_string;
+use crate::position::{Move, Position, STARTPOS_FEN};
+use crate::state::MAX_MOVE_COUNT;
+
+// Random games rarely end on their own, they are cut off here to keep the run short
+const MAX_PLIES: usize = 200;
+
+// Start positions: the perft suite covers castling, promotions, en-passant and pins
+const SUITE: &str = include_str!("../tests/perft.epd");
+
+pub struct Failure {
+    pub start: String,
+    pub moves: Vec<Move>,
+    pub fen: String,
+    pub errors: Vec<String>,
+}
+
+impl Failure {
+    pub fn print(&self) {
+        println!("Validation failed");
+        println!("Start: {}", self.start);
+        println!("Moves: {}", self.moves.iter().map(|&m| move_to_string(m)).collect::<Vec<_>>().join(" "));
+        println!("Position: {}", self.fen);
+        for error in self.errors.iter() {
+            println!("  {}", error);
+        }
+    }
+}
+
+// xorshift64*, good enough to pick moves
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+}
+
+pub fn start_positions() -> Vec<String> {
+    let mut fens = vec![STARTPOS_FEN.trim().to_string()];
+    fens.extend(SUITE.lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(';').next().unwrap().trim().to_string()));
+    fens
+}
+
+// is_legal has to agree with the generator. The moves of the previous position are good
+// candidates, they behave like stale killers or TT moves.
+fn check_legality(pos: &Position, legal: &[Move], candidates: &[Move]) -> Vec<String> {
+    candidates.iter().chain(legal.iter())
+        .filter(|&&m| pos.is_legal(m) != legal.contains(&m))
+        .map(|&m| format!("is_legal is {} for {} (code {})", pos.is_legal(m), move_to_string(m), m.code()))
+        .collect()
+}
+
+// The picker generates tactical moves first and quiet moves after them, together they
+// have to produce exactly the legal moves
+fn check_staged(pos: &Position, legal: &[Move]) -> Vec<String> {
+    let mut list = [Move::default(); MAX_MOVE_COUNT];
+    let mut staged = *pos;
+    staged.state.move_cnt = 0;
+    staged.generate_tactical(&mut list[0..MAX_MOVE_COUNT]);
+    staged.generate_quiet(&mut list[0..MAX_MOVE_COUNT]);
+    let staged = &list[0..staged.state.move_cnt];
+
+    let mut errors: Vec<String> = staged.iter().enumerate()
+        .filter(|&(i, m)| !legal.contains(m) || staged[..i].contains(m))
+        .map(|(_, &m)| format!("staged generation has extra move {} (code {})", move_to_string(m), m.code()))
+        .collect();
+    errors.extend(legal.iter()
+        .filter(|m| !staged.contains(m))
+        .map(|&m| format!("staged generation misses {} (code {})", move_to_string(m), m.code())));
+    errors
+}
+
+// gives_check has to agree with making the move, generate_checks with filtering the quiet moves
+fn check_checks(pos: &Position, legal: &[Move]) -> Vec<String> {
+    let mut errors: Vec<String> = legal.iter()
+        .filter(|&&m| pos.gives_check(m) != pos.make_move(m).state.check)
+        .map(|&m| format!("gives_check is {} for {} (code {})", pos.gives_check(m), move_to_string(m), m.code()))
+        .collect();
+
+    if !pos.state.check {
+        let mut list = [Move::default(); MAX_MOVE_COUNT];
+        let mut quiet = *pos;
+        quiet.state.move_cnt = 0;
+        quiet.generate_quiet(&mut list[0..MAX_MOVE_COUNT]);
+        let expected: Vec<Move> = list[0..quiet.state.move_cnt].iter().copied().filter(|&m| pos.gives_check(m)).collect();
+
+        let mut checks = *pos;
+        checks.state.move_cnt = 0;
+        checks.generate_checks(&mut list[0..MAX_MOVE_COUNT]);
+        let generated = &list[0..checks.state.move_cnt];
+
+        errors.extend(generated.iter().enumerate()
+            .filter(|&(i, m)| !expected.contains(m) || generated[..i].contains(m))
+            .map(|(_, &m)| format!("generate_checks has extra move {} (code {})", move_to_string(m), m.code())));
+        errors.extend(expected.iter()
+            .filter(|m| !generated.contains(m))
+            .map(|&m| format!("generate_checks misses {} (code {})", move_to_string(m), m.code())));
+    }
+    errors
+}
+
+// Plays random legal moves until the game ends or MAX_PLIES is reached, validating every
+// position on the way. Returns the number of positions checked.
+fn play_game(start: &str, rng: &mut Rng) -> Result<usize, Failure> {
+    let mut pos = Position::build_from_fen(start);
+    let mut moves = Vec::new();
+    let fail = |moves: &Vec<Move>, fen: String, errors: Vec<String>| Failure {
+        start: start.to_string(),
+        moves: moves.clone(),
+        fen,
+        errors,
+    };
+
+    if let Err(errors) = pos.validate() {
+        return Err(fail(&moves, pos.to_fen(), errors));
+    }
+
+    let mut previous = Vec::new();
+    for ply in 0..MAX_PLIES {
+        let legal = pos.legal_moves();
+        let mut errors = check_legality(&pos, &legal, &previous);
+        errors.extend(check_staged(&pos, &legal));
+        errors.extend(check_checks(&pos, &legal));
+        if !errors.is_empty() {
+            return Err(fail(&moves, pos.to_fen(), errors));
+        }
+        if legal.is_empty() {
+            return Ok(ply + 1);
+        }
+
+        let m = legal[(rng.next() % legal.len() as u64) as usize];
+        moves.push(m);
+
+        match panic::catch_unwind(|| pos.make_move(m)) {
+            Ok(next) => pos = next,
+            Err(_) => return Err(fail(&moves, pos.to_fen(), vec!["make_move panicked".to_string()])),
+        }
+        pos.state.move_ptr = 0;
+
+        if let Err(errors) = pos.validate() {
+            return Err(fail(&moves, pos.to_fen(), errors));
+        }
+        previous = legal;
+    }
+    Ok(MAX_PLIES + 1)
+}
+
+// Plays the given number of games, cycling through the start positions
+pub fn stress(games: u64, seed: u64) -> Result<usize, Failure> {
+    let starts = start_positions();
+    let mut rng = Rng(seed | 1);
+    let mut positions = 0;
+
+    for game in 0..games {
+        positions += play_game(&starts[game as usize % starts.len()], &mut rng)?;
+    }
+    Ok(positions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitboard::BITS;
+
+    #[test]
+    fn start_positions_are_valid() {
+        for fen in start_positions() {
+            assert!(Position::build_from_fen(&fen).validate().is_ok(), "{}", fen);
+        }
+    }
+
+    // Every combination of origin, target and code for the pieces of the side to move, the
+    // codes above 8 do not exist
+    fn all_candidates(pos: &Position) -> Vec<Move> {
+        let mut candidates = Vec::new();
+        for &origin in BITS.iter() {
+            if origin & pos.player == 0 {
+                continue;
+            }
+            for &target in BITS.iter() {
+                for code in 0..16 {
+                    candidates.push(Move::new(origin, target, code));
+                }
+            }
+        }
+        candidates
+    }
+
+    #[test]
+    fn is_legal_matches_generator() {
+        let mut rng = Rng(0x1E6A1);
+        for fen in start_positions() {
+            let mut pos = Position::build_from_fen(&fen);
+            for _ in 0..4 {
+                let legal = pos.legal_moves();
+                let errors = check_legality(&pos, &legal, &all_candidates(&pos));
+                assert!(errors.is_empty(), "{}: {:?}", pos.to_fen(), errors);
+                if legal.is_empty() {
+                    break;
+                }
+                pos = pos.make_move(legal[(rng.next() % legal.len() as u64) as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn random_games() {
+        if let Err(failure) = stress(500, 0x5EED) {
+            failure.print();
+            panic!("validation failed");
+        }
+    }
+}
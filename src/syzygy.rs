@@ -0,0 +1,2087 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use crate::bitboard::{BITS, LUT_KING};
+use crate::position::{Move, Position};
+
+// Syzygy tablebase probing, following the layout of the reference implementation.
+// Squares are numbered a1 = 0 to h8 = 63 like Piston's indices, pieces are encoded
+// as tier + 1 for white and tier + 9 for black.
+
+pub const TB_PIECES: usize = 7;
+
+pub const WDL_LOSS: i32 = -2;
+pub const WDL_BLESSED_LOSS: i32 = -1;
+pub const WDL_DRAW: i32 = 0;
+pub const WDL_CURSED_WIN: i32 = 1;
+pub const WDL_WIN: i32 = 2;
+
+const WDL_MAGIC: [u8; 4] = [0x71, 0xE8, 0x23, 0x5D];
+const DTZ_MAGIC: [u8; 4] = [0xD7, 0x66, 0x0C, 0xA5];
+
+const FLAG_STM: u8 = 1;
+const FLAG_MAPPED: u8 = 2;
+const FLAG_WIN_PLIES: u8 = 4;
+const FLAG_LOSS_PLIES: u8 = 8;
+const FLAG_WIDE: u8 = 16;
+const FLAG_SINGLE_VALUE: u8 = 128;
+
+const PIECE_CHARS: [char; 6] = ['P', 'N', 'B', 'R', 'Q', 'K'];
+
+const MAP_B1H1H7:      [usize; 64]     = get_map_b1h1h7();
+const MAP_A1D1D4:      [usize; 64]     = get_map_a1d1d4();
+const MAP_KK:          [[u64; 64]; 10] = get_map_kk();
+const BINOMIAL:        [[u64; 64]; 7]  = get_binomial();
+const MAP_PAWNS:       [usize; 64]     = get_map_pawns();
+const LEAD_PAWN_IDX:   [[u64; 64]; 6]  = get_lead_pawn_idx();
+const LEAD_PAWNS_SIZE: [[u64; 4]; 6]   = get_lead_pawns_size();
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ProbeState {
+    Fail,
+    Ok,
+    ChangeStm,
+    ZeroingBestMove,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum TableType {
+    Wdl,
+    Dtz,
+}
+
+// Decoding information for one side and leading file of a table
+#[derive(Clone, Default)]
+struct PairsData {
+    flags: u8,
+    sizeof_block: usize,
+    span: usize,
+    num_blocks: usize,
+    max_sym_len: usize,
+    min_sym_len: usize,
+    lowest_sym: usize,
+    btree: usize,
+    block_length: usize,
+    block_length_size: usize,
+    sparse_index: usize,
+    sparse_index_size: usize,
+    data: usize,
+    base64: Vec<u64>,
+    symlen: Vec<u8>,
+    pieces: [u8; TB_PIECES],
+    group_idx: [u64; TB_PIECES + 1],
+    group_len: [usize; TB_PIECES + 1],
+    map_idx: [usize; 4],
+}
+
+#[derive(Clone)]
+struct TableInfo {
+    key: u64,
+    key2: u64,
+    piece_count: usize,
+    has_pawns: bool,
+    has_unique_pieces: bool,
+    pawn_count: [usize; 2],
+}
+
+// A table file read whole into memory. Nothing is memory mapped: a file is loaded the first
+// time a position of its material is probed, which happens during search with the search
+// state locked, and stays loaded until the tablebases are initialized again. A long search
+// with a 6-piece set can pull in many gigabytes, the set has to fit into memory.
+struct LoadedTable {
+    bytes: Vec<u8>,
+    sides: usize,
+    pairs: Vec<PairsData>,
+    map: usize,
+}
+
+impl LoadedTable {
+    fn get(&self, stm: usize, file: usize) -> &PairsData {
+        &self.pairs[file * self.sides + stm % self.sides]
+    }
+
+    // Reads are checked, so a damaged file makes a probe fail rather than panic
+    fn read<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.bytes.get(offset..offset.checked_add(N)?)?.try_into().ok()
+    }
+
+    fn u8_at(&self, offset: usize) -> Option<u8> {
+        self.bytes.get(offset).copied()
+    }
+
+    fn u16_le(&self, offset: usize) -> Option<u16> {
+        self.read(offset).map(u16::from_le_bytes)
+    }
+
+    fn u32_le(&self, offset: usize) -> Option<u32> {
+        self.read(offset).map(u32::from_le_bytes)
+    }
+
+    fn u32_be(&self, offset: usize) -> Option<u32> {
+        self.read(offset).map(u32::from_be_bytes)
+    }
+
+    fn u64_be(&self, offset: usize) -> Option<u64> {
+        self.read(offset).map(u64::from_be_bytes)
+    }
+}
+
+enum TableFile {
+    Unloaded(String),
+    Loaded(LoadedTable),
+    Missing,
+}
+
+struct TableEntry {
+    info: TableInfo,
+    wdl: TableFile,
+    dtz: TableFile,
+}
+
+pub struct Tablebases {
+    tables: Vec<TableEntry>,
+    index: HashMap<u64, usize>,
+    paths: Vec<PathBuf>,
+    pub max_pieces: usize,
+    pub probe_depth: u8,
+}
+
+impl Tablebases {
+    pub fn new() -> Tablebases {
+        Tablebases {
+            tables: Vec::new(),
+            index: HashMap::new(),
+            paths: Vec::new(),
+            max_pieces: 0,
+            probe_depth: 1,
+        }
+    }
+
+    // Registers every table found in the given directories, returns the number of WDL tables
+    pub fn init(&mut self, path: &str) -> usize {
+        self.tables.clear();
+        self.index.clear();
+        self.max_pieces = 0;
+
+        let separator = if cfg!(windows) { ';' } else { ':' };
+        self.paths = path.split(separator).filter(|p| !p.is_empty() && *p != "<empty>").map(PathBuf::from).collect();
+        if self.paths.is_empty() {
+            return 0;
+        }
+
+        // Tiers from pawn (0) to queen (4), higher tiers first within a side
+        for p1 in 0..5 {
+            self.add(&[p1], &[]);
+            for p2 in 0..=p1 {
+                self.add(&[p1, p2], &[]);
+                self.add(&[p1], &[p2]);
+                for p3 in 0..5 {
+                    self.add(&[p1, p2], &[p3]);
+                }
+                for p3 in 0..=p2 {
+                    self.add(&[p1, p2, p3], &[]);
+                    for p4 in 0..=p3 {
+                        self.add(&[p1, p2, p3, p4], &[]);
+                        for p5 in 0..=p4 {
+                            self.add(&[p1, p2, p3, p4, p5], &[]);
+                        }
+                        for p5 in 0..5 {
+                            self.add(&[p1, p2, p3, p4], &[p5]);
+                        }
+                    }
+                    for p4 in 0..5 {
+                        self.add(&[p1, p2, p3], &[p4]);
+                        for p5 in 0..=p4 {
+                            self.add(&[p1, p2, p3], &[p4, p5]);
+                        }
+                    }
+                }
+                for p3 in 0..=p1 {
+                    let max = if p1 == p3 { p2 } else { p3 };
+                    for p4 in 0..=max {
+                        self.add(&[p1, p2], &[p3, p4]);
+                    }
+                }
+            }
+        }
+
+        self.tables.len()
+    }
+
+    fn add(&mut self, white: &[usize], black: &[usize]) {
+        let mut code = String::from("K");
+        code.extend(white.iter().map(|&t| PIECE_CHARS[t]));
+        code.push_str("vK");
+        code.extend(black.iter().map(|&t| PIECE_CHARS[t]));
+
+        let wdl_path = match self.find_file(&code, "rtbw") {
+            Some(path) => path,
+            None => return,
+        };
+        let dtz = match self.find_file(&code, "rtbz") {
+            Some(path) => TableFile::Unloaded(path),
+            None => TableFile::Missing,
+        };
+
+        let info = table_info(white, black);
+        self.max_pieces = self.max_pieces.max(info.piece_count);
+
+        let i = self.tables.len();
+        self.index.insert(info.key, i);
+        self.index.insert(info.key2, i);
+        self.tables.push(TableEntry { info, wdl: TableFile::Unloaded(wdl_path), dtz });
+    }
+
+    fn find_file(&self, code: &str, extension: &str) -> Option<String> {
+        for dir in self.paths.iter() {
+            let path = dir.join(format!("{}.{}", code, extension));
+            if path.is_file() {
+                return Some(path.to_string_lossy().to_string());
+            }
+        }
+        None
+    }
+
+    // Whether the position can be probed at all, castling rights are not stored in tablebases
+    pub fn can_probe(&self, pos: &Position) -> bool {
+        self.max_pieces > 0 && pos.state.castle_flags == 0 && pos.all.count_ones() as usize <= self.max_pieces
+    }
+
+    // Win/draw/loss from the side to move's point of view, from WDL_LOSS to WDL_WIN
+    pub fn probe_wdl(&mut self, pos: &Position) -> Option<i32> {
+        if !self.can_probe(pos) {
+            return None;
+        }
+
+        let mut result = ProbeState::Ok;
+        let wdl = self.search(pos, &mut result, false);
+        if result == ProbeState::Fail {
+            return None;
+        }
+        Some(wdl)
+    }
+
+    // Distance to a zeroing move in plies, positive if winning and negative if losing.
+    // Cursed wins and blessed losses are offset by 100.
+    pub fn probe_dtz(&mut self, pos: &Position) -> Option<i32> {
+        if !self.can_probe(pos) {
+            return None;
+        }
+
+        let mut result = ProbeState::Ok;
+        let dtz = self.probe_dtz_internal(pos, &mut result);
+        if result == ProbeState::Fail {
+            return None;
+        }
+        Some(dtz)
+    }
+
+    // Ranks each root move by its DTZ, a higher rank is better. Without a fifty-move
+    // counter the root is assumed to have just been reset.
+    pub fn root_ranks(&mut self, pos: &Position, moves: &[Move]) -> Option<Vec<i32>> {
+        if !self.can_probe(pos) {
+            return None;
+        }
+
+        let mut ranks = Vec::with_capacity(moves.len());
+        for &m in moves {
+            let mut result = ProbeState::Ok;
+            let node = pos.make_move(m);
+
+            let mut dtz = if is_zeroing(pos, m) {
+                dtz_before_zeroing(-self.search(&node, &mut result, false))
+            } else {
+                let dtz = -self.probe_dtz_internal(&node, &mut result);
+                if dtz > 0 { dtz + 1 } else if dtz < 0 { dtz - 1 } else { dtz }
+            };
+
+            if node.state.check && dtz == 2 && node.legal_moves().is_empty() {
+                dtz = 1;
+            }
+
+            if result == ProbeState::Fail {
+                return None;
+            }
+
+            let rank = if dtz > 0 {
+                if dtz <= 99 { 1000 } else { 1000 - dtz }
+            } else if dtz < 0 {
+                if -dtz * 2 < 100 { -1000 } else { -1000 - dtz }
+            } else {
+                0
+            };
+            ranks.push(rank);
+        }
+
+        Some(ranks)
+    }
+
+    fn probe_dtz_internal(&mut self, pos: &Position, result: &mut ProbeState) -> i32 {
+        *result = ProbeState::Ok;
+        let wdl = self.search(pos, result, true);
+
+        if *result == ProbeState::Fail || wdl == WDL_DRAW {
+            return 0;
+        }
+
+        if *result == ProbeState::ZeroingBestMove {
+            return dtz_before_zeroing(wdl);
+        }
+
+        let dtz = self.probe_table(pos, TableType::Dtz, wdl, result);
+
+        if *result == ProbeState::Fail {
+            return 0;
+        }
+
+        if *result != ProbeState::ChangeStm {
+            let offset = if wdl == WDL_BLESSED_LOSS || wdl == WDL_CURSED_WIN { 100 } else { 0 };
+            return (dtz + offset) * wdl.signum();
+        }
+
+        // The table stores the other side to move, so search one ply for the best DTZ
+        let mut min_dtz = i32::MAX;
+        for m in pos.legal_moves() {
+            let zeroing = is_zeroing(pos, m);
+            let node = pos.make_move(m);
+
+            let mut dtz = if zeroing {
+                -dtz_before_zeroing(self.search(&node, result, false))
+            } else {
+                -self.probe_dtz_internal(&node, result)
+            };
+
+            if dtz == 1 && node.state.check && node.legal_moves().is_empty() {
+                min_dtz = 1;
+            }
+
+            if !zeroing {
+                dtz += dtz.signum();
+            }
+
+            if dtz < min_dtz && dtz.signum() == wdl.signum() {
+                min_dtz = dtz;
+            }
+
+            if *result == ProbeState::Fail {
+                return 0;
+            }
+        }
+
+        if min_dtz == i32::MAX { -1 } else { min_dtz }
+    }
+
+    // Tables may store "don't care" values where the side to move has a winning capture,
+    // so captures (and pawn moves for DTZ) are searched before probing the table itself
+    fn search(&mut self, pos: &Position, result: &mut ProbeState, check_zeroing: bool) -> i32 {
+        let mut best = WDL_LOSS;
+        let list = pos.legal_moves();
+        let total = list.len();
+        let mut move_count = 0;
+
+        for &m in list.iter() {
+            if !is_capture(pos, m) && (!check_zeroing || m.origin() & pos.pawns == 0) {
+                continue;
+            }
+
+            move_count += 1;
+
+            let node = pos.make_move(m);
+            let value = -self.search(&node, result, false);
+
+            if *result == ProbeState::Fail {
+                return WDL_DRAW;
+            }
+
+            if value > best {
+                best = value;
+                if value >= WDL_WIN {
+                    *result = ProbeState::ZeroingBestMove;
+                    return value;
+                }
+            }
+        }
+
+        let no_more_moves = move_count != 0 && move_count == total;
+
+        let value = if no_more_moves {
+            best
+        } else {
+            let value = self.probe_table(pos, TableType::Wdl, WDL_DRAW, result);
+            if *result == ProbeState::Fail {
+                return WDL_DRAW;
+            }
+            value
+        };
+
+        if best >= value {
+            *result = if best > WDL_DRAW || no_more_moves { ProbeState::ZeroingBestMove } else { ProbeState::Ok };
+            return best;
+        }
+
+        *result = ProbeState::Ok;
+        value
+    }
+
+    fn probe_table(&mut self, pos: &Position, table_type: TableType, wdl: i32, result: &mut ProbeState) -> i32 {
+        if pos.all.count_ones() == 2 {
+            return WDL_DRAW;
+        }
+
+        let key = material_key(pos, false);
+        let i = match self.index.get(&key) {
+            Some(&i) => i,
+            None => {
+                *result = ProbeState::Fail;
+                return 0;
+            }
+        };
+
+        let entry = &mut self.tables[i];
+        let info = entry.info.clone();
+        let file = if table_type == TableType::Wdl { &mut entry.wdl } else { &mut entry.dtz };
+
+        if let TableFile::Unloaded(path) = file {
+            *file = match load_table(path, &info, table_type) {
+                Some(table) => TableFile::Loaded(table),
+                None => {
+                    println!("info string Corrupted or unreadable tablebase {}", path);
+                    TableFile::Missing
+                }
+            };
+        }
+
+        match file {
+            TableFile::Loaded(table) => do_probe_table(pos, &info, table, table_type, wdl, result),
+            _ => {
+                *result = ProbeState::Fail;
+                0
+            }
+        }
+    }
+}
+
+fn table_info(white: &[usize], black: &[usize]) -> TableInfo {
+    let mut counts = [[0usize; 6]; 2];
+    counts[0][5] = 1;
+    counts[1][5] = 1;
+    for &t in white { counts[0][t] += 1 }
+    for &t in black { counts[1][t] += 1 }
+
+    let has_unique_pieces = (0..2).any(|c| (0..5).any(|t| counts[c][t] == 1));
+
+    // The leading color is the side with fewer pawns, it compresses better
+    let lead_white = counts[1][0] == 0 || (counts[0][0] != 0 && counts[1][0] >= counts[0][0]);
+
+    TableInfo {
+        key: counts_key(&counts[0], &counts[1]),
+        key2: counts_key(&counts[1], &counts[0]),
+        piece_count: white.len() + black.len() + 2,
+        has_pawns: counts[0][0] + counts[1][0] > 0,
+        has_unique_pieces,
+        pawn_count: if lead_white { [counts[0][0], counts[1][0]] } else { [counts[1][0], counts[0][0]] },
+    }
+}
+
+fn counts_key(white: &[usize; 6], black: &[usize; 6]) -> u64 {
+    let mut key = 0;
+    for t in 0..6 {
+        key |= (white[t] as u64) << (4 * t);
+        key |= (black[t] as u64) << (4 * (t + 6));
+    }
+    key
+}
+
+// Material signature of the position, with colors swapped if flip is set
+pub fn material_key(pos: &Position, flip: bool) -> u64 {
+    let white = if pos.state.turn { pos.player } else { pos.enemy };
+    let black = pos.all ^ white;
+    let tiers = [pos.pawns, pos.knights, pos.bishops, pos.rooks, pos.queens, pos.kings];
+
+    let mut counts = [[0usize; 6]; 2];
+    for t in 0..6 {
+        counts[0][t] = (tiers[t] & white).count_ones() as usize;
+        counts[1][t] = (tiers[t] & black).count_ones() as usize;
+    }
+
+    if flip { counts_key(&counts[1], &counts[0]) } else { counts_key(&counts[0], &counts[1]) }
+}
+
+fn is_capture(pos: &Position, m: Move) -> bool {
+    m.target() & pos.enemy != 0 || m.code() == 8
+}
+
+fn is_zeroing(pos: &Position, m: Move) -> bool {
+    is_capture(pos, m) || m.origin() & pos.pawns != 0
+}
+
+fn dtz_before_zeroing(wdl: i32) -> i32 {
+    match wdl {
+        WDL_WIN => 1,
+        WDL_CURSED_WIN => 101,
+        WDL_BLESSED_LOSS => -101,
+        WDL_LOSS => -1,
+        _ => 0,
+    }
+}
+
+fn piece_on(pos: &Position, sq: usize) -> u8 {
+    let bit = BITS[sq];
+    let white = if pos.state.turn { pos.player } else { pos.enemy };
+    let color = if bit & white != 0 { 0 } else { 8 };
+    pos.piece_on(sq) as u8 + 1 + color
+}
+
+fn off_a1h8(sq: usize) -> i32 {
+    (sq >> 3) as i32 - (sq & 7) as i32
+}
+
+fn squares_of(mut bb: u64) -> impl Iterator<Item = usize> {
+    std::iter::from_fn(move || {
+        if bb == 0 {
+            return None;
+        }
+        let sq = bb.leading_zeros() as usize;
+        bb &= !BITS[sq];
+        Some(sq)
+    })
+}
+
+fn do_probe_table(pos: &Position, info: &TableInfo, table: &LoadedTable, table_type: TableType, wdl: i32, result: &mut ProbeState) -> i32 {
+    let Some((stm, tb_file, idx)) = locate(pos, info, table, table_type) else {
+        *result = ProbeState::ChangeStm;
+        return 0;
+    };
+
+    let value = decompress_pairs(table, table.get(stm, tb_file), idx)
+        .and_then(|value| map_score(table, table_type, tb_file, value, wdl));
+    value.unwrap_or_else(|| {
+        *result = ProbeState::Fail;
+        0
+    })
+}
+
+// Where the table stores a position: the side to move and the leading pawn file it is decoded
+// with, and its index. None if a DTZ table only stores the other side to move.
+fn locate(pos: &Position, info: &TableInfo, table: &LoadedTable, table_type: TableType) -> Option<(usize, usize, u64)> {
+    let mut squares = [0usize; TB_PIECES];
+    let mut pieces = [0u8; TB_PIECES];
+    let mut size = 0;
+    let mut lead_pawns_cnt = 0;
+    let mut lead_pawns = 0;
+    let mut tb_file = 0;
+
+    // Symmetric tables only store white to move, and tables are always stored with
+    // white as the stronger side, otherwise colors are switched and squares flipped
+    let symmetric_black_to_move = info.key == info.key2 && !pos.state.turn;
+    let black_stronger = material_key(pos, false) != info.key;
+    let flip = symmetric_black_to_move || black_stronger;
+
+    let flip_color = if flip { 8 } else { 0 };
+    let flip_squares = if flip { 56 } else { 0 };
+    let stm = (flip as usize) ^ (!pos.state.turn as usize);
+
+    // Pawn tables are split by the file of the leading pawn, which is the pawn
+    // with the highest MAP_PAWNS value
+    if info.has_pawns {
+        let pc = table.get(0, 0).pieces[0] ^ flip_color;
+        let white = if pos.state.turn { pos.player } else { pos.enemy };
+        let color = if pc & 8 == 0 { white } else { pos.all ^ white };
+
+        lead_pawns = pos.pawns & color;
+        for sq in squares_of(lead_pawns) {
+            squares[size] = sq ^ flip_squares;
+            size += 1;
+        }
+        lead_pawns_cnt = size;
+
+        let mut lead = 0;
+        for i in 1..lead_pawns_cnt {
+            if MAP_PAWNS[squares[i]] > MAP_PAWNS[squares[lead]] {
+                lead = i;
+            }
+        }
+        squares.swap(0, lead);
+
+        tb_file = squares[0] & 7;
+        if tb_file > 3 {
+            tb_file = (squares[0] ^ 7) & 7;
+        }
+    }
+
+    // DTZ tables only store one side to move
+    if table_type == TableType::Dtz {
+        let flags = table.get(stm, tb_file).flags;
+        if (flags & FLAG_STM) as usize != stm && (info.key != info.key2 || info.has_pawns) {
+            return None;
+        }
+    }
+
+    for sq in squares_of(pos.all ^ lead_pawns) {
+        squares[size] = sq ^ flip_squares;
+        pieces[size] = piece_on(pos, sq) ^ flip_color;
+        size += 1;
+    }
+
+    let d = table.get(stm, tb_file);
+
+    // Reorder the pieces to match the sequence stored in the table
+    for i in lead_pawns_cnt..size {
+        for j in i..size {
+            if d.pieces[i] == pieces[j] {
+                pieces.swap(i, j);
+                squares.swap(i, j);
+                break;
+            }
+        }
+    }
+
+    // The leading piece is mapped to the left half of the board
+    if squares[0] & 7 > 3 {
+        for sq in squares[0..size].iter_mut() {
+            *sq ^= 7;
+        }
+    }
+
+    let mut idx: u64;
+    if info.has_pawns {
+        idx = LEAD_PAWN_IDX[lead_pawns_cnt][squares[0]];
+
+        squares[1..lead_pawns_cnt].sort_by_key(|&sq| MAP_PAWNS[sq]);
+
+        for i in 1..lead_pawns_cnt {
+            idx += BINOMIAL[i][MAP_PAWNS[squares[i]]];
+        }
+    } else {
+        // Without pawns the leading piece is also mapped below the fifth rank
+        if squares[0] >> 3 > 3 {
+            for sq in squares[0..size].iter_mut() {
+                *sq ^= 56;
+            }
+        }
+
+        // The first piece of the leading group not on the a1-h8 diagonal is mapped below it
+        for i in 0..d.group_len[0] {
+            let off = off_a1h8(squares[i]);
+            if off == 0 {
+                continue;
+            }
+            if off > 0 {
+                for sq in squares[i..size].iter_mut() {
+                    *sq = ((*sq >> 3) | (*sq << 3)) & 63;
+                }
+            }
+            break;
+        }
+
+        if info.has_unique_pieces {
+            let s0 = squares[0];
+            let s1 = squares[1];
+            let s2 = squares[2];
+            let adjust1 = (s1 > s0) as u64;
+            let adjust2 = (s2 > s0) as u64 + (s2 > s1) as u64;
+            let (r0, r1, r2) = ((s0 >> 3) as u64, (s1 >> 3) as u64, (s2 >> 3) as u64);
+
+            idx = if off_a1h8(s0) != 0 {
+                (MAP_A1D1D4[s0] as u64 * 63 + (s1 as u64 - adjust1)) * 62 + s2 as u64 - adjust2
+            } else if off_a1h8(s1) != 0 {
+                (6 * 63 + r0 * 28 + MAP_B1H1H7[s1] as u64) * 62 + s2 as u64 - adjust2
+            } else if off_a1h8(s2) != 0 {
+                6 * 63 * 62 + 4 * 28 * 62 + r0 * 7 * 28 + (r1 - adjust1) * 28 + MAP_B1H1H7[s2] as u64
+            } else {
+                6 * 63 * 62 + 4 * 28 * 62 + 4 * 7 * 28 + r0 * 7 * 6 + (r1 - adjust1) * 6 + (r2 - adjust2)
+            };
+        } else {
+            idx = MAP_KK[MAP_A1D1D4[squares[0]]][squares[1]];
+        }
+    }
+
+    // Encode the remaining groups, each one relative to the squares of the previous groups
+    idx *= d.group_idx[0];
+    let mut group_start = d.group_len[0];
+    let mut remaining_pawns = info.has_pawns && info.pawn_count[1] != 0;
+    let mut next = 1;
+
+    while d.group_len[next] != 0 {
+        let len = d.group_len[next];
+        squares[group_start..group_start + len].sort();
+
+        let mut n = 0;
+        for i in 0..len {
+            let sq = squares[group_start + i];
+            let adjust = squares[0..group_start].iter().filter(|&&s| sq > s).count();
+            let pawn_offset = if remaining_pawns { 8 } else { 0 };
+            n += BINOMIAL[i + 1][sq - adjust - pawn_offset];
+        }
+
+        remaining_pawns = false;
+        idx += n * d.group_idx[next];
+        group_start += len;
+        next += 1;
+    }
+
+    Some((stm, tb_file, idx))
+}
+
+fn map_score(table: &LoadedTable, table_type: TableType, file: usize, value: i32, wdl: i32) -> Option<i32> {
+    if table_type == TableType::Wdl {
+        return Some(value - 2);
+    }
+
+    const WDL_MAP: [usize; 5] = [1, 3, 0, 2, 0];
+    let d = table.get(0, file);
+    let mut value = value;
+
+    if d.flags & FLAG_MAPPED != 0 {
+        let i = d.map_idx[WDL_MAP[(wdl + 2) as usize]] + value as usize;
+        value = if d.flags & FLAG_WIDE != 0 {
+            table.u16_le(table.map + 2 * i)? as i32
+        } else {
+            table.u8_at(table.map + i)? as i32
+        };
+    }
+
+    // Values are stored in moves unless flagged otherwise, convert them to plies
+    if (wdl == WDL_WIN && d.flags & FLAG_WIN_PLIES == 0)
+        || (wdl == WDL_LOSS && d.flags & FLAG_LOSS_PLIES == 0)
+        || wdl == WDL_CURSED_WIN
+        || wdl == WDL_BLESSED_LOSS {
+        value *= 2;
+    }
+
+    Some(value + 1)
+}
+
+// None if the file is damaged: the sparse index or the block lengths lead outside the blocks,
+// or the data holds a code that is not in the table
+fn decompress_pairs(table: &LoadedTable, d: &PairsData, idx: u64) -> Option<i32> {
+    if d.flags & FLAG_SINGLE_VALUE != 0 {
+        return Some(d.min_sym_len as i32);
+    }
+
+    // Locate the block holding idx through the sparse index
+    let k = (idx / d.span as u64) as usize;
+    let mut block = table.u32_le(d.sparse_index + 6 * k)? as usize;
+    let mut offset = table.u16_le(d.sparse_index + 6 * k + 4)? as i64;
+
+    offset += (idx % d.span as u64) as i64 - (d.span / 2) as i64;
+
+    while offset < 0 {
+        block = block.checked_sub(1)?;
+        offset += table.u16_le(d.block_length + 2 * block)? as i64 + 1;
+    }
+
+    while offset > table.u16_le(d.block_length + 2 * block)? as i64 {
+        offset -= table.u16_le(d.block_length + 2 * block)? as i64 + 1;
+        block += 1;
+    }
+
+    if block >= d.num_blocks {
+        return None;
+    }
+
+    // Read canonical Huffman symbols until the one covering our offset
+    let mut ptr = d.data + block * d.sizeof_block;
+    let mut buf64 = table.u64_be(ptr)?;
+    ptr += 8;
+    let mut buf64_size: usize = 64;
+    let mut sym;
+
+    loop {
+        let mut len = 0;
+        while buf64 < *d.base64.get(len)? {
+            len += 1;
+        }
+
+        let shift = 64usize.checked_sub(len + d.min_sym_len)?;
+        sym = (buf64 - d.base64[len]).checked_shr(shift as u32)? as usize;
+        sym += table.u16_le(d.lowest_sym + 2 * len)? as usize;
+
+        let sym_len = *d.symlen.get(sym)? as i64;
+        if offset < sym_len + 1 {
+            break;
+        }
+
+        offset -= sym_len + 1;
+        len += d.min_sym_len;
+        buf64 = buf64.checked_shl(len as u32)?;
+        buf64_size = buf64_size.checked_sub(len)?;
+
+        if buf64_size <= 32 {
+            buf64_size += 32;
+            buf64 |= (table.u32_be(ptr)? as u64) << (64 - buf64_size);
+            ptr += 4;
+        }
+    }
+
+    // Expand the symbol through the pair tree until reaching a single value. Parsing walked
+    // the whole tree, so every symbol below a valid one is valid too.
+    while d.symlen[sym] != 0 {
+        let left = btree_left(table, d, sym);
+        if offset < d.symlen[left] as i64 + 1 {
+            sym = left;
+        } else {
+            offset -= d.symlen[left] as i64 + 1;
+            sym = btree_right(table, d, sym);
+        }
+    }
+
+    Some(btree_left(table, d, sym) as i32)
+}
+
+fn btree_left(table: &LoadedTable, d: &PairsData, sym: usize) -> usize {
+    let p = d.btree + 3 * sym;
+    (((table.bytes[p + 1] & 0xF) as usize) << 8) | table.bytes[p] as usize
+}
+
+fn btree_right(table: &LoadedTable, d: &PairsData, sym: usize) -> usize {
+    let p = d.btree + 3 * sym;
+    ((table.bytes[p + 2] as usize) << 4) | (table.bytes[p + 1] >> 4) as usize
+}
+
+fn load_table(path: &str, info: &TableInfo, table_type: TableType) -> Option<LoadedTable> {
+    let bytes = fs::read(path).ok()?;
+    let magic = if table_type == TableType::Wdl { WDL_MAGIC } else { DTZ_MAGIC };
+    if bytes.len() < 5 || bytes[0..4] != magic {
+        return None;
+    }
+
+    let sides = if table_type == TableType::Wdl && info.key != info.key2 { 2 } else { 1 };
+    let files = if info.has_pawns { 4 } else { 1 };
+    let mut table = LoadedTable { bytes, sides, pairs: vec![PairsData::default(); files * sides], map: 0 };
+
+    // Parsing trusts the header and a damaged one can make it panic, which is caught here.
+    // Probing reads through the checked accessors and fails the probe instead.
+    let parsed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        parse_table(&mut table, info, table_type)
+    }));
+
+    match parsed {
+        Ok(Some(())) => Some(table),
+        _ => None,
+    }
+}
+
+#[allow(clippy::needless_range_loop)]
+fn parse_table(table: &mut LoadedTable, info: &TableInfo, table_type: TableType) -> Option<()> {
+    let sides = table.sides;
+    let files = table.pairs.len() / sides;
+    let pp = info.has_pawns && info.pawn_count[1] != 0;
+
+    // Skip the magic and the flags byte
+    let mut data = 5;
+
+    for f in 0..files {
+        let order = [
+            [(table.bytes[data] & 0xF) as usize, if pp { (table.bytes[data + 1] & 0xF) as usize } else { 0xF }],
+            [(table.bytes[data] >> 4) as usize, if pp { (table.bytes[data + 1] >> 4) as usize } else { 0xF }],
+        ];
+        data += 1 + pp as usize;
+
+        for k in 0..info.piece_count {
+            for i in 0..sides {
+                let byte = table.bytes[data];
+                table.pairs[f * sides + i].pieces[k] = if i == 1 { byte >> 4 } else { byte & 0xF };
+            }
+            data += 1;
+        }
+
+        for i in 0..sides {
+            set_groups(info, &mut table.pairs[f * sides + i], order[i], f);
+        }
+    }
+
+    data += data & 1;
+
+    for f in 0..files {
+        for i in 0..sides {
+            data = set_sizes(table, f * sides + i, data)?;
+        }
+    }
+
+    if table_type == TableType::Dtz {
+        table.map = data;
+        for f in 0..files {
+            let flags = table.pairs[f].flags;
+            if flags & FLAG_MAPPED == 0 {
+                continue;
+            }
+            if flags & FLAG_WIDE != 0 {
+                data += data & 1;
+                for i in 0..4 {
+                    table.pairs[f].map_idx[i] = (data - table.map) / 2 + 1;
+                    data += 2 * table.u16_le(data)? as usize + 2;
+                }
+            } else {
+                for i in 0..4 {
+                    table.pairs[f].map_idx[i] = data - table.map + 1;
+                    data += table.bytes[data] as usize + 1;
+                }
+            }
+        }
+        data += data & 1;
+    }
+
+    for p in 0..files * sides {
+        table.pairs[p].sparse_index = data;
+        data += table.pairs[p].sparse_index_size * 6;
+    }
+
+    for p in 0..files * sides {
+        table.pairs[p].block_length = data;
+        data += table.pairs[p].block_length_size * 2;
+    }
+
+    for p in 0..files * sides {
+        data = (data + 0x3F) & !0x3F;
+        table.pairs[p].data = data;
+        data += table.pairs[p].num_blocks * table.pairs[p].sizeof_block;
+    }
+
+    (data <= table.bytes.len()).then_some(())
+}
+
+// Splits the pieces into groups and computes the index multiplier of each group
+fn set_groups(info: &TableInfo, d: &mut PairsData, order: [usize; 2], file: usize) {
+    let mut n = 0;
+    let mut first_len: i32 = if info.has_pawns { 0 } else if info.has_unique_pieces { 3 } else { 2 };
+    d.group_len[n] = 1;
+
+    for i in 1..info.piece_count {
+        first_len -= 1;
+        if first_len > 0 || d.pieces[i] == d.pieces[i - 1] {
+            d.group_len[n] += 1;
+        } else {
+            n += 1;
+            d.group_len[n] = 1;
+        }
+    }
+
+    n += 1;
+    d.group_len[n] = 0;
+
+    let pp = info.has_pawns && info.pawn_count[1] != 0;
+    let mut next = if pp { 2 } else { 1 };
+    let mut free_squares = 64 - d.group_len[0] - if pp { d.group_len[1] } else { 0 };
+    let mut idx: u64 = 1;
+
+    let mut k = 0;
+    while next < n || k == order[0] || k == order[1] {
+        if k == order[0] {
+            d.group_idx[0] = idx;
+            idx *= if info.has_pawns {
+                LEAD_PAWNS_SIZE[d.group_len[0]][file]
+            } else if info.has_unique_pieces {
+                31332
+            } else {
+                462
+            };
+        } else if k == order[1] {
+            d.group_idx[1] = idx;
+            idx *= BINOMIAL[d.group_len[1]][48 - d.group_len[0]];
+        } else {
+            d.group_idx[next] = idx;
+            idx *= BINOMIAL[d.group_len[next]][free_squares];
+            free_squares -= d.group_len[next];
+            next += 1;
+        }
+        k += 1;
+    }
+
+    d.group_idx[n] = idx;
+}
+
+// Reads the block and symbol information of one PairsData, returns the offset after it
+fn set_sizes(table: &mut LoadedTable, p: usize, mut data: usize) -> Option<usize> {
+    let flags = table.bytes[data];
+    data += 1;
+    table.pairs[p].flags = flags;
+
+    if flags & FLAG_SINGLE_VALUE != 0 {
+        let d = &mut table.pairs[p];
+        d.num_blocks = 0;
+        d.span = 0;
+        d.block_length_size = 0;
+        d.sparse_index_size = 0;
+        d.min_sym_len = table.bytes[data] as usize;
+        return Some(data + 1);
+    }
+
+    let groups = table.pairs[p].group_len.iter().position(|&l| l == 0).unwrap();
+    let tb_size = table.pairs[p].group_idx[groups];
+
+    let sizeof_block = 1usize << table.bytes[data];
+    let span = 1usize << table.bytes[data + 1];
+    let padding = table.bytes[data + 2] as usize;
+    let num_blocks = table.u32_le(data + 3)? as usize;
+    let max_sym_len = table.bytes[data + 7] as usize;
+    let min_sym_len = table.bytes[data + 8] as usize;
+    data += 9;
+    let lowest_sym = data;
+
+    // Longer symbols have lower values, base64[i] is the lowest symbol of length
+    // i + min_sym_len, left aligned to 64 bits
+    let mut base64 = vec![0u64; max_sym_len - min_sym_len + 1];
+    for i in (0..base64.len() - 1).rev() {
+        let l0 = table.u16_le(lowest_sym + 2 * i)? as u64;
+        let l1 = table.u16_le(lowest_sym + 2 * (i + 1))? as u64;
+        base64[i] = (base64[i + 1] + l0 - l1) / 2;
+    }
+    for (i, base) in base64.iter_mut().enumerate() {
+        *base <<= 64 - i - min_sym_len;
+    }
+
+    data += base64.len() * 2;
+    let symlen_size = table.u16_le(data)? as usize;
+    data += 2;
+    let btree = data;
+
+    let d = &mut table.pairs[p];
+    d.sizeof_block = sizeof_block;
+    d.span = span;
+    d.sparse_index_size = tb_size.div_ceil(span as u64) as usize;
+    d.num_blocks = num_blocks;
+    d.block_length_size = num_blocks + padding;
+    d.max_sym_len = max_sym_len;
+    d.min_sym_len = min_sym_len;
+    d.lowest_sym = lowest_sym;
+    d.base64 = base64;
+    d.btree = btree;
+    d.symlen = vec![0; symlen_size];
+
+    // Symbols are built by recursive pairing, compute how many values each one expands to
+    let mut visited = vec![false; symlen_size];
+    for sym in 0..symlen_size {
+        if !visited[sym] {
+            let len = set_symlen(table, p, sym, &mut visited);
+            table.pairs[p].symlen[sym] = len;
+        }
+    }
+
+    Some(btree + symlen_size * 3 + (symlen_size & 1))
+}
+
+fn set_symlen(table: &mut LoadedTable, p: usize, sym: usize, visited: &mut Vec<bool>) -> u8 {
+    visited[sym] = true;
+    let right = btree_right(table, &table.pairs[p], sym);
+
+    if right == 0xFFF {
+        return 0;
+    }
+
+    let left = btree_left(table, &table.pairs[p], sym);
+
+    if !visited[left] {
+        let len = set_symlen(table, p, left, visited);
+        table.pairs[p].symlen[left] = len;
+    }
+
+    if !visited[right] {
+        let len = set_symlen(table, p, right, visited);
+        table.pairs[p].symlen[right] = len;
+    }
+
+    table.pairs[p].symlen[left].wrapping_add(table.pairs[p].symlen[right]).wrapping_add(1)
+}
+
+// Encodes squares below the a1-h8 diagonal as 0..27
+const fn get_map_b1h1h7() -> [usize; 64] {
+    let mut arr: [usize; 64] = [0; 64];
+    let mut code = 0;
+    let mut sq = 0;
+
+    loop {
+        if (sq >> 3) < (sq & 7) {
+            arr[sq] = code;
+            code += 1;
+        }
+
+        sq += 1;
+        if sq == 64 { break }
+    }
+    arr
+}
+
+// Encodes the a1-d1-d4 triangle as 0..9, with the diagonal squares last
+const fn get_map_a1d1d4() -> [usize; 64] {
+    let mut arr: [usize; 64] = [0; 64];
+    let mut diagonal: [usize; 4] = [0; 4];
+    let mut diagonal_cnt = 0;
+    let mut code = 0;
+    let mut sq = 0;
+
+    loop {
+        if (sq >> 3) < (sq & 7) && (sq & 7) <= 3 {
+            arr[sq] = code;
+            code += 1;
+        } else if (sq >> 3) == (sq & 7) && (sq & 7) <= 3 {
+            diagonal[diagonal_cnt] = sq;
+            diagonal_cnt += 1;
+        }
+
+        sq += 1;
+        if sq == 28 { break }
+    }
+
+    let mut i = 0;
+    loop {
+        arr[diagonal[i]] = code;
+        code += 1;
+
+        i += 1;
+        if i == 4 { break }
+    }
+    arr
+}
+
+// Encodes the 462 legal placements of two kings with the first in the a1-d1-d4 triangle
+const fn get_map_kk() -> [[u64; 64]; 10] {
+    let mut arr: [[u64; 64]; 10] = [[0; 64]; 10];
+    let mut both_on_diagonal: [(usize, usize); 64] = [(0, 0); 64];
+    let mut both_cnt = 0;
+    let mut code = 0;
+
+    let mut idx = 0;
+    loop {
+        let mut s1 = 0;
+        loop {
+            if MAP_A1D1D4[s1] == idx && (idx != 0 || s1 == 1) {
+                let off1 = (s1 >> 3) as i32 - (s1 & 7) as i32;
+                let mut s2 = 0;
+                loop {
+                    let off2 = (s2 >> 3) as i32 - (s2 & 7) as i32;
+                    if (LUT_KING[s1] | BITS[s1]) & BITS[s2] != 0 {
+                        // Illegal position
+                    } else if off1 == 0 && off2 > 0 {
+                        // First on diagonal, second above it
+                    } else if off1 == 0 && off2 == 0 {
+                        both_on_diagonal[both_cnt] = (idx, s2);
+                        both_cnt += 1;
+                    } else {
+                        arr[idx][s2] = code;
+                        code += 1;
+                    }
+
+                    s2 += 1;
+                    if s2 == 64 { break }
+                }
+            }
+
+            s1 += 1;
+            if s1 == 28 { break }
+        }
+
+        idx += 1;
+        if idx == 10 { break }
+    }
+
+    let mut i = 0;
+    loop {
+        if i == both_cnt { break }
+        arr[both_on_diagonal[i].0][both_on_diagonal[i].1] = code;
+        code += 1;
+        i += 1;
+    }
+    arr
+}
+
+// BINOMIAL[k][n] is the number of ways to choose k elements from n
+const fn get_binomial() -> [[u64; 64]; 7] {
+    let mut arr: [[u64; 64]; 7] = [[0; 64]; 7];
+    arr[0][0] = 1;
+
+    let mut n = 1;
+    loop {
+        let mut k = 0;
+        loop {
+            if k > n || k == 7 { break }
+            let a = if k > 0 { arr[k - 1][n - 1] } else { 0 };
+            let b = if k < n { arr[k][n - 1] } else { 0 };
+            arr[k][n] = a + b;
+            k += 1;
+        }
+
+        n += 1;
+        if n == 64 { break }
+    }
+    arr
+}
+
+// Encodes a2-h7 as 0..47, the pawn with the highest value is the leading pawn
+const fn get_map_pawns() -> [usize; 64] {
+    let mut arr: [usize; 64] = [0; 64];
+    let mut available = 47;
+
+    let mut f = 0;
+    loop {
+        let mut r = 1;
+        loop {
+            let sq = f + 8 * r;
+            arr[sq] = available;
+            available -= 1;
+            arr[sq ^ 7] = available;
+            available = available.saturating_sub(1);
+
+            r += 1;
+            if r == 7 { break }
+        }
+
+        f += 1;
+        if f == 4 { break }
+    }
+    arr
+}
+
+const fn get_lead_pawn_idx() -> [[u64; 64]; 6] {
+    let mut arr: [[u64; 64]; 6] = [[0; 64]; 6];
+
+    let mut cnt = 1;
+    loop {
+        let mut f = 0;
+        loop {
+            let mut idx = 0;
+            let mut r = 1;
+            loop {
+                let sq = f + 8 * r;
+                arr[cnt][sq] = idx;
+                idx += BINOMIAL[cnt - 1][MAP_PAWNS[sq]];
+
+                r += 1;
+                if r == 7 { break }
+            }
+
+            f += 1;
+            if f == 4 { break }
+        }
+
+        cnt += 1;
+        if cnt == 6 { break }
+    }
+    arr
+}
+
+const fn get_lead_pawns_size() -> [[u64; 4]; 6] {
+    let mut arr: [[u64; 4]; 6] = [[0; 4]; 6];
+
+    let mut cnt = 1;
+    loop {
+        let mut f = 0;
+        loop {
+            let mut idx = 0;
+            let mut r = 1;
+            loop {
+                idx += BINOMIAL[cnt - 1][MAP_PAWNS[f + 8 * r]];
+
+                r += 1;
+                if r == 7 { break }
+            }
+            arr[cnt][f] = idx;
+
+            f += 1;
+            if f == 4 { break }
+        }
+
+        cnt += 1;
+        if cnt == 6 { break }
+    }
+    arr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Reverse;
+    use std::collections::{BinaryHeap, VecDeque};
+    use std::path::Path;
+    use std::sync::OnceLock;
+
+    // The tables are generated by retrograde analysis and written in the Syzygy format,
+    // values are compressed with a few rounds of pairing and a canonical Huffman code
+    const BLOCK_BITS: u8 = 6;
+    const SPAN_BITS: u8 = 7;
+    const PAIR_ROUNDS: usize = 32;
+    const MAX_SYMBOLS: usize = 4000;
+
+    const WHITE_KING: u8 = 6;
+    const BLACK_KING: u8 = 14;
+
+    // Square names use a1 = 0, like the indices of the decoder
+    fn fen(pieces: &[(u8, usize)], white: bool) -> String {
+        let mut board = [None; 64];
+        for &(piece, sq) in pieces {
+            let c = PIECE_CHARS[(piece & 7) as usize - 1];
+            board[sq] = Some(if piece & 8 == 0 { c } else { c.to_ascii_lowercase() });
+        }
+
+        let mut fen = String::new();
+        for rank in (0..8).rev() {
+            let mut empty = 0;
+            for file in 0..8 {
+                match board[8 * rank + file] {
+                    Some(c) => {
+                        if empty > 0 {
+                            fen.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        fen.push(c);
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                fen.push_str(&empty.to_string());
+            }
+            if rank > 0 {
+                fen.push('/');
+            }
+        }
+
+        fen.push_str(if white { " w - - 0 1" } else { " b - - 0 1" });
+        fen
+    }
+
+    fn tb_size(d: &PairsData) -> usize {
+        let groups = d.group_len.iter().position(|&l| l == 0).unwrap();
+        d.group_idx[groups] as usize
+    }
+
+    // The in-memory layout of a table, enough to locate positions before the file exists
+    fn layout(info: &TableInfo, pieces: &[u8], table_type: TableType, flags: u8) -> LoadedTable {
+        let sides = if table_type == TableType::Wdl && info.key != info.key2 { 2 } else { 1 };
+        let files = if info.has_pawns { 4 } else { 1 };
+        let mut table = LoadedTable { bytes: Vec::new(), sides, pairs: vec![PairsData::default(); files * sides], map: 0 };
+
+        for (f, pair) in table.pairs.chunks_mut(sides).enumerate() {
+            for d in pair.iter_mut() {
+                d.pieces[0..pieces.len()].copy_from_slice(pieces);
+                d.flags = flags;
+                set_groups(info, d, [0, 0xF], f);
+            }
+        }
+        table
+    }
+
+    struct EncodedPairs {
+        header: Vec<u8>,
+        sparse_index: Vec<u8>,
+        block_length: Vec<u8>,
+        data: Vec<u8>,
+    }
+
+    // Replaces the most frequent pairs of adjacent symbols by new symbols, the tree holds
+    // (left, right) for pairs and (value, 0xFFF) for single values
+    fn pair_symbols(seq: &mut Vec<u16>, tree: &mut Vec<(u16, u16)>, lens: &mut Vec<usize>) {
+        for _ in 0..PAIR_ROUNDS {
+            if tree.len() >= MAX_SYMBOLS {
+                break;
+            }
+
+            let mut counts: HashMap<(u16, u16), usize> = HashMap::new();
+            for w in seq.windows(2) {
+                if lens[w[0] as usize] + lens[w[1] as usize] <= 256 {
+                    *counts.entry((w[0], w[1])).or_default() += 1;
+                }
+            }
+
+            let Some((&pair, &count)) = counts.iter().max_by_key(|&(&pair, &count)| (count, Reverse(pair))) else {
+                break;
+            };
+            if count < 16 {
+                break;
+            }
+
+            let sym = tree.len() as u16;
+            tree.push(pair);
+            lens.push(lens[pair.0 as usize] + lens[pair.1 as usize]);
+
+            let mut paired = Vec::with_capacity(seq.len());
+            let mut i = 0;
+            while i < seq.len() {
+                if i + 1 < seq.len() && (seq[i], seq[i + 1]) == pair {
+                    paired.push(sym);
+                    i += 2;
+                } else {
+                    paired.push(seq[i]);
+                    i += 1;
+                }
+            }
+            *seq = paired;
+        }
+    }
+
+    // Huffman code lengths, zero for symbols that do not occur
+    fn code_lengths(freq: &[usize]) -> Vec<usize> {
+        let mut used: Vec<usize> = (0..freq.len()).filter(|&s| freq[s] > 0).collect();
+        if used.len() == 1 {
+            used.push(if used[0] == 0 { 1 } else { 0 });
+        }
+
+        let mut parent = vec![usize::MAX; freq.len()];
+        let mut heap: BinaryHeap<Reverse<(usize, usize)>> = used.iter().map(|&s| Reverse((freq[s], s))).collect();
+        while heap.len() > 1 {
+            let Reverse((f1, a)) = heap.pop().unwrap();
+            let Reverse((f2, b)) = heap.pop().unwrap();
+            let node = parent.len();
+            parent.push(usize::MAX);
+            parent[a] = node;
+            parent[b] = node;
+            heap.push(Reverse((f1 + f2, node)));
+        }
+
+        let mut lengths = vec![0; freq.len()];
+        for &s in used.iter() {
+            let mut node = s;
+            while parent[node] != usize::MAX {
+                node = parent[node];
+                lengths[s] += 1;
+            }
+        }
+        lengths
+    }
+
+    fn encode_pairs(values: &[u16], flags: u8) -> EncodedPairs {
+        if values.iter().all(|&v| v == values[0]) {
+            return EncodedPairs {
+                header: vec![flags | FLAG_SINGLE_VALUE, values[0] as u8],
+                sparse_index: Vec::new(),
+                block_length: Vec::new(),
+                data: Vec::new(),
+            };
+        }
+
+        let max_value = *values.iter().max().unwrap();
+        let mut tree: Vec<(u16, u16)> = (0..=max_value).map(|v| (v, 0xFFF)).collect();
+        let mut lens = vec![1; tree.len()];
+        let mut seq = values.to_vec();
+        pair_symbols(&mut seq, &mut tree, &mut lens);
+
+        let mut freq = vec![0; tree.len()];
+        for &s in seq.iter() {
+            freq[s as usize] += 1;
+        }
+        let lengths = code_lengths(&freq);
+
+        // Symbols are numbered from the longest code down, unused ones come last
+        let mut order: Vec<usize> = (0..tree.len()).collect();
+        order.sort_by_key(|&s| (lengths[s] == 0, Reverse(lengths[s]), s));
+        let mut number = vec![0; tree.len()];
+        for (n, &s) in order.iter().enumerate() {
+            number[s] = n;
+        }
+
+        let min_len = (0..tree.len()).filter(|&s| lengths[s] > 0).map(|s| lengths[s]).min().unwrap();
+        let max_len = *lengths.iter().max().unwrap();
+        let mut lowest_sym = vec![0usize; max_len - min_len + 1];
+        for (i, lowest) in lowest_sym.iter_mut().enumerate() {
+            *lowest = lengths.iter().filter(|&&l| l > min_len + i).count();
+        }
+
+        let mut base = vec![0u64; max_len + 2];
+        for l in (min_len..max_len).rev() {
+            let count = lengths.iter().filter(|&&c| c == l + 1).count() as u64;
+            assert_eq!((base[l + 1] + count) % 2, 0);
+            base[l] = (base[l + 1] + count) / 2;
+        }
+        let code = |s: usize| base[lengths[s]] + (number[s] - lowest_sym[lengths[s] - min_len]) as u64;
+
+        // Fill the blocks with whole symbols, starting a new block when one does not fit
+        let sizeof_block = 1usize << BLOCK_BITS;
+        let mut data = Vec::new();
+        let mut starts = Vec::new();
+        let mut block_values = Vec::new();
+        let mut block = vec![0u8; sizeof_block];
+        let mut bits = 0;
+        let mut count = 0;
+        let mut start = 0;
+
+        for &s in seq.iter() {
+            let s = s as usize;
+            if bits + lengths[s] > 8 * sizeof_block || count + lens[s] > 60000 {
+                data.extend_from_slice(&block);
+                starts.push(start);
+                block_values.push(count);
+                block = vec![0u8; sizeof_block];
+                start += count;
+                bits = 0;
+                count = 0;
+            }
+
+            let c = code(s);
+            for b in (0..lengths[s]).rev() {
+                if c >> b & 1 != 0 {
+                    block[bits / 8] |= 0x80 >> (bits % 8);
+                }
+                bits += 1;
+            }
+            count += lens[s];
+        }
+        data.extend_from_slice(&block);
+        starts.push(start);
+        block_values.push(count);
+
+        let span = 1usize << SPAN_BITS;
+        let mut sparse_index = Vec::new();
+        for k in 0..values.len().div_ceil(span) {
+            let p = k * span + span / 2;
+            let b = starts.partition_point(|&s| s <= p) - 1;
+            let offset = p - starts[b];
+            assert!(offset <= u16::MAX as usize);
+            sparse_index.extend_from_slice(&(b as u32).to_le_bytes());
+            sparse_index.extend_from_slice(&(offset as u16).to_le_bytes());
+        }
+
+        let block_length = block_values.iter().flat_map(|&n| ((n - 1) as u16).to_le_bytes()).collect();
+
+        let mut header = vec![flags, BLOCK_BITS, SPAN_BITS, 0];
+        header.extend_from_slice(&(starts.len() as u32).to_le_bytes());
+        header.push(max_len as u8);
+        header.push(min_len as u8);
+        for &lowest in lowest_sym.iter() {
+            header.extend_from_slice(&(lowest as u16).to_le_bytes());
+        }
+        header.extend_from_slice(&(tree.len() as u16).to_le_bytes());
+        for &s in order.iter() {
+            let (left, right) = tree[s];
+            let (left, right) = if right == 0xFFF {
+                (left as usize, 0xFFF)
+            } else {
+                (number[left as usize], number[right as usize])
+            };
+            header.extend_from_slice(&[left as u8, ((left >> 8) | (right << 4)) as u8, (right >> 4) as u8]);
+        }
+        if tree.len() & 1 != 0 {
+            header.push(0);
+        }
+
+        EncodedPairs { header, sparse_index, block_length, data }
+    }
+
+    fn write_table(path: &Path, info: &TableInfo, pieces: &[u8], table_type: TableType, flags: u8, values: &[Vec<u16>]) {
+        let table = layout(info, pieces, table_type, flags);
+        let files = table.pairs.len() / table.sides;
+
+        let mut bytes = if table_type == TableType::Wdl { WDL_MAGIC } else { DTZ_MAGIC }.to_vec();
+        bytes.push((table.sides == 2) as u8 | (info.has_pawns as u8) << 1);
+        for _ in 0..files {
+            bytes.push(0);
+            bytes.extend(pieces.iter().map(|&p| p | p << 4));
+        }
+        bytes.resize(bytes.len() + (bytes.len() & 1), 0);
+
+        let encoded: Vec<EncodedPairs> = values.iter().map(|v| encode_pairs(v, flags)).collect();
+        for e in encoded.iter() {
+            bytes.extend_from_slice(&e.header);
+        }
+        if table_type == TableType::Dtz {
+            bytes.resize(bytes.len() + (bytes.len() & 1), 0);
+        }
+        for e in encoded.iter() {
+            bytes.extend_from_slice(&e.sparse_index);
+        }
+        for e in encoded.iter() {
+            bytes.extend_from_slice(&e.block_length);
+        }
+        for e in encoded.iter() {
+            bytes.resize((bytes.len() + 0x3F) & !0x3F, 0);
+            bytes.extend_from_slice(&e.data);
+        }
+
+        // The decoder reads ahead of the last symbol it needs
+        bytes.resize(bytes.len() + 64, 0);
+        fs::write(path, bytes).unwrap();
+    }
+
+    enum Child {
+        Node(usize, bool),
+        Fixed(i32),
+    }
+
+    // Every position of a material up to symmetry, with white as the stronger side.
+    // WDL values are from the side to move's point of view, DTZ values in plies.
+    struct Solved {
+        info: TableInfo,
+        pieces: Vec<u8>,
+        positions: Vec<Position>,
+        slots: Vec<(usize, usize, u64)>,
+        wdl: Vec<i32>,
+        dtz: Vec<i32>,
+    }
+
+    fn solve(tablebases: &mut Tablebases, white: &[usize], black: &[usize], pieces: &[u8]) -> Solved {
+        let info = table_info(white, black);
+        let table = layout(&info, pieces, TableType::Wdl, 0);
+
+        // The first piece covers every square up to symmetry: the a1-d1-d4 triangle
+        // without pawns, files a to d for a pawn
+        let first: Vec<usize> = if info.has_pawns {
+            (8..56).filter(|sq| sq & 7 < 4).collect()
+        } else {
+            (0..32).filter(|sq| sq & 7 < 4 && sq >> 3 <= sq & 7).collect()
+        };
+
+        let mut positions = Vec::new();
+        let mut slots = Vec::new();
+        let mut index: HashMap<(usize, usize, u64), usize> = HashMap::new();
+
+        for &s0 in first.iter() {
+            for s1 in 0..64 {
+                for s2 in 0..64 {
+                    let squares = [s0, s1, s2];
+                    if s1 == s0 || s2 == s0 || s2 == s1 || squares.iter().zip(pieces).any(|(&sq, &p)| p & 7 == 1 && !(8..56).contains(&sq)) {
+                        continue;
+                    }
+                    let kings: Vec<usize> = squares.iter().zip(pieces).filter(|(_, &p)| p & 7 == 6).map(|(&sq, _)| sq).collect();
+                    if LUT_KING[kings[0]] & BITS[kings[1]] != 0 {
+                        continue;
+                    }
+
+                    let placement: Vec<(u8, usize)> = pieces.iter().copied().zip(squares).collect();
+                    let both = [Position::build_from_fen(&fen(&placement, true)), Position::build_from_fen(&fen(&placement, false))];
+                    for i in 0..2 {
+                        // The side not to move can not be in check
+                        if both[1 - i].state.check {
+                            continue;
+                        }
+                        let slot = locate(&both[i], &info, &table, TableType::Wdl).unwrap();
+                        index.entry(slot).or_insert_with(|| {
+                            positions.push(both[i]);
+                            slots.push(slot);
+                            positions.len() - 1
+                        });
+                    }
+                }
+            }
+        }
+
+        let n = positions.len();
+        let mut children: Vec<Vec<Child>> = Vec::with_capacity(n);
+        for pos in positions.iter() {
+            let moves = pos.legal_moves();
+            let mut node_children = Vec::with_capacity(moves.len());
+            for m in moves {
+                let child = pos.make_move(m);
+                if material_key(&child, false) == info.key {
+                    let slot = locate(&child, &info, &table, TableType::Wdl).unwrap();
+                    node_children.push(Child::Node(index[&slot], is_zeroing(pos, m)));
+                } else {
+                    node_children.push(Child::Fixed(-tablebases.probe_wdl(&child).unwrap()));
+                }
+            }
+            children.push(node_children);
+        }
+
+        let mut parents: Vec<Vec<(usize, bool)>> = vec![Vec::new(); n];
+        for (p, node_children) in children.iter().enumerate() {
+            for c in node_children.iter() {
+                if let Child::Node(c, zeroing) = *c {
+                    parents[c].push((p, zeroing));
+                }
+            }
+        }
+
+        // Win/draw/loss: a loss once every move leads to a win for the opponent
+        let mut wdl: Vec<Option<i32>> = vec![None; n];
+        let mut pending = vec![0; n];
+        let mut best_fixed = vec![WDL_LOSS; n];
+        let mut queue = VecDeque::new();
+
+        for p in 0..n {
+            for c in children[p].iter() {
+                match *c {
+                    Child::Node(..) => pending[p] += 1,
+                    Child::Fixed(v) => best_fixed[p] = best_fixed[p].max(v),
+                }
+            }
+
+            if children[p].is_empty() {
+                wdl[p] = Some(if positions[p].state.check { WDL_LOSS } else { WDL_DRAW });
+            } else if best_fixed[p] == WDL_WIN {
+                wdl[p] = Some(WDL_WIN);
+            } else if pending[p] == 0 {
+                wdl[p] = Some(best_fixed[p]);
+            }
+            if wdl[p].is_some_and(|v| v != WDL_DRAW) {
+                queue.push_back(p);
+            }
+        }
+
+        while let Some(c) = queue.pop_front() {
+            for &(p, _) in parents[c].iter() {
+                if wdl[p].is_some() {
+                    continue;
+                }
+                if wdl[c] == Some(WDL_LOSS) {
+                    wdl[p] = Some(WDL_WIN);
+                    queue.push_back(p);
+                } else {
+                    pending[p] -= 1;
+                    if pending[p] == 0 {
+                        wdl[p] = Some(best_fixed[p]);
+                        if best_fixed[p] == WDL_LOSS {
+                            queue.push_back(p);
+                        }
+                    }
+                }
+            }
+        }
+        let wdl: Vec<i32> = wdl.iter().map(|v| v.unwrap_or(WDL_DRAW)).collect();
+
+        // DTZ by increasing distance, zeroing moves and mates count as one ply
+        let mut dtz = vec![0; n];
+        let mut pending = vec![0; n];
+        let mut queue = VecDeque::new();
+
+        for p in 0..n {
+            if wdl[p] == WDL_WIN {
+                let zeroing_win = children[p].iter().any(|c| match *c {
+                    Child::Node(c, zeroing) => wdl[c] == WDL_LOSS && (zeroing || children[c].is_empty()),
+                    Child::Fixed(v) => v == WDL_WIN,
+                });
+                if zeroing_win {
+                    dtz[p] = 1;
+                    queue.push_back(p);
+                }
+            } else if wdl[p] == WDL_LOSS {
+                pending[p] = children[p].iter().filter(|c| matches!(c, Child::Node(_, false))).count();
+                if pending[p] == 0 {
+                    dtz[p] = -1;
+                    queue.push_back(p);
+                }
+            }
+        }
+
+        while let Some(c) = queue.pop_front() {
+            for &(p, zeroing) in parents[c].iter() {
+                if zeroing || dtz[p] != 0 {
+                    continue;
+                }
+                if dtz[c] < 0 && wdl[p] == WDL_WIN {
+                    dtz[p] = 1 - dtz[c];
+                    queue.push_back(p);
+                } else if dtz[c] > 0 && wdl[p] == WDL_LOSS {
+                    pending[p] -= 1;
+                    if pending[p] == 0 {
+                        dtz[p] = -dtz[c] - 1;
+                        queue.push_back(p);
+                    }
+                }
+            }
+        }
+
+        Solved { info, pieces: pieces.to_vec(), positions, slots, wdl, dtz }
+    }
+
+    // Writes the WDL table with both sides to move and the DTZ table with white to move.
+    // Cursed tables turn every win into a cursed win, with DTZ stored in moves.
+    fn write_solved(dir: &Path, name: &str, solved: &Solved, cursed: bool) {
+        let wdl_table = layout(&solved.info, &solved.pieces, TableType::Wdl, 0);
+        let mut wdl: Vec<Vec<u16>> = wdl_table.pairs.iter().map(|d| vec![WDL_DRAW as u16 + 2; tb_size(d)]).collect();
+
+        let flags = if cursed { 0 } else { FLAG_WIN_PLIES | FLAG_LOSS_PLIES };
+        let dtz_table = layout(&solved.info, &solved.pieces, TableType::Dtz, flags);
+        let mut dtz: Vec<Vec<u16>> = dtz_table.pairs.iter().map(|d| vec![0; tb_size(d)]).collect();
+
+        for (i, &(stm, file, idx)) in solved.slots.iter().enumerate() {
+            let value = if cursed { solved.wdl[i] / 2 } else { solved.wdl[i] };
+            wdl[file * 2 + stm][idx as usize] = (value + 2) as u16;
+
+            if stm == 0 && solved.dtz[i] != 0 {
+                let plies = solved.dtz[i].abs() - 1;
+                dtz[file][idx as usize] = if cursed { plies / 2 } else { plies } as u16;
+            }
+        }
+
+        write_table(&dir.join(format!("{}.rtbw", name)), &solved.info, &solved.pieces, TableType::Wdl, 0, &wdl);
+        write_table(&dir.join(format!("{}.rtbz", name)), &solved.info, &solved.pieces, TableType::Dtz, flags, &dtz);
+    }
+
+    fn write_draws(dir: &Path, name: &str, white: &[usize], pieces: &[u8]) {
+        let info = table_info(white, &[]);
+        let table = layout(&info, pieces, TableType::Wdl, 0);
+        let values: Vec<Vec<u16>> = table.pairs.iter().map(|d| vec![WDL_DRAW as u16 + 2; tb_size(d)]).collect();
+        write_table(&dir.join(format!("{}.rtbw", name)), &info, pieces, TableType::Wdl, 0, &values);
+    }
+
+    struct Generated {
+        dir: String,
+        cursed_dir: String,
+        solved: Vec<Solved>,
+    }
+
+    // KNvK and KBvK are single value draws, KQvK and KRvK are solved and KPvK probes them
+    // for promotions. The cursed KRvK lives in its own directory.
+    fn generated() -> &'static Generated {
+        static GENERATED: OnceLock<Generated> = OnceLock::new();
+        GENERATED.get_or_init(|| {
+            let dir = std::env::temp_dir().join(format!("piston-syzygy-{}", std::process::id()));
+            let cursed_dir = dir.join("cursed");
+            fs::create_dir_all(&cursed_dir).unwrap();
+
+            write_draws(&dir, "KNvK", &[1], &[WHITE_KING, 2, BLACK_KING]);
+            write_draws(&dir, "KBvK", &[2], &[WHITE_KING, 3, BLACK_KING]);
+
+            let mut tablebases = Tablebases::new();
+            let mut solved = Vec::new();
+            for (name, white, pieces) in [("KQvK", 4, [WHITE_KING, 5, BLACK_KING]), ("KRvK", 3, [WHITE_KING, 4, BLACK_KING]), ("KPvK", 0, [1, WHITE_KING, BLACK_KING])] {
+                tablebases.init(dir.to_str().unwrap());
+                let s = solve(&mut tablebases, &[white], &[], &pieces);
+                write_solved(&dir, name, &s, false);
+                if name == "KRvK" {
+                    write_solved(&cursed_dir, name, &s, true);
+                }
+                solved.push(s);
+            }
+
+            Generated { dir: dir.to_string_lossy().to_string(), cursed_dir: cursed_dir.to_string_lossy().to_string(), solved }
+        })
+    }
+
+    fn tablebases(dir: &str) -> Tablebases {
+        let mut tablebases = Tablebases::new();
+        tablebases.init(dir);
+        tablebases
+    }
+
+    fn probe(fen: &str) -> (i32, i32) {
+        let mut tablebases = tablebases(&generated().dir);
+        let pos = Position::build_from_fen(fen);
+        (tablebases.probe_wdl(&pos).unwrap(), tablebases.probe_dtz(&pos).unwrap())
+    }
+
+    // Sizes and indices worked out with the encoding of the reference prober, apart from
+    // locate and set_groups, for piece orders as a table header could give them
+    #[test]
+    fn indices_follow_the_reference_encoding() {
+        const WHITE_PAWN: u8 = 1;
+        const WHITE_ROOK: u8 = 4;
+        const WHITE_QUEEN: u8 = 5;
+        const BLACK_PAWN: u8 = 9;
+        const BLACK_ROOK: u8 = 12;
+
+        // White and black pieces, the piece order, the size and the expected slots of FENs
+        type Table<'a> = (&'a [usize], &'a [usize], &'a [u8], u64, &'a [(&'a str, (usize, usize, u64))]);
+        let tables: [Table; 4] = [
+            // 31332 placements of the three unique leading pieces, 61 squares for the rook.
+            // Off the diagonal, then the first, the first two and all three leading pieces on
+            // it, then mirrored to the a1-d1-d4 triangle and with black as the stronger side.
+            (&[4], &[3], &[WHITE_KING, WHITE_QUEEN, BLACK_KING, BLACK_ROOK], 31332 * 61, &[
+                ("r7/6k1/8/8/4Q3/8/8/1K6 w - - 0 1", (0, 0, 1662322)),
+                ("7k/r7/8/8/8/2K5/5Q2/8 w - - 0 1", (0, 0, 1468861)),
+                ("2r5/8/8/8/3Q4/8/1K6/7k b - - 0 1", (1, 0, 1753898)),
+                ("8/6k1/8/8/8/2Q5/7r/K7 w - - 0 1", (0, 0, 469822)),
+                ("8/1Q6/6K1/7r/8/8/2k5/8 b - - 0 1", (1, 0, 108735)),
+                ("8/8/q7/3K4/8/5k2/4R3/8 w - - 0 1", (1, 0, 373207)),
+            ]),
+            // Without unique pieces the 462 king placements lead, the rooks are one group
+            (&[3, 3], &[], &[WHITE_KING, BLACK_KING, WHITE_ROOK, WHITE_ROOK], 462 * 1891, &[
+                ("8/R7/4k3/7R/8/8/2K5/8 w - - 0 1", (0, 0, 495935)),
+                ("8/5K2/8/7k/8/8/8/RR6 b - - 0 1", (1, 0, 873369)),
+            ]),
+            // The leading pawn picks the file, mirrored from the h-side and for black
+            (&[3, 0], &[3], &[WHITE_PAWN, WHITE_KING, WHITE_ROOK, BLACK_KING, BLACK_ROOK], 6 * 63 * 62 * 61 * 60, &[
+                ("7r/8/1k6/5P2/8/8/8/3R2K1 w - - 0 1", (0, 2, 75347883)),
+                ("4r3/2K5/8/8/1P6/8/1R6/k7 b - - 0 1", (1, 1, 80061074)),
+                ("7k/4r3/8/8/8/R2p4/8/4K3 w - - 0 1", (1, 3, 55664704)),
+            ]),
+            // Pawns on both sides, the side with fewer pawns leads and the other pawns follow
+            (&[0, 0], &[0], &[BLACK_PAWN, WHITE_PAWN, WHITE_PAWN, WHITE_KING, BLACK_KING], 6 * 1081 * 61 * 60, &[
+                ("8/7k/8/4p3/6P1/8/2P5/1K6 w - - 0 1", (0, 3, 17448189)),
+                ("3k4/8/7p/1P6/8/K7/5P2/8 b - - 0 1", (1, 0, 22301494)),
+            ]),
+        ];
+
+        for (white, black, pieces, size, cases) in tables {
+            let info = table_info(white, black);
+            let mut table = layout(&info, pieces, TableType::Wdl, 0);
+            if info.pawn_count[1] != 0 {
+                for (f, pair) in table.pairs.chunks_mut(table.sides).enumerate() {
+                    for d in pair.iter_mut() {
+                        set_groups(&info, d, [0, 1], f);
+                    }
+                }
+            }
+
+            for d in table.pairs.iter() {
+                assert_eq!(tb_size(d) as u64, size);
+            }
+            for &(fen, expected) in cases {
+                let pos = Position::build_from_fen(fen);
+                assert_eq!(locate(&pos, &info, &table, TableType::Wdl), Some(expected), "{}", fen);
+            }
+        }
+    }
+
+    #[test]
+    fn probes_match_the_solved_tables() {
+        let generated = generated();
+        let mut tablebases = tablebases(&generated.dir);
+        assert_eq!(tablebases.max_pieces, 3);
+
+        // A sample is enough to reach every block, probing all positions is slow without optimizations
+        for s in generated.solved.iter() {
+            for (i, pos) in s.positions.iter().enumerate().step_by(7) {
+                assert_eq!(tablebases.probe_wdl(pos), Some(s.wdl[i]), "{}", pos.to_fen());
+                assert_eq!(tablebases.probe_dtz(pos), Some(s.dtz[i]), "{}", pos.to_fen());
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_tables_fail_the_probe() {
+        let generated = generated();
+        let info = table_info(&[4], &[]);
+        let path = format!("{}/KQvK.rtbw", generated.dir);
+        let table = load_table(&path, &info, TableType::Wdl).unwrap();
+        assert!(decompress_pairs(&table, table.get(0, 0), 0).is_some());
+
+        // A sparse index entry pointing past the last block
+        let mut damaged = load_table(&path, &info, TableType::Wdl).unwrap();
+        let entry = damaged.get(0, 0).sparse_index;
+        damaged.bytes[entry..entry + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decompress_pairs(&damaged, damaged.get(0, 0), 0).is_none());
+
+        // A file cut off inside its blocks is not loaded at all
+        let dir = Path::new(&generated.dir).join("truncated");
+        fs::create_dir_all(&dir).unwrap();
+        let truncated = dir.join("KQvK.rtbw");
+        fs::write(&truncated, &table.bytes[..table.get(0, 0).data + 8]).unwrap();
+        assert!(load_table(truncated.to_str().unwrap(), &info, TableType::Wdl).is_none());
+    }
+
+    #[test]
+    fn longest_wins() {
+        // Mate in 10 moves with the queen and in 16 with the rook
+        let generated = generated();
+        let longest = |s: &Solved| s.dtz.iter().max().copied().unwrap();
+        assert_eq!(longest(&generated.solved[0]), 19);
+        assert_eq!(longest(&generated.solved[1]), 31);
+    }
+
+    #[test]
+    fn queen_and_rook_endings() {
+        assert_eq!(probe("7k/8/6K1/8/8/8/Q7/8 w - - 0 1"), (WDL_WIN, 1));
+        assert_eq!(probe("7k/8/6K1/8/8/8/Q7/8 b - - 0 1"), (WDL_DRAW, 0));
+        assert_eq!(probe("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1"), (WDL_DRAW, 0));
+        assert_eq!(probe("7k/8/6K1/8/8/8/8/R7 w - - 0 1"), (WDL_WIN, 1));
+
+        // Colors are switched when black has the stronger side
+        assert_eq!(probe("8/q7/8/8/8/6k1/8/7K b - - 0 1"), (WDL_WIN, 1));
+        assert_eq!(probe("8/8/8/8/8/8/1q6/K6k w - - 0 1"), (WDL_DRAW, 0));
+
+        // The DTZ table only stores white to move, black's side is searched one ply deep
+        let (wdl, dtz) = probe("8/8/8/3k4/8/8/8/KR6 b - - 0 1");
+        assert_eq!(wdl, WDL_LOSS);
+        assert!(dtz < -1);
+    }
+
+    #[test]
+    fn pawn_endings() {
+        assert_eq!(probe("4k3/8/4K3/4P3/8/8/8/8 w - - 0 1").0, WDL_WIN);
+        assert_eq!(probe("4k3/8/4K3/4P3/8/8/8/8 b - - 0 1").0, WDL_LOSS);
+        assert_eq!(probe("k7/8/8/P7/8/8/8/K7 w - - 0 1"), (WDL_DRAW, 0));
+        assert_eq!(probe("8/P7/8/8/8/8/7k/K7 w - - 0 1"), (WDL_WIN, 1));
+        assert_eq!(probe("8/P7/8/8/8/8/7k/K7 b - - 0 1"), (WDL_LOSS, -2));
+        assert_eq!(probe("8/8/8/8/8/1k6/P7/6K1 b - - 0 1"), (WDL_DRAW, 0));
+        assert_eq!(probe("8/8/8/8/8/k7/p7/7K b - - 0 1"), (WDL_WIN, 1));
+    }
+
+    #[test]
+    fn root_ranks_keep_only_winning_moves() {
+        let mut tablebases = tablebases(&generated().dir);
+
+        // Moves keeping the g8 square covered stalemate, Qg8+ loses the queen
+        let pos = Position::build_from_fen("7k/8/6K1/8/8/8/Q7/8 w - - 0 1");
+        let moves = pos.legal_moves();
+        let ranks = tablebases.root_ranks(&pos, &moves).unwrap();
+
+        let drawn = ["a2b3", "a2c4", "a2d5", "a2e6", "a2f7", "a2g8", "g6h6"];
+        for (m, rank) in moves.iter().zip(ranks) {
+            let name: String = [m.origin_index(), m.target_index()].iter()
+                .map(|&sq| format!("{}{}", (b'a' + (sq & 7) as u8) as char, sq / 8 + 1))
+                .collect();
+            assert_eq!(rank, if drawn.contains(&name.as_str()) { 0 } else { 1000 }, "{}", name);
+        }
+
+        // Only taking the queen saves black
+        let pos = Position::build_from_fen("7K/8/8/8/8/5k2/6Q1/8 b - - 0 1");
+        let moves = pos.legal_moves();
+        let ranks = tablebases.root_ranks(&pos, &moves).unwrap();
+        for (m, rank) in moves.iter().zip(ranks) {
+            assert_eq!(rank, if m.target() & pos.enemy != 0 { 0 } else { -1000 });
+        }
+    }
+
+    #[test]
+    fn cursed_wins_and_blessed_losses() {
+        let mut tablebases = tablebases(&generated().cursed_dir);
+
+        let pos = Position::build_from_fen("8/8/8/8/8/8/R7/K6k w - - 0 1");
+        assert_eq!(tablebases.probe_wdl(&pos), Some(WDL_CURSED_WIN));
+        assert!(tablebases.probe_dtz(&pos).unwrap() > 100);
+
+        // Winning moves rank below every win inside the fifty-move rule
+        let moves = pos.legal_moves();
+        let ranks = tablebases.root_ranks(&pos, &moves).unwrap();
+        assert!(ranks.iter().all(|&r| r < 900));
+        assert!(ranks.iter().any(|&r| r > 0));
+
+        let pos = Position::build_from_fen("8/8/8/8/8/8/R7/K6k b - - 0 1");
+        assert_eq!(tablebases.probe_wdl(&pos), Some(WDL_BLESSED_LOSS));
+        assert!(tablebases.probe_dtz(&pos).unwrap() < -100);
+    }
+
+    #[test]
+    fn finds_tables_in_each_directory() {
+        let dir = std::env::temp_dir().join(format!("piston-syzygy-init-{}", std::process::id()));
+        let other = dir.join("other");
+        fs::create_dir_all(&other).unwrap();
+        fs::write(dir.join("KQvK.rtbw"), []).unwrap();
+        fs::write(other.join("KRvK.rtbw"), []).unwrap();
+        fs::write(other.join("KRvK.rtbz"), []).unwrap();
+        fs::write(other.join("KBvK.rtbz"), []).unwrap();
+
+        let mut tablebases = Tablebases::new();
+        let path = format!("{}{}{}", dir.display(), if cfg!(windows) { ';' } else { ':' }, other.display());
+        assert_eq!(tablebases.init(&path), 2);
+        assert_eq!(tablebases.max_pieces, 3);
+        assert!(tablebases.can_probe(&Position::build_from_fen("8/8/8/4k3/8/8/8/KQ6 w - - 0 1")));
+        assert!(!tablebases.can_probe(&Position::build_from_fen("8/8/8/4k3/8/8/8/KQR5 w - - 0 1")));
+
+        // An unreadable table fails the probe instead of returning a result
+        assert_eq!(tablebases.probe_wdl(&Position::build_from_fen("8/8/8/4k3/8/8/8/KQ6 w - - 0 1")), None);
+
+        assert_eq!(tablebases.init("<empty>"), 0);
+        assert!(!tablebases.can_probe(&Position::build_from_fen("8/8/8/4k3/8/8/8/KQ6 w - - 0 1")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn material_keys_match_table_keys() {
+        let info = table_info(&[4], &[3]);
+        let white_queen = Position::build_from_fen("8/8/8/4k3/8/8/4r3/KQ6 w - - 0 1");
+        let black_queen = Position::build_from_fen("8/8/8/4k3/8/8/4q3/KR6 b - - 0 1");
+
+        assert_eq!(material_key(&white_queen, false), info.key);
+        assert_eq!(material_key(&white_queen, true), info.key2);
+        assert_eq!(material_key(&black_queen, false), info.key2);
+        assert_eq!(info.piece_count, 4);
+        assert!(!info.has_pawns && info.has_unique_pieces);
+
+        // The side with fewer pawns leads
+        let pawns = table_info(&[0, 0], &[0]);
+        assert!(pawns.has_pawns);
+        assert_eq!(pawns.pawn_count, [1, 2]);
+    }
+
+    #[test]
+    fn index_tables() {
+        assert_eq!(BINOMIAL[2][63], 1953);
+        assert_eq!(BINOMIAL[3][62], 37820);
+
+        // 462 ways to place two kings that are not adjacent, up to symmetry
+        let mut kk: Vec<u64> = MAP_KK.iter().flatten().copied().filter(|&i| i < 462).collect();
+        kk.sort();
+        kk.dedup();
+        assert_eq!(kk, (0..462).collect::<Vec<u64>>());
+    }
+}
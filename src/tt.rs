@@ -1,3 +1,6 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use crate::hash::KEY_SCHEME;
 use crate::position::Move;
 
 pub const TT_DEFAULT_SIZE: u64 = 2097152;
@@ -5,13 +8,34 @@ pub const TT_DEFAULT_INDEX_BITS: u32 = 21;
 pub const BUCKET_SIZE: usize = 4;
 pub const BUCKET_MASK: u64 = !((BUCKET_SIZE as u64)-1);
 
+// Every search an entry is old costs it this much depth when a bucket picks its victim
+const AGE_WEIGHT: i32 = 8;
+
+// hashfull looks at this many entries at the start of the table
+pub const HASHFULL_SAMPLE: usize = 1000;
+
+// Entries from this many searches ago and older are counted together
+pub const AGE_CLASSES: usize = 4;
+
+// Saved tables start with the magic, the format version, the key scheme, the number of
+// entries of the table and the number of entries that follow, 16 bytes each
+pub const HASH_FILE_MAGIC: &[u8; 8] = b"PISTONTT";
+pub const HASH_FILE_VERSION: u32 = 1;
+pub const HASH_HEADER_SIZE: usize = 36;
+
+// How the stored eval bounds the true one, zero in the entry marks an empty slot
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Bound {
+    Upper = 1,  // all-node, every move failed low
+    Lower = 2,  // cut-node, a move failed high
+    Exact = 3,  // pv-node
+}
+
 // Transposition table entry
 #[derive(Copy, Clone, Default)]
 pub struct TTEntry {
-    pub data:       u128,    // Order: REFUTATION - DEPTH - AGE - OUTCOME - EVAL - KEY
-                             // Bits:      20         5      2       2       16    64
-
-                             // Outcome: 0 = all-node, 1 = pv_node, 2 = cut-node
+    pub data:       u128,    // Order: REFUTATION - GENERATION - DEPTH - BOUND - EVAL - KEY
+                             // Bits:      16           8          8       2      16    64
 }
 
 impl TTEntry {
@@ -21,30 +45,37 @@ impl TTEntry {
         ((self.data >> 64) & 0xFFFF) as i16
     }
 
-    pub fn get_outcome(&self) -> u8 { ((self.data >> 80) & 0x3) as u8 }
-
-    pub fn get_age(&self) -> u8 {
-        ((self.data >> 82) & 0x3) as u8
+    pub fn get_bound(&self) -> Bound {
+        match (self.data >> 80) & 0x3 {
+            1 => Bound::Upper,
+            2 => Bound::Lower,
+            _ => Bound::Exact,
+        }
     }
 
-    pub fn get_depth(&self) -> u8 { ((self.data >> 84) & 0x1F) as u8 }
+    pub fn get_depth(&self) -> u8 { ((self.data >> 82) & 0xFF) as u8 }
+
+    pub fn get_generation(&self) -> u8 { ((self.data >> 90) & 0xFF) as u8 }
 
-    pub fn get_refutation(&self) -> Move { Move::from_u32(((self.data >> 89) & 0xFFFFF) as u32) }
+    pub fn get_refutation(&self) -> Move { Move(((self.data >> 98) & 0xFFFF) as u16) }
+
+    pub fn is_empty(&self) -> bool { (self.data >> 80) & 0x3 == 0 }
 }
 
-pub fn create_entry(key: u64, eval: i16, outcome: u8, age: u8, depth: u8, refutation: u32) -> TTEntry {
+pub fn create_entry(key: u64, eval: i16, bound: Bound, depth: u8, generation: u8, refutation: Move) -> TTEntry {
     let mut data = key as u128;                                          // 64 BITS, TOTAL = 64
-    data |= (eval as u128 & 0xFFFF) << 64;                                     // 16 BITS, TOTAL = 80
-    data |= (outcome as u128 & 0x3) << 80;                                     // 2  BITS, TOTAL = 82
-    data |= (age as u128 & 0x3) << 82;                                         // 2  BITS, TOTAL = 84
-    data |= (depth as u128 & 0x1F) << 84;                                      // 5  BITS, TOTAL = 89
-    data |= (refutation as u128 & 0xFFFFF) << 89;                              // 20 BITS, TOTAL = 109
+    data |= (eval as u16 as u128) << 64;                                       // 16 BITS, TOTAL = 80
+    data |= (bound as u128) << 80;                                             // 2  BITS, TOTAL = 82
+    data |= (depth as u128) << 82;                                             // 8  BITS, TOTAL = 90
+    data |= (generation as u128) << 90;                                        // 8  BITS, TOTAL = 98
+    data |= (refutation.0 as u128) << 98;                                      // 16 BITS, TOTAL = 114
     TTEntry {data}
 }
 
 pub struct TT {
     pub table: Vec<TTEntry>,
     pub mask:  u64,
+    pub generation: u8,
 }
 
 impl TT {
@@ -53,51 +84,361 @@ impl TT {
 
         for i in index..index+BUCKET_SIZE {
             let stored = self.table[i];
-            if stored.get_key() == key {
+            if !stored.is_empty() && stored.get_key() == key {
                 return Some(stored);
             }
         }
 
-        return None;
+        None
+    }
+
+    // Bumped on every go, entries of earlier searches become cheaper to replace
+    pub fn new_search(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
     }
 
-    pub fn place(&mut self, _root_key: u64, root_age: u8, key: u64, eval: i16, outcome: u8, depth: u8, refutation: u32) {
+    // A position already in the bucket is updated in place, unless the new bound is shallower
+    // than one stored this search. Otherwise an empty slot is used, or the entry that is the
+    // shallowest once its age is counted against it.
+    pub fn place(&mut self, key: u64, eval: i16, bound: Bound, depth: u8, refutation: Move) {
         let index = (key & self.mask & BUCKET_MASK) as usize;
 
-        let mut lowest = u8::MAX;
+        let mut lowest = i32::MAX;
         let mut li = index;
         for i in index..index+BUCKET_SIZE {
-            let depth = self.table[i].get_depth();
-            let recency = ((self.table[i].get_age() == root_age) as u8) << 1;
-            let value = depth + recency;
+            let stored = self.table[i];
+            if stored.is_empty() {
+                if lowest != i32::MIN {
+                    lowest = i32::MIN;
+                    li = i;
+                }
+                continue;
+            }
+
+            if stored.get_key() == key {
+                let current = stored.get_generation() == self.generation;
+                if current && bound != Bound::Exact && (depth as u16) + 2 < stored.get_depth() as u16 {
+                    return;
+                }
+                // Keep the move of an earlier visit when this one has none to offer
+                let refutation = if refutation == Move::default() { stored.get_refutation() } else { refutation };
+                self.table[i] = create_entry(key, eval, bound, depth, self.generation, refutation);
+                return;
+            }
+
+            let age = self.generation.wrapping_sub(stored.get_generation()) as i32;
+            let value = stored.get_depth() as i32 - AGE_WEIGHT * age;
             if value < lowest {
                 lowest = value;
                 li = i;
             }
         }
 
-        let entry = create_entry(key, eval, outcome, root_age, depth, refutation);
-        self.table[li] = entry;
+        self.table[li] = create_entry(key, eval, bound, depth, self.generation, refutation);
+    }
+
+    // Writes the entries of at least min_depth, returns how many were written
+    pub fn save(&self, path: &str, min_depth: u8) -> std::io::Result<usize> {
+        let entries: Vec<&TTEntry> = self.table.iter().filter(|e| !e.is_empty() && e.get_depth() >= min_depth).collect();
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(HASH_FILE_MAGIC)?;
+        writer.write_all(&HASH_FILE_VERSION.to_le_bytes())?;
+        writer.write_all(&KEY_SCHEME.to_le_bytes())?;
+        writer.write_all(&(self.table.len() as u64).to_le_bytes())?;
+        writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+        for entry in entries.iter() {
+            writer.write_all(&entry.data.to_le_bytes())?;
+        }
+        writer.flush()?;
+
+        Ok(entries.len())
+    }
+
+    // Adds the saved entries to the table, as written by the current search. A table of
+    // another size is fine, the entries are sorted into the buckets of this one.
+    pub fn load(&mut self, path: &str) -> std::io::Result<usize> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0; HASH_HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+
+        let field = |at: usize| u64::from_le_bytes(header[at..at+8].try_into().unwrap());
+        if &header[0..8] != HASH_FILE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a hash file"));
+        }
+        if u32::from_le_bytes(header[8..12].try_into().unwrap()) != HASH_FILE_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, "unsupported hash file version"));
+        }
+        if field(12) != KEY_SCHEME {
+            return Err(Error::new(ErrorKind::InvalidData, "hash file uses other Zobrist keys"));
+        }
+
+        let count = field(28) as usize;
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if bytes.len() != count * 16 {
+            return Err(Error::new(ErrorKind::InvalidData, "hash file is truncated"));
+        }
+
+        for chunk in bytes.chunks_exact(16) {
+            let stored = TTEntry { data: u128::from_le_bytes(chunk.try_into().unwrap()) };
+            if stored.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidData, "hash file holds an empty entry"));
+            }
+            let key = stored.get_key();
+            let entry = create_entry(key, stored.get_eval(), stored.get_bound(), stored.get_depth(), self.generation, stored.get_refutation());
+            self.insert(entry);
+        }
+
+        Ok(count)
+    }
+
+    // Puts a loaded entry into an empty slot of its bucket, or over a shallower entry
+    fn insert(&mut self, entry: TTEntry) {
+        let index = (entry.get_key() & self.mask & BUCKET_MASK) as usize;
+        let bucket = &mut self.table[index..index+BUCKET_SIZE];
+
+        if let Some(slot) = bucket.iter_mut().find(|e| e.is_empty() || e.get_key() == entry.get_key()) {
+            if slot.is_empty() || slot.get_depth() <= entry.get_depth() {
+                *slot = entry;
+            }
+            return;
+        }
+
+        let slot = bucket.iter_mut().min_by_key(|e| e.get_depth()).unwrap();
+        if slot.get_depth() < entry.get_depth() {
+            *slot = entry;
+        }
     }
 
     pub fn reset(&mut self) {
         for i in 0..self.table.len() {
             self.table[i] = TTEntry::default();
         }
+        self.generation = 0;
     }
 
+    // Permille of the sampled entries written by the current search, which the UCI hashfull
+    // reports. Entries of earlier searches are free to be replaced.
     pub fn hashfull(&self) -> u64 {
-        let mut counter: u64 = 0;
-        for i in 0..self.table.len() {
-            if self.table[i].data != 0 { counter += 1 }
+        let sample = &self.table[0..self.table.len().min(HASHFULL_SAMPLE)];
+        let used = sample.iter().filter(|e| !e.is_empty() && e.get_generation() == self.generation).count();
+        (used * 1000 / sample.len()) as u64
+    }
+
+    // Walks the whole table, only meant for the hashstats command
+    pub fn stats(&self) -> TTStats {
+        let mut stats = TTStats { entries: self.table.len() as u64, used: 0, buckets: [0; BUCKET_SIZE+1], ages: [0; AGE_CLASSES] };
+        for bucket in self.table.chunks(BUCKET_SIZE) {
+            let mut fill = 0;
+            for entry in bucket.iter().filter(|e| !e.is_empty()) {
+                let age = self.generation.wrapping_sub(entry.get_generation()) as usize;
+                stats.ages[age.min(AGE_CLASSES - 1)] += 1;
+                fill += 1;
+            }
+            stats.used += fill as u64;
+            stats.buckets[fill] += 1;
         }
-        counter / (TT_DEFAULT_SIZE / 1000)
+        stats
     }
 }
 
+// Occupancy of the table, buckets counts the buckets by number of used slots and ages the
+// entries by how many searches ago they were written
+pub struct TTStats {
+    pub entries: u64,
+    pub used: u64,
+    pub buckets: [u64; BUCKET_SIZE+1],
+    pub ages: [u64; AGE_CLASSES],
+}
+
 pub fn create_tt (size: u64) -> TT {
     assert!(size.is_power_of_two());
     let mask: u64 = size - 1;
     let table = vec![TTEntry::default(); size as usize];
-    TT {mask, table}
-}
\ No newline at end of file
+    TT {mask, table, generation: 0}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitboard::BITS;
+
+    // All of these land in the first bucket of a small table
+    fn key(n: u64) -> u64 {
+        n << 8
+    }
+
+    fn e2e4() -> Move {
+        Move::new(BITS[12], BITS[28], 5)
+    }
+
+    #[test]
+    fn entry_round_trip() {
+        let entry = create_entry(0xDEADBEEF12345678, -9876, Bound::Lower, 200, 77, e2e4());
+        assert_eq!(entry.get_key(), 0xDEADBEEF12345678);
+        assert_eq!(entry.get_eval(), -9876);
+        assert_eq!(entry.get_bound(), Bound::Lower);
+        assert_eq!(entry.get_depth(), 200);
+        assert_eq!(entry.get_generation(), 77);
+        assert!(entry.get_refutation() == e2e4());
+        assert!(!entry.is_empty());
+    }
+
+    #[test]
+    fn probe_ignores_empty_slots() {
+        let mut tt = create_tt(16);
+        assert!(tt.probe(0).is_none());
+
+        tt.place(key(1), 25, Bound::Exact, 4, e2e4());
+        let entry = tt.probe(key(1)).unwrap();
+        assert_eq!((entry.get_eval(), entry.get_bound(), entry.get_depth()), (25, Bound::Exact, 4));
+        assert!(tt.probe(key(2)).is_none());
+    }
+
+    #[test]
+    fn same_key_is_updated_in_place() {
+        let mut tt = create_tt(16);
+        tt.place(key(1), 10, Bound::Upper, 3, e2e4());
+        tt.place(key(1), 30, Bound::Lower, 5, Move::default());
+
+        let copies = tt.table.iter().filter(|e| !e.is_empty() && e.get_key() == key(1)).count();
+        assert_eq!(copies, 1);
+        let entry = tt.probe(key(1)).unwrap();
+        assert_eq!((entry.get_eval(), entry.get_bound(), entry.get_depth()), (30, Bound::Lower, 5));
+        assert!(entry.get_refutation() == e2e4());
+    }
+
+    #[test]
+    fn shallow_bounds_keep_deep_entries_of_the_same_search() {
+        let mut tt = create_tt(16);
+        tt.place(key(1), 10, Bound::Lower, 12, e2e4());
+
+        tt.place(key(1), 20, Bound::Upper, 2, Move::default());
+        assert_eq!(tt.probe(key(1)).unwrap().get_eval(), 10);
+
+        tt.place(key(1), 30, Bound::Exact, 2, Move::default());
+        assert_eq!(tt.probe(key(1)).unwrap().get_eval(), 30);
+
+        tt.place(key(1), 10, Bound::Lower, 12, e2e4());
+        tt.new_search();
+        tt.place(key(1), 20, Bound::Upper, 2, Move::default());
+        assert_eq!(tt.probe(key(1)).unwrap().get_eval(), 20);
+    }
+
+    #[test]
+    fn shallowest_entry_is_replaced() {
+        let mut tt = create_tt(16);
+        for (n, depth) in [(1, 8), (2, 3), (3, 9), (4, 6)] {
+            tt.place(key(n), 0, Bound::Exact, depth, Move::default());
+        }
+        tt.place(key(5), 0, Bound::Exact, 1, Move::default());
+
+        assert!(tt.probe(key(2)).is_none());
+        for n in [1, 3, 4, 5] {
+            assert!(tt.probe(key(n)).is_some(), "key {} was replaced", n);
+        }
+    }
+
+    #[test]
+    fn old_entries_go_before_deeper_current_ones() {
+        let mut tt = create_tt(16);
+        tt.place(key(1), 0, Bound::Exact, 20, Move::default());
+        for _ in 0..3 {
+            tt.new_search();
+        }
+        for n in 2..5 {
+            tt.place(key(n), 0, Bound::Exact, 5, Move::default());
+        }
+        tt.place(key(5), 0, Bound::Exact, 1, Move::default());
+
+        assert!(tt.probe(key(1)).is_none());
+        for n in 2..6 {
+            assert!(tt.probe(key(n)).is_some(), "key {} was replaced", n);
+        }
+    }
+
+    #[test]
+    fn hashfull_counts_the_current_search() {
+        let mut tt = create_tt(4096);
+        assert_eq!(tt.hashfull(), 0);
+
+        for n in 0..500 {
+            tt.place(n * 2 + 1, 0, Bound::Exact, 1, Move::default());
+        }
+        assert_eq!(tt.hashfull(), 500);
+
+        tt.new_search();
+        assert_eq!(tt.hashfull(), 0);
+    }
+
+    #[test]
+    fn stats_count_fill_and_age() {
+        let mut tt = create_tt(16);
+        tt.place(key(1), 0, Bound::Exact, 1, Move::default());
+        tt.place(key(2), 0, Bound::Exact, 1, Move::default());
+        tt.new_search();
+        tt.place(key(3), 0, Bound::Exact, 1, Move::default());
+        tt.place(4, 0, Bound::Exact, 1, Move::default());
+
+        let stats = tt.stats();
+        assert_eq!((stats.entries, stats.used), (16, 4));
+        assert_eq!(stats.buckets, [2, 1, 0, 1, 0]);
+        assert_eq!(stats.ages, [2, 2, 0, 0]);
+    }
+
+    fn hash_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("piston-{}-{}.hash", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn saved_entries_load_into_any_size() {
+        let path = hash_file("round-trip");
+        let mut tt = create_tt(16);
+        tt.place(key(1), -40, Bound::Lower, 9, e2e4());
+        tt.place(key(2), 15, Bound::Upper, 2, Move::default());
+        tt.place(3, 7, Bound::Exact, 5, Move::default());
+        assert_eq!(tt.save(&path, 5).unwrap(), 2);
+
+        for size in [16, 64] {
+            let mut loaded = create_tt(size);
+            loaded.new_search();
+            assert_eq!(loaded.load(&path).unwrap(), 2);
+
+            let entry = loaded.probe(key(1)).unwrap();
+            assert_eq!((entry.get_eval(), entry.get_bound(), entry.get_depth()), (-40, Bound::Lower, 9));
+            assert!(entry.get_refutation() == e2e4());
+            assert_eq!(entry.get_generation(), loaded.generation);
+            assert!(loaded.probe(3).is_some());
+            assert!(loaded.probe(key(2)).is_none());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn other_key_schemes_are_rejected() {
+        let path = hash_file("key-scheme");
+        let mut tt = create_tt(16);
+        tt.place(key(1), 0, Bound::Exact, 5, Move::default());
+        tt.save(&path, 0).unwrap();
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[12] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let error = create_tt(16).load(&path).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reset_clears_entries_and_generation() {
+        let mut tt = create_tt(16);
+        tt.new_search();
+        tt.place(key(1), 0, Bound::Exact, 5, Move::default());
+        tt.reset();
+        assert!(tt.probe(key(1)).is_none());
+        assert_eq!(tt.generation, 0);
+    }
+}
@@ -1,45 +1,117 @@
+use std::collections::VecDeque;
 use std::io::stdin;
-use std::ops::{Deref};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::mpsc;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use crate::bitboard::BITS;
-use crate::output::string_to_index;
+use crate::book::Book;
+use crate::output::{move_to_string, string_to_index};
 use crate::position::{Move, Position, STARTPOS_FEN};
-use crate::state::{EngineState, MAX_MOVE_COUNT, SearchStats};
+use crate::san::SanError;
+use crate::state::{EngineState, MAX_MOVE_COUNT, MAX_PLY, SearchLimits, SearchStats};
 use crate::output::Display;
-use crate::search::{perft, pvs};
+use crate::perft::divide;
+use crate::search::{pvs, SearchListener, SearchResult};
+use crate::xboard::xboard_loop;
 
 const CMD_ERR: &str = "Error parsing command.";
 
+// Changes to the root, the options or the tables wait until the running search has finished
+fn waits_for_search(command: &str) -> bool {
+    matches!(command, "go" | "position" | "move" | "setoption" | "ucinewgame" | "savehash" | "loadhash")
+}
+
+// Reads stdin on its own thread, so commands arrive while the loop waits for a search. The
+// channel closes at EOF.
+pub fn spawn_reader() -> Receiver<Vec<String>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in stdin().lines() {
+            let Ok(line) = line else { break };
+            let tokens: Vec<String> = line.split_whitespace().map(String::from).collect();
+            if !tokens.is_empty() && sender.send(tokens).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// stop, quit, isready and ponderhit are handled as they arrive. Other commands run in order,
+// those that change the next search once the running one is done. A stop still reaches the
+// running search when commands are queued behind it, and isready and ponderhit wait for the
+// queued commands before them. EOF quits like quit does.
 pub fn uci_loop() {
     println!("Piston dev build");
     println!();
 
     let es = &mut EngineState::new();
+    let input = spawn_reader();
+    let mut queue: VecDeque<Vec<String>> = VecDeque::new();
 
     loop {
-        let mut buffer = String::new();
-        stdin().read_line(&mut buffer).unwrap();
-        let tokens: Vec<&str> = buffer.trim().split(" ").collect();
-
-        if let Some(&token) = tokens.get(0) {
-            match token {
-                "go" => handle_go(es, tokens),
-                "move" => handle_move(es, tokens),
-                "position" => handle_position(es, tokens),
-                _ => handle_info_cmd(es, tokens)
+        while let Some(tokens) = queue.front() {
+            if waits_for_search(&tokens[0]) && search_running(es) {
+                break;
             }
+            let tokens = queue.pop_front().unwrap();
+            handle_command(es, tokens.iter().map(|t| t.as_str()).collect());
+        }
+
+        let received = if queue.is_empty() {
+            input.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            input.recv_timeout(QUEUE_POLL)
+        };
+
+        match received {
+            Ok(tokens) => match tokens[0].as_str() {
+                "quit" | "exit" => break,
+                "xboard" | "protover" if queue.is_empty() => return xboard_loop(es, input, tokens),
+                "stop" => {
+                    es.stop.store(true, Relaxed);
+                    if !queue.is_empty() {
+                        queue.push_back(tokens);
+                    }
+                }
+                "isready" | "ponderhit" if queue.is_empty() => handle_command(es, tokens.iter().map(|t| t.as_str()).collect()),
+                _ => queue.push_back(tokens),
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
         }
     }
+
+    stop_search(es);
+}
+
+// How often queued commands check whether the search has finished
+const QUEUE_POLL: Duration = Duration::from_millis(5);
+
+pub fn handle_command(es: &mut EngineState, tokens: Vec<&str>) {
+    match tokens[0] {
+        "go" => handle_go(es, tokens),
+        "move" => handle_move(es, tokens),
+        "position" => handle_position(es, tokens),
+        "setoption" => handle_setoption(es, tokens),
+        "ucinewgame" => handle_ucinewgame(es),
+        _ => handle_info_cmd(es, tokens)
+    }
 }
 
 pub fn handle_info_cmd(es: &EngineState, tokens: Vec<&str>) {
-    match tokens.get(0).unwrap().deref() {
+    match *tokens.first().unwrap() {
         "uci" => {
             println!("id name Piston Dev");
             println!("id author Carl");
+            println!("option name OwnBook type check default false");
+            println!("option name BookFile type string default <empty>");
+            println!("option name BookBestMove type check default false");
+            println!("option name SyzygyPath type string default <empty>");
+            println!("option name SyzygyProbeDepth type spin default 1 min 1 max 100");
+            println!("option name HashSaveDepth type spin default 0 min 0 max 255");
+            println!("option name Ponder type check default false");
             println!("uciok");
         }
 
@@ -48,17 +120,89 @@ pub fn handle_info_cmd(es: &EngineState, tokens: Vec<&str>) {
         "state" => es.root.state.print(),
 
         "stats" => {
+            let ss_arc = es.search_state.clone();
+            match ss_arc.try_lock() {
+                Ok(state) => state.stats.print(),
+                Err(_) => println!("Cannot access statistics during search."),
+            };
+        }
+
+        "hashstats" => {
             let ss_arc = es.search_state.clone();
             let lock_result = ss_arc.try_lock();
-            if lock_result.is_ok() {
-                lock_result.unwrap().stats.print();
+            if let Ok(state) = lock_result {
+                state.hash_table.stats().print();
             } else {
-                println!("Cannot access statistics during search.");
+                println!("Cannot access the hash table during search.");
+            }
+        }
+
+        "savehash" => {
+            let path = tokens[1..].join(" ");
+            let ss_arc = es.search_state.clone();
+            let lock_result = ss_arc.try_lock();
+            if let Ok(state) = lock_result {
+                match state.hash_table.save(&path, es.hash_save_depth) {
+                    Ok(count) => println!("info string Saved {} hash entries to {}", count, path),
+                    Err(e) => println!("info string Could not save hash to {}: {}", path, e),
+                }
+            } else {
+                println!("Cannot access the hash table during search.");
+            }
+        }
+
+        "loadhash" => {
+            let path = tokens[1..].join(" ");
+            let ss_arc = es.search_state.clone();
+            let lock_result = ss_arc.try_lock();
+            if let Ok(mut state) = lock_result {
+                match state.hash_table.load(&path) {
+                    Ok(count) => println!("info string Loaded {} hash entries from {}", count, path),
+                    Err(e) => println!("info string Could not load hash from {}: {}", path, e),
+                }
+            } else {
+                println!("Cannot access the hash table during search.");
             }
         }
 
         "d" => es.root.print(),
 
+        "book" => {
+            if let Some(book) = &es.book {
+                let moves = book.moves(&es.root);
+                let total: u64 = moves.iter().map(|(_, w)| *w as u64).sum();
+                for (m, w) in moves {
+                    m.print();
+                    println!("weight {} ({:.1}%)", w, 100.0 * w as f64 / total.max(1) as f64);
+                }
+                println!("Book entries: {}", book.probe(es.root.state.key).len());
+            } else {
+                println!("No book loaded.");
+            }
+        }
+
+        "probe" => {
+            let ss_arc = es.search_state.clone();
+            let lock_result = ss_arc.try_lock();
+            if let Ok(mut state) = lock_result {
+                let tb = &mut state.tablebases;
+                if !tb.can_probe(&es.root) {
+                    println!("Position is not covered by the loaded tablebases.");
+                } else {
+                    match tb.probe_wdl(&es.root) {
+                        Some(wdl) => println!("WDL: {}", ["loss", "blessed loss", "draw", "cursed win", "win"][(wdl + 2) as usize]),
+                        None => println!("WDL: probe failed"),
+                    }
+                    match tb.probe_dtz(&es.root) {
+                        Some(dtz) => println!("DTZ: {}", dtz),
+                        None => println!("DTZ: probe failed"),
+                    }
+                }
+            } else {
+                println!("Cannot probe tablebases during search.");
+            }
+        }
+
         "pm" => {
             let pos = &mut es.root.clone();
             let mut list = [Move::default(); MAX_MOVE_COUNT];
@@ -80,20 +224,17 @@ pub fn handle_info_cmd(es: &EngineState, tokens: Vec<&str>) {
             pos.print_moves(&mut list[0..MAX_MOVE_COUNT]);
         }
 
-        "exit" | "quit" => {
-            es.terminate.store(true, Relaxed);
-            std::process::exit(0);
-        }
-
+        "stop" => es.stop.store(true, Relaxed),
 
-        "stop" => es.terminate.store(true, Relaxed),
+        // The search limits apply from now on, the time counts from the go
+        "ponderhit" => es.ponder.store(false, Relaxed),
 
         _ => {}
     }
 }
 
 pub fn handle_position(es: &mut EngineState, tokens: Vec<&str>) {
-    let mut pos = es.root.clone();
+    let mut pos = es.root;
     let mut iter = tokens.into_iter();
     iter.next();
 
@@ -107,115 +248,378 @@ pub fn handle_position(es: &mut EngineState, tokens: Vec<&str>) {
 
             "fen" => {
                 let mut fen = String::new();
-                while let Some(token) = iter.next() {
+                for token in iter.by_ref() {
                     if token == "moves" {
                         break
                     } else {
                         fen.push_str(token);
-                        fen.push_str(" ");
+                        fen.push(' ');
+                    }
+                }
+                // A bad FEN leaves the current position, the moves after it are not applied either
+                match Position::parse_fen(&fen) {
+                    Ok(parsed) => pos = parsed,
+                    Err(e) => {
+                        println!("info string Invalid FEN: {}", e);
+                        return;
                     }
                 }
-                pos = Position::build_from_fen(fen.trim());
             }
             _ => {}
         }
 
-        while let Some(token) = iter.next() {
-            let m = parse_move(&pos, token);
-            pos = pos.make_move(m);
+        for token in iter {
+            match parse_move(&pos, token).filter(|&m| pos.is_legal(m)) {
+                Some(m) => pos = pos.make_move(m),
+                None => {
+                    println!("info string Illegal move {}", token);
+                    break;
+                }
+            }
         }
     }
     es.root = pos;
     es.root.state.move_ptr = 0;
 }
 
+pub fn handle_setoption(es: &mut EngineState, tokens: Vec<&str>) {
+    let mut name = String::new();
+    let mut value = String::new();
+    let mut iter = tokens.into_iter();
+    iter.next();
+
+    let mut target = &mut name;
+    for token in iter.by_ref() {
+        match token {
+            "name" => target = &mut name,
+            "value" => target = &mut value,
+            _ => {
+                target.push_str(token);
+                target.push(' ');
+            }
+        }
+    }
+
+    match name.trim().to_lowercase().as_str() {
+        "ownbook" => es.own_book = value.trim() == "true",
+
+        "bookbestmove" => es.book_best_only = value.trim() == "true",
+
+        "bookfile" => {
+            let path = value.trim();
+            if path.is_empty() || path == "<empty>" {
+                es.book = None;
+            } else {
+                match Book::load(path) {
+                    Ok(book) => es.book = Some(book),
+                    Err(e) => {
+                        es.book = None;
+                        println!("info string Could not load book {}: {}", path, e);
+                    }
+                }
+            }
+        }
+
+        "syzygypath" => {
+            let ss_arc = es.search_state.clone();
+            let lock_result = ss_arc.try_lock();
+            if let Ok(mut state) = lock_result {
+                let count = state.tablebases.init(value.trim());
+                println!("info string Found {} tablebases, up to {} pieces", count, state.tablebases.max_pieces);
+            } else {
+                println!("Cannot change tablebases during search.");
+            }
+        }
+
+        "syzygyprobedepth" => {
+            let ss_arc = es.search_state.clone();
+            let lock_result = ss_arc.try_lock();
+            if let Ok(mut state) = lock_result {
+                match value.trim().parse::<u8>() {
+                    Ok(depth) => state.tablebases.probe_depth = depth.max(1),
+                    Err(_) => println!("{}", CMD_ERR),
+                }
+            } else {
+                println!("Cannot change tablebases during search.");
+            }
+        }
+
+        // Pondering only needs go ponder and ponderhit, the option just tells GUIs they work
+        "ponder" => {}
+
+        "hashsavedepth" => match value.trim().parse::<u8>() {
+            Ok(depth) => es.hash_save_depth = depth,
+            Err(_) => println!("{}", CMD_ERR),
+        },
+
+        _ => println!("{}", CMD_ERR),
+    }
+}
+
+// Accepts moves in coordinate form as well as in SAN
+pub fn parse_user_move(pos: &Position, m: &str) -> Result<Move, SanError> {
+    if is_coordinate_move(m) {
+        parse_move(pos, m).filter(|&mv| pos.is_legal(mv)).ok_or(SanError::Illegal)
+    } else {
+        pos.parse_san(m)
+    }
+}
+
 pub fn handle_move(es: &mut EngineState, tokens: Vec<&str>) {
-    let mut pos = es.root.clone();
+    let mut pos = es.root;
     if let Some(&m) = tokens.get(1) {
-        pos = pos.make_move(parse_move(&pos, m));
+        match parse_user_move(&pos, m) {
+            Ok(m) => pos = pos.make_move(m),
+            Err(e) => {
+                println!("Invalid move {}: {:?}", m, e);
+                return;
+            }
+        }
     }
     es.root = pos;
     es.root.state.move_ptr = 0;
 }
 
+fn is_coordinate_move(m: &str) -> bool {
+    let bytes = m.as_bytes();
+    (bytes.len() == 4 || bytes.len() == 5)
+        && (b'a'..=b'h').contains(&bytes[0]) && (b'1'..=b'8').contains(&bytes[1])
+        && (b'a'..=b'h').contains(&bytes[2]) && (b'1'..=b'8').contains(&bytes[3])
+}
+
+// Without depth, nodes, movetime or infinite the search runs for four seconds
 pub fn handle_go(es: &mut EngineState, tokens: Vec<&str>) {
     let mut iter = tokens.into_iter();
     iter.next();
 
-    'outer: while let Some(token) = iter.next() {
+    let mut limits = SearchLimits::new();
+    let mut infinite = false;
+    let mut ponder = false;
+    let mut clock_time = [None; 2];
+    let mut clock_increment = [Duration::ZERO; 2];
+    let mut moves_to_go = None;
+
+    if search_running(es) {
+        println!("A search is already in progress!");
+        return;
+    }
+
+    while let Some(token) = iter.next() {
         match token {
 
             "perft" => {
-                let depth = iter.next().unwrap_or("1").parse::<u8>().unwrap();
-
-                // temporary fix
-                if depth <= 1 {
-                    let pos = &mut es.root.clone();
-                    let mut list = [Move::default(); MAX_MOVE_COUNT];
-                    pos.generate(&mut list[0..MAX_MOVE_COUNT]);
-                    println!("Nodes searched: {}", pos.state.move_cnt)
-                } else {
-                    let root_clone = es.root.clone();
-                    let ss_arc = es.search_state.clone();
-                    thread::spawn(move || {
-                        let lock_result = ss_arc.try_lock();
-                        if lock_result.is_ok() {
-                            let mut state = lock_result.unwrap();
-
-                            state.root = root_clone;
-                            state.max_depth = depth;
-                            state.stats = SearchStats::new();
-
-                            perft(&mut state);
-                        } else {
-                            println!("A search is already in progress!");
-                        }
-                    });
-                }
+                let Ok(depth) = iter.next().unwrap_or("1").parse::<u8>() else {
+                    println!("{}", CMD_ERR);
+                    return;
+                };
+
+                let root = es.root;
+                es.search = Some(thread::spawn(move || {
+                    let clock = Instant::now();
+                    let counts = divide(&root, depth.max(1));
+                    let nodes: u64 = counts.iter().map(|(_, c)| c).sum();
+
+                    for (m, count) in counts {
+                        println!("{}: {}", move_to_string(m), count);
+                    }
+
+                    let stop_time = clock.elapsed().as_millis();
+                    println!();
+                    println!("Nodes searched: {}", nodes);
+                    println!("Time elapsed: {} ms", stop_time);
+                    println!("Nodes per second: {} million", nodes as u128 / (stop_time + 1) / 1000);
+                }));
+                return;
             }
 
-            _ => {
-                let (sender, receiver) = mpsc::channel();
-                let root_clone = es.root.clone();
-                let ss_arc = es.search_state.clone();
+            "depth" => match iter.next().map(|d| d.parse::<u8>()) {
+                Some(Ok(depth)) => limits.depth = depth.clamp(1, MAX_PLY as u8),
+                _ => println!("{}", CMD_ERR),
+            },
+
+            "nodes" => match iter.next().map(|n| n.parse::<u64>()) {
+                Some(Ok(nodes)) => limits.nodes = Some(nodes),
+                _ => println!("{}", CMD_ERR),
+            },
+
+            "movetime" => match iter.next().map(|t| t.parse::<u64>()) {
+                Some(Ok(time)) => limits.time = Some(Duration::from_millis(time)),
+                _ => println!("{}", CMD_ERR),
+            },
+
+            "wtime" | "btime" | "winc" | "binc" => match iter.next().map(|t| t.parse::<u64>()) {
+                Some(Ok(time)) => {
+                    let side = (token.starts_with('b')) as usize;
+                    let time = Duration::from_millis(time);
+                    if token.ends_with("time") { clock_time[side] = Some(time) } else { clock_increment[side] = time }
+                }
+                _ => println!("{}", CMD_ERR),
+            },
 
-                thread::spawn(move || {
-                    let lock_result = ss_arc.try_lock();
-                    if lock_result.is_ok() {
-                        let mut state = lock_result.unwrap();
+            "movestogo" => match iter.next().map(|n| n.parse::<u32>()) {
+                Some(Ok(moves)) => moves_to_go = Some(moves),
+                _ => println!("{}", CMD_ERR),
+            },
 
-                        state.root = root_clone;
-                        state.stats = SearchStats::new();
+            "infinite" => infinite = true,
 
-                        pvs(&mut state, &receiver);
-                    } else {
-                        println!("A search is already in progress!");
-                    }
-                });
+            "ponder" => ponder = true,
 
-                let terminate_arc = es.terminate.clone();
-                thread::spawn(move || {
-                    let clock = Instant::now();
-                    while clock.elapsed().as_millis() < 4000 && !terminate_arc.load(Relaxed) {}
-                    sender.send(true).expect("Search thread has terminated unexpectedly.");
-                    terminate_arc.store(false, Relaxed);
-                });
+            _ => {}
+        }
+    }
 
-                break 'outer;
-            }
+    // A movetime overrides the clock
+    let side = (!es.root.state.turn) as usize;
+    if let (None, Some(left)) = (limits.time, clock_time[side]) {
+        limits.time = Some(allocate_time(left, clock_increment[side], moves_to_go));
+    }
+
+    if !infinite && limits.depth == MAX_PLY as u8 && limits.nodes.is_none() && limits.time.is_none() {
+        limits.time = Some(Duration::from_millis(4000));
+    }
+
+    if es.own_book {
+        if let Some(m) = es.book.as_ref().and_then(|b| b.pick(&es.root, es.book_best_only)) {
+            print!("bestmove ");
+            m.print();
+            println!();
+            return;
+        }
+    }
+
+    let clock = Instant::now();
+    spawn_search(es, limits, ponder, clock, UciListener { clock }, |result| print_bestmove(&result));
+}
+
+// Spread over this many moves when the clock has no moves to go
+const DEFAULT_MOVES_TO_GO: u32 = 30;
+
+// Kept back for the time it takes to pass a move to the GUI
+const MOVE_OVERHEAD: Duration = Duration::from_millis(50);
+
+// Time for the next move from what is left on the clock, the front ends share it. Most of
+// the increment is used, and never more than half the remaining time.
+pub fn allocate_time(left: Duration, increment: Duration, moves_to_go: Option<u32>) -> Duration {
+    let moves = moves_to_go.unwrap_or(DEFAULT_MOVES_TO_GO).max(1);
+    let budget = left / moves + increment * 3 / 4;
+    budget.min(left.saturating_sub(MOVE_OVERHEAD) / 2).max(Duration::from_millis(1))
+}
+
+// Searches the root on a thread of its own, done gets the result. The clock is the one the
+// limits count from, started when the command arrived.
+pub fn spawn_search<L, F>(es: &mut EngineState, limits: SearchLimits, ponder: bool, clock: Instant, mut listener: L, done: F)
+    where L: SearchListener + Send + 'static, F: FnOnce(SearchResult) + Send + 'static {
+
+    // The flag is cleared before the search starts, so a stop sent right after go is not lost
+    es.stop.store(false, Relaxed);
+    es.ponder.store(ponder, Relaxed);
+    let root_clone = es.root;
+    let ss_arc = es.search_state.clone();
+
+    es.search = Some(thread::spawn(move || {
+        let mut state = ss_arc.lock().unwrap();
+
+        state.root = root_clone;
+        state.stats = SearchStats::new();
+        state.limits = limits;
+        state.clock = clock;
+
+        let result = pvs(&mut state, &mut listener);
+
+        // A pondering search keeps its bestmove until the ponderhit or stop, even when done
+        while state.ponder.load(Relaxed) && !state.stop.load(Relaxed) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        done(result);
+    }));
+}
+
+// Only one search thread exists at a time, a finished one is joined before the next starts
+pub fn search_running(es: &mut EngineState) -> bool {
+    if es.search.as_ref().is_some_and(|handle| !handle.is_finished()) {
+        return true;
+    }
+    if let Some(handle) = es.search.take() {
+        handle.join().unwrap();
+    }
+    false
+}
+
+// Stops the running search, if any, and waits for its bestmove
+pub fn stop_search(es: &mut EngineState) {
+    es.stop.store(true, Relaxed);
+    if let Some(handle) = es.search.take() {
+        handle.join().unwrap();
+    }
+}
+
+// Runs before the next command is read, so an isready sent after it is answered once the
+// tables are cleared
+pub fn handle_ucinewgame(es: &mut EngineState) {
+    stop_search(es);
+    es.search_state.lock().unwrap().new_game();
+    es.root = Position::build_from_fen(STARTPOS_FEN);
+}
+
+// Root moves are announced once the search has run this long
+const CURRMOVE_DELAY: Duration = Duration::from_secs(1);
+
+// Prints the info lines of a search started by go, the clock is the one of the search
+pub struct UciListener {
+    pub clock: Instant,
+}
+
+// Each info line is printed by a single println, so a reply from the input thread such as
+// readyok cannot land in the middle of it
+impl UciListener {
+    fn progress(&self, result: &SearchResult) -> String {
+        format!("nodes {} nps {} hashfull {} tbhits {} time {}",
+            result.nodes, result.nps(), result.hashfull, result.tb_hits, result.time.as_millis())
+    }
+}
+
+impl SearchListener for UciListener {
+    fn iteration(&mut self, result: &SearchResult) {
+        let pv: Vec<String> = result.pv.iter().map(|&m| move_to_string(m)).collect();
+        println!("info depth {} seldepth {} score cp {} {} pv {}",
+            result.depth, result.seldepth, result.score, self.progress(result), pv.join(" "));
+    }
+
+    fn current_move(&mut self, m: Move, number: usize, depth: u8) {
+        if self.clock.elapsed() >= CURRMOVE_DELAY {
+            println!("info depth {} currmove {} currmovenumber {}", depth, move_to_string(m), number);
         }
     }
+
+    fn heartbeat(&mut self, result: &SearchResult) {
+        println!("info depth {} seldepth {} {}", result.depth, result.seldepth, self.progress(result));
+    }
 }
 
-pub fn parse_move(root: &Position, m: &str) -> Move {
+pub fn print_bestmove(result: &SearchResult) {
+    match (result.best_move, result.ponder_move) {
+        (Some(m), Some(ponder)) => println!("bestmove {} ponder {}", move_to_string(m), move_to_string(ponder)),
+        (Some(m), None) => println!("bestmove {}", move_to_string(m)),
+        (None, _) => println!("bestmove 0000"),
+    }
+}
+
+// None unless the string is a coordinate move, whether it is legal is up to the caller
+pub fn parse_move(root: &Position, m: &str) -> Option<Move> {
+    if !is_coordinate_move(m) || (m.len() == 5 && !"nbrq".contains(&m[4..5])) {
+        return None;
+    }
+
     let origin = BITS[string_to_index(&m[0..2])];
     let target = BITS[string_to_index(&m[2..4])];
-    let tier = root.square_tier(origin) as u8;
+    let tier = root.piece_on(string_to_index(&m[0..2]));
     let mut code = 0;
     if tier == 0 {
-        if target & root.state.en_passant != 0 {
+        let diagonal = (string_to_index(&m[0..2]) ^ string_to_index(&m[2..4])) & 7 != 0;
+        if diagonal && target & root.all == 0 {
             code = 8;
-        } else if origin == target << 2 || origin == target >> 2 {
+        } else if origin == target << 16 || origin == target >> 16 {
             code = 5;
         } else if m.len() > 4 {
             let promos = " nbrq";
@@ -228,5 +632,38 @@ pub fn parse_move(root: &Position, m: &str) -> Move {
             code = 6;
         }
     }
-    Move { origin, target, tier, code }
-}
\ No newline at end of file
+    Some(Move::new(origin, target, code))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_moves_are_rejected() {
+        let pos = Position::build_from_fen(STARTPOS_FEN);
+        for m in ["", "e2", "e2e", "e2e9", "i2i4", "e7e8k", "e2e4 ", "e2-e4"] {
+            assert!(parse_move(&pos, m).is_none(), "{:?} was parsed", m);
+        }
+        assert!(parse_move(&pos, "e2e4").is_some_and(|m| m.code() == 5));
+    }
+
+    #[test]
+    fn invalid_fens_keep_the_position() {
+        let mut es = EngineState::new();
+        handle_position(&mut es, "position fen 4k3/8/8/8/8/8/8/4K3 w - - 0 1 moves e1e2".split(' ').collect());
+        let fen = es.root.to_fen();
+        assert_eq!(fen, "4k3/8/8/8/8/8/4K3/8 b - - 0 1");
+
+        for bad in ["8/8/8/8/8/8/8/8 w - - 0 1", "4k3/8/8/8/8/8/8/4K3 x - - 0 1", "garbage"] {
+            handle_position(&mut es, format!("position fen {} moves e8e7", bad).split(' ').collect());
+            assert_eq!(es.root.to_fen(), fen, "{} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn promotions_are_parsed() {
+        let pos = Position::build_from_fen("8/P6k/8/8/8/8/8/K7 w - - 0 1");
+        let m = parse_move(&pos, "a7a8n").unwrap();
+        assert!(pos.is_legal(m) && m.code() == 1);
+    }
+}
@@ -0,0 +1,336 @@
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+use crate::output::move_to_string;
+use crate::position::{Move, Position};
+use crate::search::{SearchListener, SearchResult};
+use crate::state::{EngineState, MAX_PLY, SearchLimits};
+use crate::uci::{allocate_time, handle_ucinewgame, parse_user_move, search_running, spawn_search, stop_search};
+
+const FEATURES: &str = "feature myname=\"Piston Dev\" ping=1 setboard=1 usermove=1 playother=1 analyze=1 \
+    colors=0 san=0 time=1 draw=0 sigint=0 sigterm=0 reuse=1 done=1";
+
+// How often the loop looks for a finished search while no command arrives
+const RESULT_POLL: Duration = Duration::from_millis(5);
+
+// Thinking time when neither level, st nor time was sent
+const DEFAULT_MOVE_TIME: Duration = Duration::from_secs(4);
+
+// The game as the GUI sees it. Results of searches that were started before the game changed
+// carry an older search id and are dropped.
+struct Game {
+    history: Vec<Position>,
+    force: bool,
+    engine_white: bool,
+    analyzing: bool,
+    post: bool,
+    moves_per_control: u32,
+    increment: Duration,
+    move_time: Option<Duration>,
+    depth: Option<u8>,
+    clock: Option<Duration>,
+    search_id: u64,
+    results: Sender<(u64, SearchResult)>,
+}
+
+// Prints the thinking output: ply, score, time in centiseconds, nodes and the PV
+struct XboardListener {
+    post: bool,
+}
+
+impl SearchListener for XboardListener {
+    fn iteration(&mut self, result: &SearchResult) {
+        if self.post {
+            let pv: Vec<String> = result.pv.iter().map(|&m| move_to_string(m)).collect();
+            println!("{} {} {} {} {}", result.depth, result.score, result.time.as_millis() / 10, result.nodes, pv.join(" "));
+        }
+    }
+}
+
+// Entered when the first command is xboard or protover, reads from the same input thread as
+// the UCI loop. The first command is passed on, protover is answered right away.
+pub fn xboard_loop(es: &mut EngineState, input: Receiver<Vec<String>>, first: Vec<String>) {
+    let (sender, results) = mpsc::channel();
+    let mut game = Game {
+        history: Vec::new(),
+        force: false,
+        engine_white: false,
+        analyzing: false,
+        post: false,
+        moves_per_control: 0,
+        increment: Duration::ZERO,
+        move_time: None,
+        depth: None,
+        clock: None,
+        search_id: 0,
+        results: sender,
+    };
+
+    let mut received = Ok(first);
+    loop {
+        match received {
+            Ok(tokens) => {
+                let tokens: Vec<&str> = tokens.iter().map(|t| t.as_str()).collect();
+                if !handle_command(es, &mut game, &tokens) {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        while let Ok((id, result)) = results.try_recv() {
+            if id == game.search_id && !game.analyzing {
+                play_engine_move(es, &mut game, result);
+            }
+        }
+
+        received = input.recv_timeout(RESULT_POLL);
+    }
+
+    stop_search(es);
+}
+
+// Returns false on quit
+fn handle_command(es: &mut EngineState, game: &mut Game, tokens: &[&str]) -> bool {
+    let argument = |i: usize| tokens.get(i).and_then(|t| t.parse::<u64>().ok());
+
+    match tokens[0] {
+        "xboard" | "accepted" | "rejected" | "hard" | "easy" | "random" | "computer" | "name"
+            | "rating" | "ics" | "otim" | "draw" | "hint" | "bk" | "." => {}
+
+        "protover" => println!("{}", FEATURES),
+
+        "quit" => return false,
+
+        "ping" => println!("pong {}", tokens.get(1).unwrap_or(&"")),
+
+        "new" => {
+            abandon_search(es, game);
+            handle_ucinewgame(es);
+            game.history.clear();
+            game.force = false;
+            game.engine_white = false;
+            game.move_time = None;
+            game.depth = None;
+            restart_analysis(es, game);
+        }
+
+        "force" => {
+            abandon_search(es, game);
+            game.force = true;
+        }
+
+        "go" => {
+            abandon_search(es, game);
+            game.force = false;
+            game.engine_white = es.root.state.turn;
+            think(es, game);
+        }
+
+        "playother" => {
+            abandon_search(es, game);
+            game.force = false;
+            game.engine_white = !es.root.state.turn;
+        }
+
+        // Moves the engine now, with the best move found so far
+        "?" => {
+            if !game.analyzing {
+                es.stop.store(true, Relaxed);
+            }
+        }
+
+        "level" => match (argument(1), tokens.get(2).and_then(|t| parse_base(t)), argument(3)) {
+            (Some(moves), Some(_), Some(increment)) => {
+                game.moves_per_control = moves as u32;
+                game.increment = Duration::from_secs(increment);
+                game.move_time = None;
+            }
+            _ => println!("Error (bad arguments): {}", tokens.join(" ")),
+        },
+
+        "st" => match argument(1) {
+            Some(seconds) => game.move_time = Some(Duration::from_secs(seconds)),
+            None => println!("Error (bad arguments): {}", tokens.join(" ")),
+        },
+
+        "sd" => match argument(1) {
+            Some(depth) => game.depth = Some(depth.clamp(1, MAX_PLY as u64) as u8),
+            None => println!("Error (bad arguments): {}", tokens.join(" ")),
+        },
+
+        "time" => match argument(1) {
+            Some(centis) => game.clock = Some(Duration::from_millis(centis * 10)),
+            None => println!("Error (bad arguments): {}", tokens.join(" ")),
+        },
+
+        "post" => game.post = true,
+
+        "nopost" => game.post = false,
+
+        "usermove" => {
+            let Some(&m) = tokens.get(1) else {
+                println!("Error (bad arguments): usermove");
+                return true;
+            };
+            match parse_user_move(&es.root, m) {
+                Ok(parsed) => {
+                    abandon_search(es, game);
+                    make_move(es, game, parsed);
+                    if let Some(claim) = claim_result(&es.root) {
+                        println!("{}", claim);
+                    } else if !game.force && !game.analyzing && es.root.state.turn == game.engine_white {
+                        think(es, game);
+                    }
+                    restart_analysis(es, game);
+                }
+                Err(_) => println!("Illegal move: {}", m),
+            }
+        }
+
+        "undo" | "remove" => {
+            abandon_search(es, game);
+            let plies = if tokens[0] == "undo" { 1 } else { 2 };
+            for _ in 0..plies {
+                if let Some(pos) = game.history.pop() {
+                    es.root = pos;
+                }
+            }
+            restart_analysis(es, game);
+        }
+
+        "setboard" => match Position::parse_fen(&tokens[1..].join(" ")) {
+            Ok(pos) => {
+                abandon_search(es, game);
+                es.root = pos;
+                es.root.state.move_ptr = 0;
+                game.history.clear();
+                restart_analysis(es, game);
+            }
+            // The old board stays, the GUI shows the message to the user
+            Err(_) => println!("tellusererror Illegal position"),
+        },
+
+        "analyze" => {
+            abandon_search(es, game);
+            game.analyzing = true;
+            restart_analysis(es, game);
+        }
+
+        "exit" => {
+            abandon_search(es, game);
+            game.analyzing = false;
+        }
+
+        // The game is over, whatever the GUI says it ended with
+        "result" => {
+            abandon_search(es, game);
+            game.force = true;
+        }
+
+        _ => println!("Error (unknown command): {}", tokens[0]),
+    }
+    true
+}
+
+// The base of level is given in minutes or as minutes:seconds, only the moves per control and
+// the increment matter once the time command reports the clock
+fn parse_base(base: &str) -> Option<Duration> {
+    let (minutes, seconds) = base.split_once(':').unwrap_or((base, "0"));
+    Some(Duration::from_secs(minutes.parse::<u64>().ok()? * 60 + seconds.parse::<u64>().ok()?))
+}
+
+// Stops the running search and makes sure its result is not played
+fn abandon_search(es: &mut EngineState, game: &mut Game) {
+    game.search_id += 1;
+    stop_search(es);
+}
+
+fn make_move(es: &mut EngineState, game: &mut Game, m: Move) {
+    game.history.push(es.root);
+    es.root = es.root.make_move(m);
+    es.root.state.move_ptr = 0;
+}
+
+fn play_engine_move(es: &mut EngineState, game: &mut Game, result: SearchResult) {
+    let Some(m) = result.best_move else { return };
+    make_move(es, game, m);
+    println!("move {}", move_to_string(m));
+    if let Some(claim) = claim_result(&es.root) {
+        println!("{}", claim);
+    }
+}
+
+// Mates, stalemates and bare kings, there is no move counter for the fifty move rule
+fn claim_result(pos: &Position) -> Option<&'static str> {
+    if pos.legal_moves().is_empty() {
+        Some(match (pos.state.check, pos.state.turn) {
+            (true, true) => "0-1 {Black mates}",
+            (true, false) => "1-0 {White mates}",
+            (false, _) => "1/2-1/2 {Stalemate}",
+        })
+    } else if pos.all == pos.kings {
+        Some("1/2-1/2 {Insufficient material}")
+    } else {
+        None
+    }
+}
+
+fn think(es: &mut EngineState, game: &mut Game) {
+    if claim_result(&es.root).is_some() || search_running(es) {
+        return;
+    }
+
+    let mut limits = SearchLimits::new();
+    if let Some(depth) = game.depth {
+        limits.depth = depth;
+    }
+    limits.time = Some(match (game.move_time, game.clock) {
+        (Some(time), _) => time,
+        (None, Some(left)) => {
+            let played = (es.root.state.half_move / 2) as u32;
+            let moves_to_go = (game.moves_per_control > 0).then(|| game.moves_per_control - played % game.moves_per_control);
+            allocate_time(left, game.increment, moves_to_go)
+        }
+        (None, None) => DEFAULT_MOVE_TIME,
+    });
+
+    start(es, game, limits);
+}
+
+// Analysis searches without limits and starts over whenever the position changes
+fn restart_analysis(es: &mut EngineState, game: &mut Game) {
+    if game.analyzing {
+        abandon_search(es, game);
+        start(es, game, SearchLimits::new());
+    }
+}
+
+fn start(es: &mut EngineState, game: &mut Game, limits: SearchLimits) {
+    let id = game.search_id;
+    let results = game.results.clone();
+    let listener = XboardListener { post: game.post || game.analyzing };
+    spawn_search(es, limits, false, Instant::now(), listener, move |result| { results.send((id, result)).ok(); });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::position::STARTPOS_FEN;
+
+    #[test]
+    fn results_are_claimed() {
+        assert_eq!(claim_result(&Position::build_from_fen(STARTPOS_FEN)), None);
+        assert_eq!(claim_result(&Position::build_from_fen("6Rk/5Q2/8/8/8/8/8/K7 b - - 0 1")), Some("1-0 {White mates}"));
+        assert_eq!(claim_result(&Position::build_from_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1")), Some("1/2-1/2 {Stalemate}"));
+        assert_eq!(claim_result(&Position::build_from_fen("7k/8/6K1/8/8/8/8/8 b - - 0 1")), Some("1/2-1/2 {Insufficient material}"));
+    }
+
+    #[test]
+    fn level_bases() {
+        assert_eq!(parse_base("5"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_base("0:30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_base("x"), None);
+    }
+}